@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE confirmation_throttle(
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    PRIMARY KEY (scope, key),
+    window_start timestamptz NOT NULL,
+    sends INT NOT NULL,
+    last_sent_at timestamptz NOT NULL
+);
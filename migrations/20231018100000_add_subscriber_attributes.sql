@@ -0,0 +1,12 @@
+-- Add migration script here
+ALTER TABLE subscribers
+    ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
+
+CREATE TABLE attribute_definitions(
+    key TEXT NOT NULL,
+    PRIMARY KEY (key),
+    kind TEXT NOT NULL,
+    description TEXT,
+    required BOOLEAN NOT NULL DEFAULT false,
+    created_at timestamptz NOT NULL
+);
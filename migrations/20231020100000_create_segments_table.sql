@@ -0,0 +1,36 @@
+-- Add migration script here
+CREATE TABLE list_subscriptions(
+    list TEXT NOT NULL,
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    PRIMARY KEY (list, subscriber_id),
+    subscribed_at timestamptz NOT NULL
+);
+
+CREATE TABLE subscriber_tags(
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (subscriber_id, tag),
+    tagged_at timestamptz NOT NULL
+);
+CREATE INDEX subscriber_tags_tag_idx ON subscriber_tags (tag);
+
+CREATE TABLE subscriber_events(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    issue_id uuid,
+    details JSONB NOT NULL DEFAULT '{}',
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX subscriber_events_subscriber_id_kind_idx
+    ON subscriber_events (subscriber_id, kind, occurred_at);
+
+CREATE TABLE segments(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    name TEXT NOT NULL UNIQUE,
+    filter TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL
+);
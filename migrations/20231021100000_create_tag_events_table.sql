@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE tag_events(
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    PRIMARY KEY (id),
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    action TEXT NOT NULL,
+    actor TEXT,
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX tag_events_tag_idx ON tag_events (tag, occurred_at);
+CREATE INDEX tag_events_subscriber_id_idx ON tag_events (subscriber_id, occurred_at);
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE erasures(
+    email_hash TEXT NOT NULL,
+    PRIMARY KEY (email_hash),
+    erased_at timestamptz NOT NULL,
+    erased_by TEXT
+);
+
+CREATE TABLE data_request_tokens(
+    token TEXT NOT NULL,
+    PRIMARY KEY (token),
+    email TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+CREATE INDEX data_request_tokens_email_idx ON data_request_tokens (email);
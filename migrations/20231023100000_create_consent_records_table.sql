@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TABLE consent_records(
+    id uuid NOT NULL DEFAULT gen_random_uuid(),
+    PRIMARY KEY (id),
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    list TEXT,
+    ip TEXT,
+    user_agent TEXT,
+    details JSONB NOT NULL DEFAULT '{}',
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX consent_records_subscriber_id_idx ON consent_records (subscriber_id, occurred_at);
+
+-- Append-only, rows only ever go away together with their subscriber
+CREATE FUNCTION consent_records_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'consent_records is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER consent_records_append_only
+    BEFORE UPDATE ON consent_records
+    FOR EACH ROW EXECUTE FUNCTION consent_records_append_only();
+
+ALTER TABLE subscribers
+    ADD COLUMN unsubscribe_token TEXT NOT NULL UNIQUE DEFAULT replace(gen_random_uuid()::text, '-', '');
@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE suppressions(
+    email_hash TEXT NOT NULL,
+    PRIMARY KEY (email_hash),
+    -- Not kept for erased addresses
+    email TEXT,
+    reason TEXT NOT NULL,
+    source TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+INSERT INTO suppressions (email_hash, email, reason, source, created_at)
+SELECT email_hash, NULL, 'erased', 'erasure', erased_at FROM erasures;
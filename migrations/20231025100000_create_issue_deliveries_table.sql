@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE issue_deliveries(
+    issue_id uuid NOT NULL,
+    subscriber_id uuid NOT NULL REFERENCES subscribers (id) ON DELETE CASCADE,
+    PRIMARY KEY (issue_id, subscriber_id),
+    sent_at timestamptz NOT NULL
+);
+CREATE INDEX issue_deliveries_subscriber_id_sent_at_idx
+    ON issue_deliveries (subscriber_id, sent_at);
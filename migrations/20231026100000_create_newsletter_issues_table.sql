@@ -0,0 +1,24 @@
+-- Add migration script here
+CREATE TABLE newsletter_issues(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    author_id uuid REFERENCES users (id) ON DELETE SET NULL,
+    status TEXT NOT NULL,
+    segment_id uuid,
+    filter TEXT,
+    tag TEXT,
+    recipients INTEGER NOT NULL DEFAULT 0,
+    delivered INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    suppressed INTEGER NOT NULL DEFAULT 0,
+    created_at timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL,
+    scheduled_at timestamptz,
+    sent_at timestamptz,
+    cancelled_at timestamptz
+);
+CREATE INDEX newsletter_issues_status_created_at_idx
+    ON newsletter_issues (status, created_at);
@@ -0,0 +1,4 @@
+-- Add migration script here
+CREATE INDEX newsletter_issues_scheduled_at_idx
+    ON newsletter_issues (scheduled_at)
+    WHERE status = 'scheduled';
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE idempotency_keys(
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    PRIMARY KEY (user_id, idempotency_key),
+    -- Missing while the first request is still being processed
+    response_status_code SMALLINT,
+    response_content_type TEXT,
+    response_body BYTEA,
+    created_at timestamptz NOT NULL
+);
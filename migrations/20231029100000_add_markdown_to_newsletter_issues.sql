@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN markdown_content TEXT;
+-- Hand-written text and HTML now override what's rendered from the Markdown
+ALTER TABLE newsletter_issues ALTER COLUMN text_content DROP NOT NULL;
+ALTER TABLE newsletter_issues ALTER COLUMN html_content DROP NOT NULL;
+ALTER TABLE newsletter_issues ADD CONSTRAINT newsletter_issues_content_check CHECK (
+    markdown_content IS NOT NULL OR (text_content IS NOT NULL AND html_content IS NOT NULL)
+);
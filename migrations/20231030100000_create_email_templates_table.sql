@@ -0,0 +1,17 @@
+-- Add migration script here
+-- Customized transactional emails, the built-in ones are used for anything missing here
+CREATE TABLE email_templates(
+    kind TEXT NOT NULL,
+    -- '' for all lists and all locales, so that they can be part of the key
+    list TEXT NOT NULL DEFAULT '',
+    locale TEXT NOT NULL DEFAULT '',
+    subject TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    updated_by uuid REFERENCES users (id) ON DELETE SET NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (kind, list, locale)
+);
+
+ALTER TABLE subscribers
+    ADD COLUMN locale TEXT;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Hex SHA-256 of the request, a key reused for a different request is rejected
+ALTER TABLE idempotency_keys ADD COLUMN request_hash TEXT NOT NULL DEFAULT '';
+ALTER TABLE idempotency_keys ALTER COLUMN request_hash DROP DEFAULT;
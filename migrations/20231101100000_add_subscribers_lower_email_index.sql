@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Emails are matched case-insensitively, so two of them differing only in case are the same
+-- subscriber. Fails if such duplicates already exist, they have to be merged by hand first.
+CREATE UNIQUE INDEX subscribers_lower_email_idx ON subscribers (lower(email));
@@ -0,0 +1,80 @@
+use crate::{ServerError, SubscribeError};
+use axum::{
+    extract::rejection::JsonRejection,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+pub type ApiResult<T> = core::result::Result<T, ApiError>;
+
+/// Renders errors as JSON for the `/api` routes, instead of the plain-text bodies
+/// that the form endpoints use.
+#[derive(Debug)]
+pub enum ApiError {
+    Server(ServerError),
+    Rejection(JsonRejection),
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ApiErrorBody {
+    pub error: ApiErrorDetails,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ApiErrorDetails {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl From<ServerError> for ApiError {
+    fn from(value: ServerError) -> Self {
+        Self::Server(value)
+    }
+}
+
+impl From<SubscribeError> for ApiError {
+    fn from(value: SubscribeError) -> Self {
+        Self::Server(value.into())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(value: JsonRejection) -> Self {
+        Self::Rejection(value)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, headers, details) = match self {
+            ApiError::Server(e) => (
+                e.status(),
+                e.headers(),
+                ApiErrorDetails {
+                    code: e.code(),
+                    message: e.to_string(),
+                    retry_after_secs: match &e {
+                        ServerError::Subscribe(SubscribeError::TooManyRequests { retry_after }) => {
+                            Some(retry_after.as_secs_f64().ceil() as u64)
+                        }
+                        _ => None,
+                    },
+                },
+            ),
+            ApiError::Rejection(e) => (
+                e.status(),
+                HeaderMap::new(),
+                ApiErrorDetails {
+                    code: "invalid_body",
+                    message: e.body_text(),
+                    retry_after_secs: None,
+                },
+            ),
+        };
+
+        (status, headers, Json(ApiErrorBody { error: details })).into_response()
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{
+    api::{ApiError, ApiResult},
+    auth::AuthUser,
+    ServerError,
+};
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::{collections::HashMap, str::FromStr};
+use tracing::{info, instrument};
+
+/// Custom per-subscriber data, stored in the `subscribers.attributes` JSONB column.
+pub type AttributeValues = serde_json::Map<String, Value>;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AttributeKind {
+    Text,
+    Number,
+    Boolean,
+    /// `YYYY-MM-DD`
+    Date,
+}
+
+impl AttributeKind {
+    /// Normalizes `value` into what gets stored for this kind.
+    ///
+    /// Strings are accepted for every kind, since that's all an urlencoded form can carry.
+    pub fn coerce(&self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (Self::Text, Value::String(_)) => Some(value.clone()),
+            (Self::Number, Value::Number(_)) => Some(value.clone()),
+            (Self::Number, Value::String(s)) => match s.trim().parse::<i64>() {
+                Ok(n) => Some(Value::from(n)),
+                Err(_) => s
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(Value::Number),
+            },
+            (Self::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (Self::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+                "true" | "on" | "yes" | "1" => Some(Value::Bool(true)),
+                "false" | "off" | "no" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Self::Date, Value::String(s)) => NaiveDate::from_str(s.trim())
+                .ok()
+                .map(|date| Value::String(date.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttributeError {
+    #[error("Unknown attribute \"{0}\"")]
+    Unknown(String),
+    #[error("Attribute \"{key}\" must be of type {kind}")]
+    WrongType { key: String, kind: AttributeKind },
+    #[error("Missing required attribute \"{0}\"")]
+    Missing(String),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AttributeDefinition {
+    pub key: String,
+    pub kind: AttributeKind,
+    pub description: Option<String>,
+    pub required: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The set of attribute keys admins have defined, and what type each of them holds.
+#[derive(Debug, Clone, Default)]
+pub struct AttributeSchema(pub HashMap<String, AttributeDefinition>);
+
+impl AttributeSchema {
+    pub async fn load(pool: &PgPool) -> anyhow::Result<Self> {
+        let definitions = sqlx::query!(
+            r#"
+            SELECT key, kind, description, required, created_at FROM attribute_definitions
+            "#
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|obj| {
+            let definition = AttributeDefinition {
+                kind: AttributeKind::from_str(&obj.kind).expect("Stored value must be valid"),
+                key: obj.key,
+                description: obj.description,
+                required: obj.required,
+                created_at: obj.created_at,
+            };
+            (definition.key.clone(), definition)
+        })
+        .collect();
+
+        Ok(Self(definitions))
+    }
+
+    /// Checks `values` against the schema, returning them normalized.
+    ///
+    /// `require_all` enforces the required attributes, which only makes sense for new subscribers.
+    pub fn validate(
+        &self,
+        values: &AttributeValues,
+        require_all: bool,
+    ) -> Result<AttributeValues, AttributeError> {
+        let mut validated = AttributeValues::new();
+
+        for (key, value) in values {
+            let definition = self
+                .0
+                .get(key)
+                .ok_or_else(|| AttributeError::Unknown(key.clone()))?;
+            // Treat empty inputs as absent, an untouched optional form field sends ""
+            if matches!(value, Value::Null) || value.as_str().is_some_and(str::is_empty) {
+                continue;
+            }
+            let value = definition
+                .kind
+                .coerce(value)
+                .ok_or_else(|| AttributeError::WrongType {
+                    key: key.clone(),
+                    kind: definition.kind,
+                })?;
+            validated.insert(key.clone(), value);
+        }
+
+        if require_all {
+            if let Some(missing) = self
+                .0
+                .values()
+                .find(|definition| definition.required && !validated.contains_key(&definition.key))
+            {
+                return Err(AttributeError::Missing(missing.key.clone()));
+            }
+        }
+
+        Ok(validated)
+    }
+}
+
+/// Keys end up in templates and segment filters, so they're kept to identifier-like strings.
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    key.len() <= 64
+        && chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AttributeDefinitionBody {
+    pub kind: AttributeKind,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+pub async fn list_attribute_definitions(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+) -> ApiResult<Json<Vec<AttributeDefinition>>> {
+    let mut definitions = AttributeSchema::load(&pool)
+        .await
+        .map_err(ServerError::Unexpected)?
+        .0
+        .into_values()
+        .collect::<Vec<_>>();
+    definitions.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok(Json(definitions))
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn put_attribute_definition(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(key): Path<String>,
+    body: Result<Json<AttributeDefinitionBody>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<AttributeDefinition>)> {
+    let Json(body) = body?;
+    if !is_valid_key(&key) {
+        return Err(ServerError::BadRequest(
+            "Attribute keys must start with a lowercase letter and only contain \
+            lowercase letters, digits and '_', up to 64 characters."
+                .into(),
+        )
+        .into());
+    }
+
+    let obj = sqlx::query!(
+        r#"
+        INSERT INTO attribute_definitions (key, kind, description, required, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (key) DO UPDATE
+        SET kind = $2, description = $3, required = $4
+        RETURNING created_at, (xmax = 0) AS "inserted!"
+        "#,
+        key,
+        body.kind.to_string(),
+        body.description,
+        body.required,
+        Utc::now()
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    info!(key, kind = %body.kind, "Attribute definition saved");
+
+    Ok((
+        if obj.inserted {
+            StatusCode::CREATED
+        } else {
+            StatusCode::OK
+        },
+        Json(AttributeDefinition {
+            key,
+            kind: body.kind,
+            description: body.description,
+            required: body.required,
+            created_at: obj.created_at,
+        }),
+    ))
+}
+
+/// Also strips the attribute from every subscriber.
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn delete_attribute_definition(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(key): Path<String>,
+) -> ApiResult<StatusCode> {
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM attribute_definitions
+        WHERE key = $1
+        "#,
+        key
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(ApiError::from(ServerError::NotFound(format!(
+            "No such attribute \"{key}\""
+        ))));
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE subscribers
+        SET attributes = attributes - $1
+        WHERE attributes ? $1
+        "#,
+        key
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(key, "Attribute definition deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,8 +1,9 @@
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::{
-    extract::{rejection::TypedHeaderRejection, State, TypedHeader},
+    async_trait,
+    extract::{rejection::TypedHeaderRejection, FromRef, FromRequestParts, State, TypedHeader},
     headers::{authorization::Basic, Authorization},
-    http,
+    http::{self, request::Parts},
     response::{IntoResponse, Response},
 };
 use sqlx::PgPool;
@@ -10,7 +11,7 @@ use tokio::task;
 use tracing::{error, info, instrument};
 use uuid::Uuid;
 
-use crate::{AuthError, ServerError, ServerResult};
+use crate::{api::ApiError, AuthError, ServerError, ServerResult};
 
 pub async fn argon2_hash(password: String) -> anyhow::Result<String> {
     Ok(task::spawn_blocking(move || {
@@ -33,42 +34,80 @@ pub async fn argon2_verify(password: String, hash: String) -> anyhow::Result<()>
     Ok(())
 }
 
-#[instrument(skip(pool, auth),
-fields(
-    username = %auth.as_ref().map(|header| {
-        let TypedHeader(auth) = header;
-        format!("\"{:#}\"", auth.username())
-    }).unwrap_or("None".into())
-))]
-pub async fn singup(
-    auth: Result<TypedHeader<Authorization<Basic>>, TypedHeaderRejection>,
-    State(pool): State<PgPool>,
-) -> ServerResult<Response> {
-    match auth {
-        Ok(TypedHeader(auth)) => {
-            let password_hash = argon2_hash(auth.password().into()).await?;
-            let uuid = Uuid::new_v4();
-            sqlx::query!(
-                r#"
-                INSERT INTO users (id, username, password_hash)
-                VALUES ($1, $2, $3)
-                "#,
-                uuid,
-                auth.username(),
-                password_hash
-            )
-            .execute(&pool)
-            .await
-            .map_err(ServerError::unexpected)?;
+/// Checks Basic auth credentials against the `users` table, returning the user's id.
+pub async fn authenticate(pool: &PgPool, auth: &Authorization<Basic>) -> Result<Uuid, AuthError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT id, password_hash FROM users
+        WHERE username = $1
+        "#,
+        auth.username(),
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| AuthError::Unexpected(e.into()))?
+    .ok_or_else(|| AuthError::UserNotFound)?;
 
-            info!(?uuid, "New user signed-up");
+    argon2_verify(auth.password().into(), user.password_hash)
+        .await
+        .map_err(|_| AuthError::IncorrectPassword)?;
 
-            Ok(http::StatusCode::OK.into_response())
-        }
-        Err(_err) => Ok(http::StatusCode::BAD_REQUEST.into_response()),
+    Ok(user.id)
+}
+
+/// Extractor for routes that require a signed-up user, authenticated with Basic auth.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub username: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(auth) =
+            TypedHeader::<Authorization<Basic>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| ServerError::from(AuthError::MissingCredentials))?;
+        let id = authenticate(&PgPool::from_ref(state), &auth)
+            .await
+            .map_err(ServerError::from)?;
+
+        Ok(Self {
+            id,
+            username: auth.username().into(),
+        })
     }
 }
 
+/// Adds a user that can sign in to the admin API. There's no signup route, so that only whoever
+/// runs the server can hand out access.
+pub async fn create_user(pool: &PgPool, username: &str, password: String) -> anyhow::Result<Uuid> {
+    let password_hash = argon2_hash(password).await?;
+    let uuid = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO users (id, username, password_hash)
+        VALUES ($1, $2, $3)
+        "#,
+        uuid,
+        username,
+        password_hash
+    )
+    .execute(pool)
+    .await?;
+
+    info!(?uuid, username, "New user created");
+
+    Ok(uuid)
+}
+
 #[instrument(skip(pool, auth),
 fields(
     username = %auth.as_ref().map(|header| {
@@ -82,22 +121,7 @@ pub async fn login(
 ) -> ServerResult<Response> {
     match auth {
         Ok(TypedHeader(auth)) => {
-            let password_hash = sqlx::query!(
-                r#"
-                SELECT password_hash FROM users
-                WHERE username = $1
-                "#,
-                auth.username(),
-            )
-            .fetch_optional(&pool)
-            .await
-            .map_err(|e| AuthError::Unexpected(e.into()))?
-            .ok_or_else(|| AuthError::UserNotFound)?
-            .password_hash;
-
-            argon2_verify(auth.password().into(), password_hash)
-                .await
-                .map_err(|_| AuthError::IncorrectPassword)?;
+            authenticate(&pool, &auth).await?;
 
             info!("User logged in");
 
@@ -0,0 +1,325 @@
+use crate::{config::BotProtectionConfig, SubscribeError};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{fmt::Debug, net::IpAddr, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies `<issued_at>.<hmac>` tokens that get embedded into the subscribe form,
+/// so that submissions which come in too fast or too late after the form was served get rejected.
+#[derive(Clone)]
+pub struct FormTokenSigner {
+    secret: Vec<u8>,
+    pub min_age: Duration,
+    pub max_age: Duration,
+}
+
+impl Debug for FormTokenSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FormTokenSigner")
+            .field("secret", &"REDACTED")
+            .field("min_age", &self.min_age)
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+impl FormTokenSigner {
+    pub fn new(secret: impl Into<Vec<u8>>, min_age: Duration, max_age: Duration) -> Self {
+        Self {
+            secret: secret.into(),
+            min_age,
+            max_age,
+        }
+    }
+
+    fn mac(&self, issued_at: i64) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(issued_at.to_string().as_bytes());
+        mac
+    }
+
+    pub fn issue(&self) -> String {
+        let issued_at = Utc::now().timestamp_millis();
+        let signature = hex::encode(self.mac(issued_at).finalize().into_bytes());
+        format!("{issued_at}.{signature}")
+    }
+
+    pub fn verify(&self, token: &str) -> Result<()> {
+        let (issued_at, signature) = token.split_once('.').context("Malformed form token")?;
+        let issued_at = issued_at.parse::<i64>().context("Malformed form token")?;
+        let signature = hex::decode(signature).context("Malformed form token")?;
+
+        self.mac(issued_at)
+            .verify_slice(&signature)
+            .context("Form token signature mismatch")?;
+
+        let age = Duration::from_millis(
+            u64::try_from(Utc::now().timestamp_millis() - issued_at)
+                .context("Form token is issued in the future")?,
+        );
+        if age < self.min_age {
+            bail!("Form was submitted too fast ({age:?})");
+        }
+        if age > self.max_age {
+            bail!("Form token is stale ({age:?})");
+        }
+
+        Ok(())
+    }
+}
+
+/// Verifies the response token produced by a captcha widget on the client.
+#[async_trait::async_trait]
+pub trait CaptchaVerifier: Debug + Send + Sync {
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>) -> Result<bool>;
+}
+
+/// Works with any provider that implements the `siteverify` API shape,
+/// i.e. hCaptcha, reCAPTCHA and Cloudflare Turnstile.
+#[derive(Debug, Clone)]
+pub struct SiteVerifyCaptcha {
+    pub client: reqwest::Client,
+    pub verify_url: reqwest::Url,
+    pub secret: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+impl SiteVerifyCaptcha {
+    pub fn new(timeout: Duration, verify_url: reqwest::Url, secret: String) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            verify_url,
+            secret,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for SiteVerifyCaptcha {
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>) -> Result<bool> {
+        let remote_ip = remote_ip.map(|ip| ip.to_string());
+        let mut params = vec![("secret", self.secret.as_str()), ("response", response)];
+        if let Some(remote_ip) = &remote_ip {
+            params.push(("remoteip", remote_ip));
+        }
+
+        let resp = self
+            .client
+            .post(self.verify_url.clone())
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SiteVerifyResponse>()
+            .await?;
+
+        if !resp.success {
+            info!(error_codes = ?resp.error_codes, "Captcha verification failed");
+        }
+
+        Ok(resp.success)
+    }
+}
+
+/// Accepts exactly the configured response token, meant for tests and local setups.
+#[derive(Debug, Clone)]
+pub struct StubCaptchaVerifier {
+    pub accepted_response: String,
+}
+
+#[async_trait::async_trait]
+impl CaptchaVerifier for StubCaptchaVerifier {
+    async fn verify(&self, response: &str, _remote_ip: Option<IpAddr>) -> Result<bool> {
+        Ok(response == self.accepted_response)
+    }
+}
+
+/// Fields of the subscribe form that are only there to tell bots apart from people.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BotProtectionFields {
+    /// Hidden from people with CSS, so only bots that fill every input will set it.
+    #[serde(default)]
+    pub website: String,
+    pub form_token: Option<String>,
+    #[serde(
+        alias = "h-captcha-response",
+        alias = "g-recaptcha-response",
+        alias = "cf-turnstile-response"
+    )]
+    pub captcha_response: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BotProtection {
+    pub honeypot: bool,
+    pub form_token: Option<FormTokenSigner>,
+    pub captcha: Option<Arc<dyn CaptchaVerifier>>,
+}
+
+impl BotProtection {
+    pub async fn check(
+        &self,
+        fields: &BotProtectionFields,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<(), SubscribeError> {
+        if self.honeypot && !fields.website.is_empty() {
+            info!("Honeypot field was filled in");
+            return Err(SubscribeError::BotDetected);
+        }
+
+        if let Some(signer) = &self.form_token {
+            let token = fields
+                .form_token
+                .as_deref()
+                .ok_or(SubscribeError::BotDetected)?;
+            if let Err(err) = signer.verify(token) {
+                info!(?err, "Rejected form token");
+                return Err(SubscribeError::BotDetected);
+            }
+        }
+
+        if let Some(captcha) = &self.captcha {
+            let response = fields
+                .captcha_response
+                .as_deref()
+                .ok_or(SubscribeError::BotDetected)?;
+            match captcha.verify(response, remote_ip).await {
+                Ok(true) => {}
+                Ok(false) => return Err(SubscribeError::BotDetected),
+                Err(err) => {
+                    warn!(err = ?err.context("Captcha verifier errored"));
+                    return Err(SubscribeError::BotDetected);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<BotProtectionConfig> for BotProtection {
+    type Error = anyhow::Error;
+
+    fn try_from(value: BotProtectionConfig) -> std::result::Result<Self, Self::Error> {
+        let captcha: Option<Arc<dyn CaptchaVerifier>> =
+            match (value.captcha_verify_url, value.captcha_secret) {
+                (Some(verify_url), Some(secret)) => Some(Arc::new(SiteVerifyCaptcha::new(
+                    value.captcha_timeout_ms,
+                    verify_url.0,
+                    secret,
+                )?)),
+                (None, None) => None,
+                _ => bail!("Both captcha_verify_url and captcha_secret must be set"),
+            };
+
+        Ok(Self {
+            honeypot: value.honeypot,
+            form_token: value.form_token_secret.map(|secret| {
+                FormTokenSigner::new(
+                    secret,
+                    value.form_token_min_age_ms,
+                    value.form_token_max_age_ms,
+                )
+            }),
+            captcha,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> FormTokenSigner {
+        FormTokenSigner::new(
+            "secret",
+            Duration::from_secs(2),
+            Duration::from_secs(60 * 60),
+        )
+    }
+
+    fn token_issued_ago(signer: &FormTokenSigner, ago: Duration) -> String {
+        let issued_at = Utc::now().timestamp_millis() - ago.as_millis() as i64;
+        let signature = hex::encode(signer.mac(issued_at).finalize().into_bytes());
+        format!("{issued_at}.{signature}")
+    }
+
+    fn protection() -> BotProtection {
+        BotProtection {
+            honeypot: true,
+            form_token: Some(signer()),
+            captcha: Some(Arc::new(StubCaptchaVerifier {
+                accepted_response: "human".into(),
+            })),
+        }
+    }
+
+    fn fields(form_token: String) -> BotProtectionFields {
+        BotProtectionFields {
+            website: String::new(),
+            form_token: Some(form_token),
+            captcha_response: Some("human".into()),
+        }
+    }
+
+    async fn is_bot(fields: &BotProtectionFields) -> bool {
+        matches!(
+            protection().check(fields, None).await,
+            Err(SubscribeError::BotDetected)
+        )
+    }
+
+    #[tokio::test]
+    async fn accepts_a_person() {
+        let fields = fields(token_issued_ago(&signer(), Duration::from_secs(10)));
+        assert!(protection().check(&fields, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_filled_honeypot() {
+        let mut fields = fields(token_issued_ago(&signer(), Duration::from_secs(10)));
+        fields.website = "https://spam.example".into();
+        assert!(is_bot(&fields).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_forms_submitted_too_fast() {
+        assert!(is_bot(&fields(signer().issue())).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_form_tokens() {
+        let token = token_issued_ago(&signer(), Duration::from_secs(2 * 60 * 60));
+        assert!(is_bot(&fields(token)).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        let other = FormTokenSigner::new("other", signer().min_age, signer().max_age);
+        let token = token_issued_ago(&other, Duration::from_secs(10));
+        assert!(is_bot(&fields(token)).await);
+        assert!(is_bot(&fields("not-a-token".into())).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_tokens_and_wrong_captchas() {
+        let mut missing = fields(String::new());
+        missing.form_token = None;
+        assert!(is_bot(&missing).await);
+
+        let mut wrong = fields(token_issued_ago(&signer(), Duration::from_secs(10)));
+        wrong.captcha_response = Some("bot".into());
+        assert!(is_bot(&wrong).await);
+    }
+}
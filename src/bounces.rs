@@ -0,0 +1,471 @@
+//! Bounces and spam complaints, whatever they were reported by.
+//!
+//! Providers' webhooks and other sources are turned into [`DeliveryEvent`]s, which all go through
+//! [`process_delivery_event`].
+
+use crate::{
+    api::ApiResult,
+    auth::AuthUser,
+    config::BouncePolicyConfig,
+    consent::{record_consent, ConsentKind, ConsentSource},
+    gdpr::email_hash,
+    subscribe::SubscriptionStatus,
+    suppressions::{suppress, SuppressionReason},
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{rejection::JsonRejection, FromRef, Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::{collections::HashMap, str::FromStr};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DeliveryEventKind {
+    /// The address doesn't exist or will never accept mail.
+    HardBounce,
+    /// Temporary failure, like a full mailbox.
+    SoftBounce,
+    Complaint,
+}
+
+impl DeliveryEventKind {
+    /// Kind of the `subscriber_events` row, usable as `event.<kind>` in filters.
+    pub fn subscriber_event(&self) -> &'static str {
+        match self {
+            DeliveryEventKind::HardBounce => "bounced",
+            DeliveryEventKind::SoftBounce => "soft_bounced",
+            DeliveryEventKind::Complaint => "complained",
+        }
+    }
+}
+
+/// A provider-agnostic bounce or complaint.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeliveryEvent {
+    pub kind: DeliveryEventKind,
+    pub email: String,
+    pub occurred_at: DateTime<Utc>,
+    /// The issue the bounced message belonged to, if it can be told.
+    pub issue_id: Option<Uuid>,
+    /// What reported the event, e.g. `postmark` or `dsn`.
+    pub source: &'static str,
+    /// Provider-specific details, kept with the subscriber event.
+    pub details: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct DeliveryEventOutcome {
+    /// Matched a subscriber, otherwise only the suppression list was updated.
+    pub subscriber_id: Option<Uuid>,
+    pub suppressed: bool,
+    /// The soft bounce pushed the subscriber over the [`BouncePolicy`].
+    pub deactivated: bool,
+}
+
+/// When soft bounces deactivate a confirmed subscriber.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BouncePolicy {
+    /// Soft-bounced issues that deactivate a subscriber, `0` turns deactivation off.
+    pub soft_bounce_limit: u32,
+    /// How many of the subscriber's latest issues are looked at.
+    pub issue_window: u32,
+}
+
+impl From<BouncePolicyConfig> for BouncePolicy {
+    fn from(value: BouncePolicyConfig) -> Self {
+        Self {
+            soft_bounce_limit: value.soft_bounce_limit,
+            issue_window: value.issue_window,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BounceScore {
+    /// Issues sent to the subscriber within the window, at most `issue_window`.
+    pub issues: i64,
+    /// How many of those soft-bounced.
+    pub soft_bounced_issues: i64,
+}
+
+impl BounceScore {
+    /// Soft bounces that don't say which issue bounced, like most DSNs, count against the last
+    /// issue sent before them.
+    pub async fn load<'e>(
+        executor: impl sqlx::PgExecutor<'e>,
+        subscriber_id: Uuid,
+        issue_window: u32,
+    ) -> ServerResult<Self> {
+        sqlx::query_as!(
+            BounceScore,
+            r#"
+            WITH recent AS (
+                SELECT issue_id FROM issue_deliveries
+                WHERE subscriber_id = $1
+                ORDER BY sent_at DESC
+                LIMIT $2
+            ),
+            bounced AS (
+                SELECT COALESCE(subscriber_events.issue_id, latest.issue_id) AS issue_id
+                FROM subscriber_events
+                LEFT JOIN LATERAL (
+                    SELECT issue_id FROM issue_deliveries
+                    WHERE issue_deliveries.subscriber_id = subscriber_events.subscriber_id
+                        AND issue_deliveries.sent_at <= subscriber_events.occurred_at
+                    ORDER BY sent_at DESC
+                    LIMIT 1
+                ) AS latest ON true
+                WHERE subscriber_events.subscriber_id = $1 AND subscriber_events.kind = $3
+            )
+            SELECT
+                (SELECT COUNT(*) FROM recent) AS "issues!",
+                (
+                    SELECT COUNT(DISTINCT issue_id) FROM bounced
+                    WHERE issue_id IN (SELECT issue_id FROM recent)
+                ) AS "soft_bounced_issues!"
+            "#,
+            subscriber_id,
+            issue_window as i64,
+            DeliveryEventKind::SoftBounce.subscriber_event()
+        )
+        .fetch_one(executor)
+        .await
+        .map_err(ServerError::unexpected)
+    }
+
+    pub fn exceeds(&self, policy: &BouncePolicy) -> bool {
+        policy.soft_bounce_limit > 0 && self.soft_bounced_issues >= policy.soft_bounce_limit as i64
+    }
+}
+
+/// Updates the subscriber's status, suppresses the address for hard bounces and complaints, and
+/// records a subscriber event against the issue. Confirmed subscribers that soft-bounce too often
+/// under `policy` are deactivated.
+pub async fn process_delivery_event(
+    pool: &PgPool,
+    policy: &BouncePolicy,
+    event: &DeliveryEvent,
+) -> ServerResult<DeliveryEventOutcome> {
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+    let outcome = record_delivery_event(&mut transaction, policy, event).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    Ok(outcome)
+}
+
+/// [`process_delivery_event`] within the caller's transaction, so that several events can be
+/// committed together.
+#[instrument(skip(transaction, policy, event), fields(kind = %event.kind, source = event.source))]
+pub async fn record_delivery_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    policy: &BouncePolicy,
+    event: &DeliveryEvent,
+) -> ServerResult<DeliveryEventOutcome> {
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id, status FROM subscribers
+        WHERE lower(email) = lower($1)
+        FOR UPDATE
+        "#,
+        event.email.trim()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let suppression = match event.kind {
+        DeliveryEventKind::HardBounce => Some(SuppressionReason::HardBounce),
+        DeliveryEventKind::Complaint => Some(SuppressionReason::Complaint),
+        DeliveryEventKind::SoftBounce => None,
+    };
+    let suppressed = match suppression {
+        Some(reason) => {
+            suppress(
+                &mut **transaction,
+                Some(&event.email),
+                &email_hash(&event.email),
+                reason,
+                event.source,
+            )
+            .await?
+        }
+        None => false,
+    };
+
+    let Some(subscriber) = subscriber else {
+        info!("No subscriber for the address");
+        return Ok(DeliveryEventOutcome {
+            subscriber_id: None,
+            suppressed,
+            deactivated: false,
+        });
+    };
+    let uuid = subscriber.id;
+    let status =
+        SubscriptionStatus::from_str(&subscriber.status).expect("Stored value must be valid");
+
+    let new_status = match (event.kind, status) {
+        // A complaint is the strongest signal, it overrides everything
+        (DeliveryEventKind::Complaint, SubscriptionStatus::Complained) => None,
+        (DeliveryEventKind::Complaint, _) => Some(SubscriptionStatus::Complained),
+        // Someone who already left keeps that status
+        (
+            DeliveryEventKind::HardBounce,
+            SubscriptionStatus::Pending
+            | SubscriptionStatus::Confirmed
+            | SubscriptionStatus::Deactivated,
+        ) => Some(SubscriptionStatus::Bounced),
+        (DeliveryEventKind::HardBounce | DeliveryEventKind::SoftBounce, _) => None,
+    };
+    if let Some(new_status) = new_status {
+        set_status(transaction, uuid, new_status).await?;
+        info!(?uuid, from = %status, to = %new_status, "Changed subscriber status");
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriber_events (id, subscriber_id, kind, issue_id, details, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        Uuid::new_v4(),
+        uuid,
+        event.kind.subscriber_event(),
+        event.issue_id,
+        json!({ "source": event.source, "details": event.details }),
+        event.occurred_at
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let mut deactivated = false;
+    if event.kind == DeliveryEventKind::SoftBounce && status == SubscriptionStatus::Confirmed {
+        let score = BounceScore::load(&mut **transaction, uuid, policy.issue_window).await?;
+        if score.exceeds(policy) {
+            set_status(transaction, uuid, SubscriptionStatus::Deactivated).await?;
+            sqlx::query!(
+                r#"
+                INSERT INTO subscriber_events (id, subscriber_id, kind, details, occurred_at)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+                Uuid::new_v4(),
+                uuid,
+                "deactivated",
+                json!({ "score": score, "policy": policy }),
+                Utc::now()
+            )
+            .execute(&mut **transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+            info!(
+                ?uuid,
+                ?score,
+                "Deactivated subscriber after repeated soft bounces"
+            );
+            deactivated = true;
+        }
+    }
+
+    // Marking mail as spam withdraws consent as much as unsubscribing does
+    if event.kind == DeliveryEventKind::Complaint {
+        record_consent(
+            &mut **transaction,
+            &[uuid],
+            ConsentKind::Unsubscribe,
+            None,
+            &ConsentSource::channel("complaint"),
+            json!({ "source": event.source, "issue_id": event.issue_id }),
+        )
+        .await?;
+    }
+
+    Ok(DeliveryEventOutcome {
+        subscriber_id: Some(uuid),
+        suppressed,
+        deactivated,
+    })
+}
+
+async fn set_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    status: SubscriptionStatus,
+) -> ServerResult<()> {
+    sqlx::query!(
+        r#"
+        UPDATE subscribers
+        SET status = $1
+        WHERE id = $2
+        "#,
+        status.to_string(),
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(())
+}
+
+/// The fields of Postmark's bounce and spam complaint webhooks that matter here.
+///
+/// https://postmarkapp.com/developer/webhooks/bounce-webhook
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PostmarkWebhook {
+    pub record_type: String,
+    #[serde(rename = "Type")]
+    pub kind: Option<String>,
+    #[serde(rename = "MessageID")]
+    pub message_id: Option<String>,
+    pub email: Option<String>,
+    pub bounced_at: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub details: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+impl PostmarkWebhook {
+    /// `None` for record types and bounce types that aren't about deliverability.
+    pub fn into_delivery_event(self) -> Option<DeliveryEvent> {
+        let kind = match (
+            self.record_type.as_str(),
+            self.kind.as_deref().unwrap_or_default(),
+        ) {
+            ("SpamComplaint", _) | ("Bounce", "SpamComplaint") => DeliveryEventKind::Complaint,
+            ("Bounce", "HardBounce" | "BadEmailAddress" | "ManuallyDeactivated") => {
+                DeliveryEventKind::HardBounce
+            }
+            ("Bounce", "SoftBounce" | "Transient" | "DnsError" | "Blocked") => {
+                DeliveryEventKind::SoftBounce
+            }
+            _ => return None,
+        };
+
+        Some(DeliveryEvent {
+            kind,
+            email: self.email?,
+            occurred_at: self.bounced_at.unwrap_or_else(Utc::now),
+            issue_id: self
+                .metadata
+                .get("issue_id")
+                .and_then(|id| Uuid::parse_str(id).ok()),
+            source: "postmark",
+            details: json!({
+                "type": self.kind,
+                "message_id": self.message_id,
+                "description": self.description,
+                "details": self.details,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WebhookResponse {
+    /// Whether the payload was a bounce or complaint, other record types are acknowledged and
+    /// dropped.
+    pub processed: bool,
+    #[serde(flatten)]
+    pub outcome: Option<DeliveryEventOutcome>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BounceState {
+    pub pool: PgPool,
+    pub policy: BouncePolicy,
+}
+
+impl FromRef<BounceState> for PgPool {
+    fn from_ref(state: &BounceState) -> Self {
+        state.pool.clone()
+    }
+}
+
+/// Target for Postmark's bounce and spam complaint webhooks, with the credentials of a user in
+/// the webhook URL.
+pub async fn postmark_webhook(
+    _user: AuthUser,
+    State(state): State<BounceState>,
+    body: Result<Json<PostmarkWebhook>, JsonRejection>,
+) -> ApiResult<Json<WebhookResponse>> {
+    let Json(body) = body?;
+    let Some(event) = body.into_delivery_event() else {
+        return Ok(Json(WebhookResponse {
+            processed: false,
+            outcome: None,
+        }));
+    };
+
+    let outcome = process_delivery_event(&state.pool, &state.policy, &event).await?;
+
+    Ok(Json(WebhookResponse {
+        processed: true,
+        outcome: Some(outcome),
+    }))
+}
+
+pub async fn get_bounce_policy(
+    _user: AuthUser,
+    State(state): State<BounceState>,
+) -> Json<BouncePolicy> {
+    Json(state.policy)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriberBounces {
+    pub status: String,
+    pub score: BounceScore,
+    pub policy: BouncePolicy,
+    pub hard_bounces: i64,
+    /// When the policy last deactivated the subscriber.
+    pub deactivated_at: Option<DateTime<Utc>>,
+}
+
+/// The subscriber's standing against the bounce policy.
+pub async fn get_subscriber_bounces(
+    _user: AuthUser,
+    State(state): State<BounceState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<SubscriberBounces>> {
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT status,
+            (
+                SELECT COUNT(*) FROM subscriber_events
+                WHERE subscriber_id = subscribers.id AND kind = $2
+            ) AS "hard_bounces!",
+            (
+                SELECT MAX(occurred_at) FROM subscriber_events
+                WHERE subscriber_id = subscribers.id AND kind = 'deactivated'
+            ) AS deactivated_at
+        FROM subscribers
+        WHERE id = $1
+        "#,
+        id,
+        DeliveryEventKind::HardBounce.subscriber_event()
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {id}")))?;
+
+    Ok(Json(SubscriberBounces {
+        status: subscriber.status,
+        score: BounceScore::load(&state.pool, id, state.policy.issue_window).await?,
+        policy: state.policy,
+        hard_bounces: subscriber.hard_bounces,
+        deactivated_at: subscriber.deactivated_at,
+    }))
+}
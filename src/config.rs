@@ -1,6 +1,6 @@
 use crate::{email::EmailAdderess, helpers};
 use anyhow::Result;
-use std::time::Duration;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 #[derive(Debug, serde::Deserialize)]
 #[allow(dead_code)]
@@ -8,6 +8,20 @@ pub struct Config {
     pub app: AppConfig,
     pub database: DatabaseConfig,
     pub email_client: EmailClientConfig,
+    pub domain_check: DomainCheckConfig,
+    pub bot_protection: BotProtectionConfig,
+    pub confirmation_throttle: ConfirmationThrottleConfig,
+    pub confirmation: ConfirmationConfig,
+    pub data_requests: DataRequestsConfig,
+    pub pruning: PruningConfig,
+    pub bounce_mailbox: BounceMailboxConfig,
+    pub bounce_policy: BouncePolicyConfig,
+    pub scheduler: SchedulerConfig,
+    pub idempotency: IdempotencyConfig,
+    pub transactional_emails: TransactionalEmailsConfig,
+    /// Keyed by the list's slug, which is what the subscribe form refers to.
+    #[serde(default)]
+    pub lists: HashMap<String, ListConfig>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -15,6 +29,8 @@ pub struct Config {
 pub struct AppConfig {
     pub socket_addr: helpers::SocketAddr,
     pub public_url: Option<helpers::Url>,
+    /// Take the client IP from `X-Forwarded-For`, only enable when running behind a reverse proxy.
+    pub trust_x_forwarded_for: bool,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -32,6 +48,166 @@ pub struct EmailClientConfig {
     pub sender_email: EmailAdderess,
     #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
     pub timeout_ms: Duration,
+    /// Emails of an issue or test send that are in flight at once.
+    pub max_concurrent_sends: usize,
+}
+
+/// DNS check for the MX/A records of a subscriber's email domain.
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct DomainCheckConfig {
+    pub enabled: bool,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub timeout_ms: Duration,
+    /// Accept the subscriber anyway if the resolver errors out or times out.
+    pub fail_open: bool,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct BotProtectionConfig {
+    pub honeypot: bool,
+    /// Signed form tokens are required once a secret is set.
+    pub form_token_secret: Option<String>,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub form_token_min_age_ms: Duration,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub form_token_max_age_ms: Duration,
+    /// `siteverify` endpoint of hCaptcha, reCAPTCHA or Turnstile.
+    pub captcha_verify_url: Option<helpers::Url>,
+    pub captcha_secret: Option<String>,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub captcha_timeout_ms: Duration,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct ConfirmationThrottleConfig {
+    pub per_email: ThrottleRuleConfig,
+    pub per_ip: ThrottleRuleConfig,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct ThrottleRuleConfig {
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub cooldown_ms: Duration,
+    pub max_sends: u32,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub window_ms: Duration,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct ConfirmationConfig {
+    /// Confirmation links older than this are rejected, a fresh one gets sent on re-subscribe.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub token_ttl_ms: Duration,
+    /// Redirect here instead of showing the built-in pages, when confirmed.
+    pub success_redirect_url: Option<helpers::Url>,
+    /// Redirect here instead of showing the built-in pages, when the token is invalid or expired.
+    pub failure_redirect_url: Option<helpers::Url>,
+    pub theme: PageThemeConfig,
+}
+
+/// Look of the built-in HTML landing pages.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct PageThemeConfig {
+    pub brand_name: String,
+    pub accent_color: String,
+    pub logo_url: Option<helpers::Url>,
+    /// Extra stylesheet that gets linked after the built-in styles.
+    pub stylesheet_url: Option<helpers::Url>,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct DataRequestsConfig {
+    /// Lets subscribers request their own data export or erasure through an emailed link.
+    pub self_service: bool,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub token_ttl_ms: Duration,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct PruningConfig {
+    /// Run the pruning job in the background of the server, off by default since it deletes
+    /// data. `mailmule prune` works either way.
+    pub enabled: bool,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub interval_ms: Duration,
+    /// Never-confirmed subscribers are deleted once their last signup is this old.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub pending_retention_ms: Duration,
+}
+
+/// Maildir that bounces from a self-hosted relay are delivered to.
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct BounceMailboxConfig {
+    /// Polled in the background when set, `mailmule ingest-bounces` works either way.
+    pub maildir: Option<PathBuf>,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub interval_ms: Duration,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct SchedulerConfig {
+    /// Send scheduled issues from the background of the server. Any number of instances can run
+    /// it, each issue is only sent by one of them.
+    pub enabled: bool,
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub interval_ms: Duration,
+    /// IANA name, e.g. `Europe/Berlin`, for schedules given without an offset.
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub timezone: chrono_tz::Tz,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct IdempotencyConfig {
+    /// How long an `Idempotency-Key` and its response are kept for retries.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub ttl_ms: Duration,
+}
+
+/// Optional transactional emails, the confirmation email is always sent.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct TransactionalEmailsConfig {
+    /// Sent once a subscription is confirmed.
+    pub welcome: bool,
+    /// Sent after unsubscribing, from everything or a single list.
+    pub unsubscribe_receipt: bool,
+}
+
+/// Confirmed subscribers are deactivated once `soft_bounce_limit` of their last `issue_window`
+/// issues soft-bounced.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct BouncePolicyConfig {
+    /// `0` never deactivates anyone.
+    pub soft_bounce_limit: u32,
+    pub issue_window: u32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[allow(dead_code)]
+pub struct ListConfig {
+    pub name: String,
+    pub success_redirect_url: Option<helpers::Url>,
+    pub failure_redirect_url: Option<helpers::Url>,
 }
 
 impl Config {
@@ -39,9 +215,41 @@ impl Config {
         config::Config::builder()
             .add_source(config::File::with_name("mailmule"))
             .add_source(config::Environment::with_prefix("MM"))
+            .set_default("app.trust_x_forwarded_for", false)?
             .set_default("email_client.api_url", "https://api.postmarkapp.com")?
             .set_default("email_client.api_token", "POSTMARK_API_TEST")?
             .set_default("email_client.timeout_ms", "10000")?
+            .set_default("email_client.max_concurrent_sends", 10)?
+            .set_default("domain_check.enabled", false)?
+            .set_default("domain_check.timeout_ms", "3000")?
+            .set_default("domain_check.fail_open", true)?
+            .set_default("bot_protection.honeypot", true)?
+            .set_default("bot_protection.form_token_min_age_ms", "2000")?
+            .set_default("bot_protection.form_token_max_age_ms", "3600000")?
+            .set_default("bot_protection.captcha_timeout_ms", "10000")?
+            .set_default("confirmation_throttle.per_email.cooldown_ms", "60000")?
+            .set_default("confirmation_throttle.per_email.max_sends", 5)?
+            .set_default("confirmation_throttle.per_email.window_ms", "86400000")?
+            .set_default("confirmation_throttle.per_ip.cooldown_ms", "0")?
+            .set_default("confirmation_throttle.per_ip.max_sends", 20)?
+            .set_default("confirmation_throttle.per_ip.window_ms", "3600000")?
+            .set_default("confirmation.token_ttl_ms", "604800000")?
+            .set_default("confirmation.theme.brand_name", "mailmule")?
+            .set_default("confirmation.theme.accent_color", "#4f46e5")?
+            .set_default("data_requests.self_service", false)?
+            .set_default("data_requests.token_ttl_ms", "86400000")?
+            .set_default("pruning.enabled", false)?
+            .set_default("pruning.interval_ms", "3600000")?
+            .set_default("pruning.pending_retention_ms", "2592000000")?
+            .set_default("bounce_mailbox.interval_ms", "60000")?
+            .set_default("bounce_policy.soft_bounce_limit", "3")?
+            .set_default("bounce_policy.issue_window", "5")?
+            .set_default("scheduler.enabled", true)?
+            .set_default("scheduler.interval_ms", "30000")?
+            .set_default("scheduler.timezone", "UTC")?
+            .set_default("idempotency.ttl_ms", "86400000")?
+            .set_default("transactional_emails.welcome", false)?
+            .set_default("transactional_emails.unsubscribe_receipt", false)?
             .build()?
             .try_deserialize()
             .map_err(anyhow::Error::from)
@@ -54,8 +262,9 @@ impl AppConfig {
             .public_url
             .as_ref()
             .map(|url| url.0.clone())
-            .unwrap_or({
-                reqwest::Url::parse(&format!("http://{}", self.socket_addr.0.to_string()))?
-            }))
+            .unwrap_or(reqwest::Url::parse(&format!(
+                "http://{}",
+                self.socket_addr.0
+            ))?))
     }
 }
@@ -0,0 +1,201 @@
+use crate::{api::ApiResult, auth::AuthUser, helpers, ServerError, ServerResult};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ConsentKind {
+    /// Filled in the form or called the API, consent isn't given until it's confirmed.
+    Signup,
+    Confirmation,
+    Unsubscribe,
+    /// Brought in by an admin, with the consent collected elsewhere.
+    Import,
+}
+
+/// Where a consent change came from.
+#[derive(Debug, Clone)]
+pub struct ConsentSource {
+    /// `form`, `api`, `link`, `import` or `admin`.
+    pub channel: &'static str,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl ConsentSource {
+    pub fn from_request(
+        channel: &'static str,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+        trust_x_forwarded_for: bool,
+    ) -> Self {
+        Self {
+            channel,
+            ip: Some(helpers::client_ip(headers, peer, trust_x_forwarded_for)),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(Into::into),
+        }
+    }
+
+    pub fn channel(channel: &'static str) -> Self {
+        Self {
+            channel,
+            ip: None,
+            user_agent: None,
+        }
+    }
+}
+
+/// Appends to the consent log, `consent_records` rejects updates.
+pub async fn record_consent<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    subscriber_ids: &[Uuid],
+    kind: ConsentKind,
+    list: Option<&str>,
+    source: &ConsentSource,
+    details: Value,
+) -> ServerResult<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO consent_records (subscriber_id, kind, channel, list, ip, user_agent, details, occurred_at)
+        SELECT id, $2, $3, $4, $5, $6, $7, $8 FROM unnest($1::uuid[]) AS id
+        "#,
+        subscriber_ids,
+        kind.to_string(),
+        source.channel,
+        list,
+        source.ip.map(|ip| ip.to_string()),
+        source.user_agent,
+        details,
+        Utc::now()
+    )
+    .execute(executor)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ConsentRecord {
+    pub kind: String,
+    pub channel: String,
+    pub list: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+    pub occurred_at: DateTime<Utc>,
+}
+
+pub async fn consent_records(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> ServerResult<Vec<ConsentRecord>> {
+    sqlx::query_as!(
+        ConsentRecord,
+        r#"
+        SELECT kind, channel, list, ip, user_agent, details, occurred_at FROM consent_records
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ConsentExportQuery {
+    #[serde(default)]
+    pub format: ConsentExportFormat,
+}
+
+/// The consent log of one subscriber, oldest first.
+pub async fn export_consent(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ConsentExportQuery>,
+) -> ApiResult<Response> {
+    let email = sqlx::query_scalar!(
+        r#"
+        SELECT email FROM subscribers
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {id}")))?;
+    let records = consent_records(&pool, id).await?;
+
+    Ok(match query.format {
+        ConsentExportFormat::Json => Json(records).into_response(),
+        ConsentExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer
+                .write_record([
+                    "email",
+                    "kind",
+                    "channel",
+                    "list",
+                    "ip",
+                    "user_agent",
+                    "details",
+                    "occurred_at",
+                ])
+                .map_err(ServerError::unexpected)?;
+            for record in records {
+                writer
+                    .write_record([
+                        email.clone(),
+                        record.kind,
+                        record.channel,
+                        record.list.unwrap_or_default(),
+                        record.ip.unwrap_or_default(),
+                        record.user_agent.unwrap_or_default(),
+                        record.details.to_string(),
+                        record.occurred_at.to_rfc3339(),
+                    ])
+                    .map_err(ServerError::unexpected)?;
+            }
+            let body = writer
+                .into_inner()
+                .map_err(|e| ServerError::unexpected(e.into_error()))?;
+
+            (
+                [
+                    (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_owned()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"consent-{id}.csv\""),
+                    ),
+                ],
+                body,
+            )
+                .into_response()
+        }
+    })
+}
@@ -0,0 +1,272 @@
+use crate::{config::DomainCheckConfig, email::EmailAdderess, SubscribeError};
+use anyhow::Result;
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use std::{collections::HashSet, fmt::Debug, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+/// What the MX lookup of a domain found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MxRecords {
+    None,
+    Found,
+    /// A single `MX 0 .`, the domain says it accepts no mail at all (RFC 7505).
+    Null,
+}
+
+/// Looks up the DNS records that decide whether a domain can receive mail.
+#[async_trait::async_trait]
+pub trait DomainResolver: Debug + Send + Sync {
+    async fn mx_records(&self, domain: &str) -> Result<MxRecords>;
+    /// `Ok(false)` means the lookup succeeded but no A/AAAA records exist.
+    async fn has_address_records(&self, domain: &str) -> Result<bool>;
+}
+
+/// Resolver configured from the host's `/etc/resolv.conf` (or its platform equivalent).
+#[derive(Debug, Clone)]
+pub struct SystemResolver(TokioAsyncResolver);
+
+impl SystemResolver {
+    pub fn from_system_conf() -> Result<Self> {
+        Ok(Self(TokioAsyncResolver::tokio_from_system_conf()?))
+    }
+}
+
+fn found<T>(res: Result<T, ResolveError>) -> Result<Option<T>> {
+    match res {
+        Ok(found) => Ok(Some(found)),
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(None),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for SystemResolver {
+    async fn mx_records(&self, domain: &str) -> Result<MxRecords> {
+        // Trailing dot so that the search domains from the system config aren't appended
+        let Some(lookup) = found(self.0.mx_lookup(format!("{domain}.")).await)? else {
+            return Ok(MxRecords::None);
+        };
+        let mut exchanges = lookup.iter().map(|mx| mx.exchange());
+        Ok(match (exchanges.next(), exchanges.next()) {
+            (None, _) => MxRecords::None,
+            (Some(exchange), None) if exchange.is_root() => MxRecords::Null,
+            _ => MxRecords::Found,
+        })
+    }
+
+    async fn has_address_records(&self, domain: &str) -> Result<bool> {
+        Ok(found(self.0.lookup_ip(format!("{domain}.")).await)?.is_some())
+    }
+}
+
+/// In-memory resolver that never touches the network, meant for tests and local setups.
+#[derive(Debug, Default, Clone)]
+pub struct StubResolver {
+    pub mx: HashSet<String>,
+    pub null_mx: HashSet<String>,
+    pub address: HashSet<String>,
+    /// Every lookup errors out when set, as if the upstream resolver was unreachable.
+    pub failing: bool,
+}
+
+impl StubResolver {
+    // Domains are case-insensitive, lookups are lowercased too
+    pub fn with_mx(mut self, domain: &str) -> Self {
+        self.mx.insert(domain.to_lowercase());
+        self
+    }
+
+    pub fn with_null_mx(mut self, domain: &str) -> Self {
+        self.null_mx.insert(domain.to_lowercase());
+        self
+    }
+
+    pub fn with_address(mut self, domain: &str) -> Self {
+        self.address.insert(domain.to_lowercase());
+        self
+    }
+
+    pub fn failing(mut self) -> Self {
+        self.failing = true;
+        self
+    }
+
+    fn lookup(&self, records: &HashSet<String>, domain: &str) -> Result<bool> {
+        if self.failing {
+            anyhow::bail!("Stub resolver is set to fail");
+        }
+        Ok(records.contains(&domain.to_lowercase()))
+    }
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for StubResolver {
+    async fn mx_records(&self, domain: &str) -> Result<MxRecords> {
+        Ok(if self.lookup(&self.null_mx, domain)? {
+            MxRecords::Null
+        } else if self.lookup(&self.mx, domain)? {
+            MxRecords::Found
+        } else {
+            MxRecords::None
+        })
+    }
+
+    async fn has_address_records(&self, domain: &str) -> Result<bool> {
+        self.lookup(&self.address, domain)
+    }
+}
+
+/// Verifies that the domain of an email address can actually receive mail.
+#[derive(Debug, Clone)]
+pub struct DomainChecker {
+    pub resolver: Arc<dyn DomainResolver>,
+    pub timeout: Duration,
+    /// Let the address through if the lookup itself fails or times out.
+    pub fail_open: bool,
+}
+
+impl DomainChecker {
+    pub fn new(resolver: Arc<dyn DomainResolver>, timeout: Duration, fail_open: bool) -> Self {
+        Self {
+            resolver,
+            timeout,
+            fail_open,
+        }
+    }
+
+    /// A domain is deliverable if it has MX records, or falls back to its A/AAAA records
+    /// as the implicit MX (RFC 5321 section 5.1). A null MX rules out the fallback.
+    async fn is_deliverable(&self, domain: &str) -> Result<bool> {
+        match self.resolver.mx_records(domain).await? {
+            MxRecords::Found => Ok(true),
+            MxRecords::Null => Ok(false),
+            MxRecords::None => self.resolver.has_address_records(domain).await,
+        }
+    }
+
+    pub async fn check(&self, email: &EmailAdderess) -> Result<(), SubscribeError> {
+        let domain = email.domain();
+        let res = tokio::time::timeout(self.timeout, self.is_deliverable(domain))
+            .await
+            .unwrap_or_else(|elapsed| Err(elapsed.into()));
+
+        match res {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                info!(domain, "Domain has no MX or A records, or a null MX");
+                Err(SubscribeError::UndeliverableDomain(domain.into()))
+            }
+            Err(err) if self.fail_open => {
+                warn!(domain, err = ?err.context("Domain check failed, letting it through"));
+                Ok(())
+            }
+            Err(err) => Err(SubscribeError::DomainCheckFailed(err)),
+        }
+    }
+}
+
+impl TryFrom<DomainCheckConfig> for DomainChecker {
+    type Error = anyhow::Error;
+
+    fn try_from(value: DomainCheckConfig) -> std::result::Result<Self, Self::Error> {
+        Ok(Self::new(
+            Arc::new(SystemResolver::from_system_conf()?),
+            value.timeout_ms,
+            value.fail_open,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct SlowResolver;
+
+    #[async_trait::async_trait]
+    impl DomainResolver for SlowResolver {
+        async fn mx_records(&self, _domain: &str) -> Result<MxRecords> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(MxRecords::Found)
+        }
+
+        async fn has_address_records(&self, _domain: &str) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn checker(resolver: impl DomainResolver + 'static, fail_open: bool) -> DomainChecker {
+        DomainChecker::new(Arc::new(resolver), Duration::from_millis(50), fail_open)
+    }
+
+    fn email(s: &str) -> EmailAdderess {
+        EmailAdderess::new(s.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_domains_with_mx_records() {
+        let checker = checker(StubResolver::default().with_mx("Example.com"), false);
+        assert!(checker.check(&email("ann@example.com")).await.is_ok());
+        assert!(checker.check(&email("ann@EXAMPLE.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_address_records() {
+        let checker = checker(StubResolver::default().with_address("example.com"), false);
+        assert!(checker.check(&email("ann@example.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_domains_without_records() {
+        let checker = checker(StubResolver::default().with_mx("example.com"), false);
+        assert!(matches!(
+            checker.check(&email("ann@example.org")).await,
+            Err(SubscribeError::UndeliverableDomain(domain)) if domain == "example.org"
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_null_mx_even_with_address_records() {
+        let resolver = StubResolver::default()
+            .with_null_mx("example.com")
+            .with_address("example.com");
+        assert!(matches!(
+            checker(resolver, false)
+                .check(&email("ann@example.com"))
+                .await,
+            Err(SubscribeError::UndeliverableDomain(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn lookup_failures_follow_fail_open() {
+        let closed = checker(StubResolver::default().failing(), false);
+        assert!(matches!(
+            closed.check(&email("ann@example.com")).await,
+            Err(SubscribeError::DomainCheckFailed(_))
+        ));
+
+        let open = checker(StubResolver::default().failing(), true);
+        assert!(open.check(&email("ann@example.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn timeouts_follow_fail_open() {
+        assert!(matches!(
+            checker(SlowResolver, false)
+                .check(&email("ann@example.com"))
+                .await,
+            Err(SubscribeError::DomainCheckFailed(_))
+        ));
+        assert!(checker(SlowResolver, true)
+            .check(&email("ann@example.com"))
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,450 @@
+//! RFC 3464 delivery status notifications, for bounces that come back to a mailbox instead of a
+//! provider webhook.
+//!
+//! Notifications are picked up from a Maildir and fed into the same pipeline as the webhooks.
+
+use crate::bounces::{record_delivery_event, BouncePolicy, DeliveryEvent, DeliveryEventKind};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use mail_parser::{MessageParser, MimeHeaders};
+use serde_json::json;
+use sqlx::PgPool;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// The per-recipient fields of a delivery status notification.
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct RecipientStatus {
+    pub original_recipient: Option<String>,
+    pub final_recipient: Option<String>,
+    /// `failed`, `delayed`, `delivered`, `relayed` or `expanded`.
+    pub action: String,
+    /// Enhanced status code (RFC 3463), e.g. `5.1.1`.
+    pub status: String,
+    pub diagnostic_code: Option<String>,
+    pub last_attempt_date: Option<DateTime<Utc>>,
+}
+
+impl RecipientStatus {
+    /// The address the message was originally sent to, which is what subscribers are matched on.
+    pub fn recipient(&self) -> Option<&str> {
+        self.original_recipient
+            .as_deref()
+            .or(self.final_recipient.as_deref())
+    }
+
+    /// `None` for successful deliveries and anything else that isn't a bounce, delays included
+    /// since the message is still being retried.
+    pub fn classify(&self) -> Option<DeliveryEventKind> {
+        match self.action.as_str() {
+            "failed" if self.status.starts_with('5') => match self.status.as_str() {
+                // Mailbox full and message too big can get better on their own
+                "5.2.2" | "5.3.4" => Some(DeliveryEventKind::SoftBounce),
+                _ => Some(DeliveryEventKind::HardBounce),
+            },
+            "failed" => Some(DeliveryEventKind::SoftBounce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Dsn {
+    pub reporting_mta: Option<String>,
+    pub arrival_date: Option<DateTime<Utc>>,
+    /// `Message-ID` of the message that bounced, if the notification included it.
+    pub original_message_id: Option<String>,
+    pub recipients: Vec<RecipientStatus>,
+}
+
+/// Splits a `message/delivery-status` body into its field groups, unfolding continuation lines.
+/// Field names are lowercased.
+fn field_groups(body: &str) -> Vec<Vec<(String, String)>> {
+    let mut groups = Vec::new();
+    let mut group: Vec<(String, String)> = Vec::new();
+    for line in body.lines() {
+        if line.trim().is_empty() {
+            if !group.is_empty() {
+                groups.push(std::mem::take(&mut group));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = group.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            group.push((name.trim().to_lowercase(), value.trim().to_owned()));
+        }
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+fn field<'a>(group: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    group
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// `rfc822; someone@example.com` to `someone@example.com`.
+fn address(value: &str) -> Option<String> {
+    let address = value
+        .split_once(';')
+        .map_or(value, |(_, address)| address)
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    address.contains('@').then(|| address.to_lowercase())
+}
+
+/// `smtp; 550 5.1.1 User unknown` to `550 5.1.1 User unknown`.
+fn diagnostic(value: &str) -> String {
+    value
+        .split_once(';')
+        .map_or(value, |(_, diagnostic)| diagnostic)
+        .trim()
+        .to_owned()
+}
+
+fn date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Parses a `multipart/report` delivery status notification, `None` if `raw` isn't one.
+pub fn parse_dsn(raw: &[u8]) -> Option<Dsn> {
+    let message = MessageParser::default().parse(raw)?;
+
+    let status_part = message.parts.iter().find(|part| {
+        part.is_content_type("message", "delivery-status")
+            || part.is_content_type("message", "global-delivery-status")
+    })?;
+    let mut groups = field_groups(&String::from_utf8_lossy(status_part.contents())).into_iter();
+    let per_message = groups.next()?;
+
+    let recipients = groups
+        .map(|group| RecipientStatus {
+            original_recipient: field(&group, "original-recipient").and_then(address),
+            final_recipient: field(&group, "final-recipient").and_then(address),
+            action: field(&group, "action").unwrap_or_default().to_lowercase(),
+            status: field(&group, "status")
+                .and_then(|status| status.split_whitespace().next())
+                .unwrap_or_default()
+                .to_owned(),
+            diagnostic_code: field(&group, "diagnostic-code").map(diagnostic),
+            last_attempt_date: field(&group, "last-attempt-date").and_then(date),
+        })
+        .filter(|recipient| recipient.recipient().is_some())
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        return None;
+    }
+
+    // The returned message, or only its headers
+    let original_message_id = message.parts.iter().find_map(|part| {
+        if let Some(original) = part.message() {
+            return original.message_id().map(Into::into);
+        }
+        part.is_content_type("text", "rfc822-headers")
+            .then(|| MessageParser::default().parse_headers(part.contents()))
+            .flatten()
+            .and_then(|headers| headers.message_id().map(Into::into))
+    });
+
+    Some(Dsn {
+        reporting_mta: field(&per_message, "reporting-mta").map(diagnostic),
+        arrival_date: field(&per_message, "arrival-date").and_then(date),
+        original_message_id,
+        recipients,
+    })
+}
+
+impl Dsn {
+    pub fn delivery_events(&self) -> Vec<DeliveryEvent> {
+        self.recipients
+            .iter()
+            .filter_map(|recipient| {
+                Some(DeliveryEvent {
+                    kind: recipient.classify()?,
+                    email: recipient.recipient()?.into(),
+                    occurred_at: recipient
+                        .last_attempt_date
+                        .or(self.arrival_date)
+                        .unwrap_or_else(Utc::now),
+                    issue_id: None,
+                    source: "dsn",
+                    details: json!({
+                        "action": recipient.action,
+                        "status": recipient.status,
+                        "diagnostic_code": recipient.diagnostic_code,
+                        "reporting_mta": self.reporting_mta,
+                        "original_message_id": self.original_message_id,
+                    }),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct MailboxReport {
+    pub messages: u64,
+    /// Messages that weren't delivery status notifications, they're left unread in `cur`.
+    pub skipped: u64,
+    pub events: u64,
+    /// Messages that failed to process, they stay in `new` and are retried on the next run.
+    pub failed: u64,
+}
+
+/// Key of the advisory lock that keeps two instances from reading the same mailbox.
+const MAILBOX_LOCK_KEY: i64 = 0x6d61_696c_626f_7865;
+
+/// Reads delivery status notifications from the `new` directory of a Maildir, moving every
+/// processed message to `cur`, like a mail client would.
+#[derive(Debug, Clone)]
+pub struct BounceMailbox {
+    pub maildir: PathBuf,
+    pub policy: BouncePolicy,
+}
+
+impl BounceMailbox {
+    pub fn new(maildir: impl Into<PathBuf>, policy: BouncePolicy) -> Self {
+        Self {
+            maildir: maildir.into(),
+            policy,
+        }
+    }
+
+    /// Moves `path` to `cur`, with the `S`een flag when it was handled.
+    async fn file_away(&self, path: &Path, seen: bool) -> Result<()> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Maildir entries must have UTF-8 names")?;
+        let flags = if seen { ":2,S" } else { ":2," };
+        tokio::fs::rename(
+            path,
+            self.maildir.join("cur").join(format!("{name}{flags}")),
+        )
+        .await
+        .with_context(|| format!("Failed to move {} to cur", path.display()))
+    }
+
+    async fn process_message(&self, pool: &PgPool, path: &Path) -> Result<Option<u64>> {
+        let raw = tokio::fs::read(path).await?;
+        let Some(dsn) = parse_dsn(&raw) else {
+            return Ok(None);
+        };
+
+        // All or nothing, so that a retried message doesn't record its events twice
+        let events = dsn.delivery_events();
+        let mut transaction = pool.begin().await?;
+        for event in &events {
+            record_delivery_event(&mut transaction, &self.policy, event).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(Some(events.len() as u64))
+    }
+
+    /// Does nothing if another instance is already reading the mailbox.
+    pub async fn run(&self, pool: &PgPool) -> Result<MailboxReport> {
+        let mut report = MailboxReport::default();
+
+        // Held until the transaction ends with this function
+        let mut lock = pool.begin().await?;
+        let locked = sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_xact_lock($1) AS "locked!""#,
+            MAILBOX_LOCK_KEY
+        )
+        .fetch_one(&mut *lock)
+        .await?;
+        if !locked {
+            info!("Another instance is processing the bounce mailbox");
+            return Ok(report);
+        }
+
+        let mut paths = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.maildir.join("new"))
+            .await
+            .with_context(|| format!("Failed to read {}/new", self.maildir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            // Dotfiles are still being written by the delivery agent
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            paths.push(entry.path());
+        }
+        paths.sort();
+
+        for path in paths {
+            report.messages += 1;
+            match self.process_message(pool, &path).await {
+                Ok(Some(events)) => {
+                    report.events += events;
+                    if let Err(e) = self.file_away(&path, true).await {
+                        // It's processed again on the next run
+                        error!(path = %path.display(), error = ?e, "Failed to file away a bounce");
+                    }
+                }
+                Ok(None) => {
+                    warn!(path = %path.display(), "Not a delivery status notification");
+                    report.skipped += 1;
+                    if let Err(e) = self.file_away(&path, false).await {
+                        error!(path = %path.display(), error = ?e, "Failed to file away a message");
+                    }
+                }
+                Err(e) => {
+                    error!(path = %path.display(), error = ?e, "Failed to process a bounce");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            messages = report.messages,
+            skipped = report.skipped,
+            events = report.events,
+            failed = report.failed,
+            "Processed the bounce mailbox"
+        );
+
+        Ok(report)
+    }
+
+    /// Runs forever, every `interval`.
+    pub async fn run_periodically(self, pool: PgPool, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.run(&pool).await {
+                error!(error = ?e, "Processing the bounce mailbox failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSN: &str = "From: MAILER-DAEMON@mx.example.com\r
+To: bounces@news.example.com\r
+Subject: Undelivered Mail Returned to Sender\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b1\"\r
+\r
+--b1\r
+Content-Type: text/plain\r
+\r
+Your message could not be delivered.\r
+--b1\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mx.example.com\r
+Arrival-Date: Mon, 2 Oct 2023 10:00:00 +0000\r
+\r
+Original-Recipient: rfc822; Ann@Example.org\r
+Final-Recipient: rfc822; ann@mail.example.org\r
+Action: failed\r
+Status: 5.1.1\r
+Diagnostic-Code: smtp; 550 5.1.1 User unknown\r
+Last-Attempt-Date: Mon, 2 Oct 2023 10:05:00 +0000\r
+\r
+Final-Recipient: rfc822; <bob@example.org>\r
+Action: delayed\r
+Status: 4.4.1 (connection timed out)\r
+Diagnostic-Code: smtp; 421 4.4.1 Try again\r
+ later\r
+\r
+--b1\r
+Content-Type: text/rfc822-headers\r
+\r
+From: news@news.example.com\r
+Message-ID: <issue-1@news.example.com>\r
+\r
+--b1--\r
+";
+
+    fn status(action: &str, status: &str) -> RecipientStatus {
+        RecipientStatus {
+            final_recipient: Some("ann@example.org".into()),
+            action: action.into(),
+            status: status.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_a_dsn() {
+        let dsn = parse_dsn(DSN.as_bytes()).unwrap();
+        assert_eq!(dsn.reporting_mta.as_deref(), Some("mx.example.com"));
+        assert_eq!(
+            dsn.original_message_id.as_deref(),
+            Some("issue-1@news.example.com")
+        );
+        assert_eq!(dsn.recipients.len(), 2);
+
+        let ann = &dsn.recipients[0];
+        assert_eq!(ann.recipient(), Some("ann@example.org"));
+        assert_eq!(ann.action, "failed");
+        assert_eq!(ann.status, "5.1.1");
+        assert_eq!(
+            ann.diagnostic_code.as_deref(),
+            Some("550 5.1.1 User unknown")
+        );
+        assert!(ann.last_attempt_date.is_some());
+
+        let bob = &dsn.recipients[1];
+        assert_eq!(bob.recipient(), Some("bob@example.org"));
+        assert_eq!(bob.status, "4.4.1");
+        assert_eq!(
+            bob.diagnostic_code.as_deref(),
+            Some("421 4.4.1 Try again later")
+        );
+    }
+
+    #[test]
+    fn only_bounces_become_events() {
+        let events = parse_dsn(DSN.as_bytes()).unwrap().delivery_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, DeliveryEventKind::HardBounce);
+        assert_eq!(events[0].email, "ann@example.org");
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert!(parse_dsn(b"From: ann@example.org\r\nSubject: Hi\r\n\r\nHello").is_none());
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        assert_eq!(
+            status("failed", "5.1.1").classify(),
+            Some(DeliveryEventKind::HardBounce)
+        );
+        assert_eq!(
+            status("failed", "5.2.2").classify(),
+            Some(DeliveryEventKind::SoftBounce)
+        );
+        assert_eq!(
+            status("failed", "5.3.4").classify(),
+            Some(DeliveryEventKind::SoftBounce)
+        );
+        assert_eq!(
+            status("failed", "4.2.2").classify(),
+            Some(DeliveryEventKind::SoftBounce)
+        );
+        assert_eq!(status("delayed", "4.4.1").classify(), None);
+        assert_eq!(status("delivered", "2.0.0").classify(), None);
+        assert_eq!(status("relayed", "2.0.0").classify(), None);
+    }
+}
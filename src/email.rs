@@ -1,6 +1,6 @@
-use crate::config::EmailClientConfig;
+use crate::{config::EmailClientConfig, suppressions::SuppressionList};
 use anyhow::{bail, Result};
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct EmailAdderess(String);
@@ -15,6 +15,15 @@ impl EmailAdderess {
     }
 }
 
+impl EmailAdderess {
+    pub fn domain(&self) -> &str {
+        self.0
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .expect("Validated email must contain '@'")
+    }
+}
+
 impl AsRef<str> for EmailAdderess {
     fn as_ref(&self) -> &str {
         &self.0
@@ -38,6 +47,18 @@ pub struct EmailClient {
     pub api_url: reqwest::Url,
     pub api_token: String,
     pub sender_email: EmailAdderess,
+    /// Sends to suppressed addresses fail with [`Suppressed`] when set.
+    pub suppressions: Option<SuppressionList>,
+    /// For callers sending many emails at once.
+    pub max_concurrent_sends: usize,
+}
+
+/// The recipient is on the suppression list, nothing was sent.
+#[derive(Debug, thiserror::Error)]
+#[error("{email} is suppressed ({reason})")]
+pub struct Suppressed {
+    pub email: String,
+    pub reason: String,
 }
 
 impl EmailClient {
@@ -52,20 +73,37 @@ impl EmailClient {
             api_url,
             api_token,
             sender_email,
+            suppressions: None,
+            max_concurrent_sends: 10,
         })
     }
+
+    pub fn with_suppressions(self, suppressions: SuppressionList) -> Self {
+        Self {
+            suppressions: Some(suppressions),
+            ..self
+        }
+    }
+
+    pub fn with_max_concurrent_sends(self, max_concurrent_sends: usize) -> Self {
+        Self {
+            max_concurrent_sends: max_concurrent_sends.max(1),
+            ..self
+        }
+    }
 }
 
 impl TryFrom<EmailClientConfig> for EmailClient {
     type Error = anyhow::Error;
 
     fn try_from(value: EmailClientConfig) -> std::result::Result<Self, Self::Error> {
-        Self::new(
+        Ok(Self::new(
             value.timeout_ms,
             value.api_url.0,
             value.api_token,
             value.sender_email,
-        )
+        )?
+        .with_max_concurrent_sends(value.max_concurrent_sends))
     }
 }
 
@@ -77,6 +115,18 @@ pub struct EmailRequestBody<'a> {
     pub subject: &'a str,
     pub text_body: &'a str,
     pub html_body: &'a str,
+    /// Comes back in bounce and complaint webhooks.
+    #[serde(default, borrow, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<&'a str, &'a str>,
+    #[serde(default, borrow, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<EmailHeader<'a>>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
 }
 
 impl EmailClient {
@@ -88,12 +138,42 @@ impl EmailClient {
         text_body: &str,
         html_body: &str,
     ) -> Result<()> {
+        self.send_email_with_metadata(to, subject, text_body, html_body, &[], &[])
+            .await
+    }
+
+    /// Like [`Self::send_email`], with key-value pairs attached to the message, e.g. the issue id,
+    /// and extra headers, e.g. `List-Unsubscribe`.
+    pub async fn send_email_with_metadata(
+        &self,
+        to: &EmailAdderess,
+        subject: &str,
+        text_body: &str,
+        html_body: &str,
+        metadata: &[(&str, &str)],
+        headers: &[(&str, &str)],
+    ) -> Result<()> {
+        if let Some(suppressions) = &self.suppressions {
+            if let Some(suppression) = suppressions.find(to.as_ref()).await? {
+                return Err(Suppressed {
+                    email: to.as_ref().into(),
+                    reason: suppression.reason,
+                }
+                .into());
+            }
+        }
+
         let request_body = EmailRequestBody {
             from: self.sender_email.as_ref(),
             to: to.as_ref(),
             subject,
             text_body,
             html_body,
+            metadata: metadata.iter().copied().collect(),
+            headers: headers
+                .iter()
+                .map(|&(name, value)| EmailHeader { name, value })
+                .collect(),
         };
 
         let send_email_api_endpoint = self.api_url.join("email")?;
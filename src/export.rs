@@ -0,0 +1,239 @@
+use crate::{
+    api::ApiResult, attributes::AttributeSchema, auth::AuthUser, helpers, segments::Audience,
+    subscribe::SubscriptionStatus, ServerError, ServerResult,
+};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{rejection::QueryRejection, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::str::FromStr;
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    pub status: Option<String>,
+    pub list: Option<String>,
+    /// Lower bound of `subscribed_at`, inclusive.
+    pub since: Option<String>,
+    /// Upper bound of `subscribed_at`, exclusive.
+    pub until: Option<String>,
+    #[serde(flatten)]
+    pub audience: Audience,
+}
+
+#[derive(Debug, sqlx::FromRow, serde::Serialize)]
+pub struct ExportRow {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub lists: Vec<String>,
+    pub tags: Vec<String>,
+    pub attributes: Value,
+}
+
+fn parse_bound(name: &str, value: Option<&str>) -> ServerResult<Option<DateTime<Utc>>> {
+    value
+        .map(|value| {
+            helpers::parse_datetime(value).ok_or_else(|| {
+                ServerError::BadRequest(format!("{name} must be a YYYY-MM-DD or RFC 3339 date"))
+            })
+        })
+        .transpose()
+}
+
+async fn export_query(
+    pool: &PgPool,
+    query: &ExportQuery,
+    schema: &AttributeSchema,
+) -> ServerResult<QueryBuilder<'static, Postgres>> {
+    let status = query
+        .status
+        .as_deref()
+        .map(|status| {
+            SubscriptionStatus::from_str(status)
+                .map_err(|_| ServerError::BadRequest(format!("Unknown status \"{status}\"")))
+        })
+        .transpose()?;
+    let since = parse_bound("since", query.since.as_deref())?;
+    let until = parse_bound("until", query.until.as_deref())?;
+    let filter = query.audience.resolve(pool, schema).await?;
+
+    let mut qb = QueryBuilder::new(
+        "SELECT id, email, name, status, subscribed_at, attributes, \
+        ARRAY(SELECT list FROM list_subscriptions \
+            WHERE list_subscriptions.subscriber_id = subscribers.id ORDER BY list) AS lists, \
+        ARRAY(SELECT tag FROM subscriber_tags \
+            WHERE subscriber_tags.subscriber_id = subscribers.id ORDER BY tag) AS tags \
+        FROM subscribers WHERE true",
+    );
+    if let Some(status) = status {
+        qb.push(" AND status = ").push_bind(status.to_string());
+    }
+    if let Some(list) = &query.list {
+        qb.push(
+            " AND EXISTS (SELECT 1 FROM list_subscriptions \
+            WHERE list_subscriptions.subscriber_id = subscribers.id AND list_subscriptions.list = ",
+        )
+        .push_bind(list.to_lowercase())
+        .push(")");
+    }
+    if let Some(since) = since {
+        qb.push(" AND subscribed_at >= ").push_bind(since);
+    }
+    if let Some(until) = until {
+        qb.push(" AND subscribed_at < ").push_bind(until);
+    }
+    if let Some(filter) = &filter {
+        qb.push(" AND ");
+        filter.push_sql(&mut qb, schema);
+    }
+    qb.push(" ORDER BY email");
+
+    Ok(qb)
+}
+
+fn attribute_to_field(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Spreadsheets run cells starting with these as formulas, names and attributes are user input.
+const FORMULA_PREFIXES: [u8; 6] = [b'=', b'+', b'-', b'@', b'\t', b'\r'];
+
+fn csv_record<I, T>(record: I) -> anyhow::Result<Vec<u8>>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(record.into_iter().map(|cell| {
+        let cell = cell.as_ref();
+        match cell.first() {
+            Some(first) if FORMULA_PREFIXES.contains(first) => [b"'", cell].concat(),
+            _ => cell.to_vec(),
+        }
+    }))?;
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Rows are encoded as they come out of the database, so memory use doesn't grow with the export.
+fn export_stream(
+    pool: PgPool,
+    mut qb: QueryBuilder<'static, Postgres>,
+    format: ExportFormat,
+    attribute_keys: Vec<String>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> {
+    async_stream::try_stream! {
+        if let ExportFormat::Csv = format {
+            yield Bytes::from(csv_record(
+                ["id", "email", "name", "status", "subscribed_at", "lists", "tags"]
+                    .into_iter()
+                    .chain(attribute_keys.iter().map(String::as_str)),
+            )?);
+        }
+
+        let mut rows = qb.build_query_as::<ExportRow>().fetch(&pool);
+        while let Some(row) = rows.try_next().await? {
+            let chunk = match format {
+                ExportFormat::Csv => csv_record(
+                        [
+                            row.id.to_string(),
+                            row.email,
+                            row.name,
+                            row.status,
+                            row.subscribed_at.to_rfc3339(),
+                            row.lists.join(";"),
+                            row.tags.join(";"),
+                        ]
+                        .into_iter()
+                        .chain(
+                            attribute_keys
+                                .iter()
+                                .map(|key| attribute_to_field(row.attributes.get(key))),
+                        ),
+                )?,
+                ExportFormat::Ndjson => {
+                    let mut line = serde_json::to_vec(&row)?;
+                    line.push(b'\n');
+                    line
+                }
+            };
+            yield Bytes::from(chunk);
+        }
+    }
+}
+
+/// CSV has a column per attribute and `;`-separated lists and tags. Feeding it back into the
+/// import restores emails, names and attributes, but not the status, lists or tags.
+pub async fn export_subscribers(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> ApiResult<Response> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let schema = AttributeSchema::load(&pool)
+        .await
+        .map_err(ServerError::Unexpected)?;
+    let qb = export_query(&pool, &query, &schema).await?;
+
+    let mut attribute_keys = schema.0.into_keys().collect::<Vec<_>>();
+    attribute_keys.sort();
+
+    let (content_type, extension) = match query.format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "ndjson"),
+    };
+    let stream = export_stream(pool, qb, query.format, attribute_keys)
+        .inspect_err(|e| error!(error = ?e, "Subscriber export failed mid-stream"));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"subscribers-{}.{extension}\"",
+                    Utc::now().format("%Y-%m-%d")
+                ),
+            ),
+        ],
+        StreamBody::new(stream),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_cells_are_not_formulas() {
+        let record = csv_record(["=1+1", "+1", "-1", "@SUM(A1)", "\tx", "\rx", "a=b", ""]).unwrap();
+        assert_eq!(
+            String::from_utf8(record).unwrap(),
+            "'=1+1,'+1,'-1,'@SUM(A1),'\tx,\"'\rx\",a=b,\n"
+        );
+    }
+}
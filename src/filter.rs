@@ -0,0 +1,763 @@
+//! A small filter language for picking subscribers, e.g.
+//!
+//! ```text
+//! attr.plan = "pro" and (list = "weekly" or tag = "beta-tester")
+//!     and subscribed_at >= "2026-01-01" and not event.soft_bounced within 90 days
+//! ```
+//!
+//! Predicates:
+//! - `attr.<key> <op> <value>` and `attr.<key> exists`, checked against the attribute schema
+//! - `list = "<slug>"`
+//! - `tag = "<tag>"`
+//! - `subscribed_at <op> "<YYYY-MM-DD or RFC 3339>"`
+//! - `event.<kind> within <n> days`, for the recorded events `bounced`, `soft_bounced`,
+//!   `complained` and `deactivated`, with `n` up to 36500
+//!
+//! Operators are `=`, `!=`, `<`, `<=`, `>`, `>=`; predicates combine with `and`, `or`, `not`
+//! and parentheses, nested at most 64 deep in a filter of at most 10000 bytes. Every value ends up
+//! as a bind parameter in the compiled SQL.
+//!
+//! A subscriber without the attribute never matches a comparison on it, except `!=`, and
+//! always matches its negation, e.g. both `attr.plan != "pro"` and `not attr.plan = "pro"` match
+//! subscribers that have no plan at all.
+
+use crate::attributes::{AttributeKind, AttributeSchema};
+use crate::helpers;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::{Postgres, QueryBuilder};
+
+/// Upper bound for `event.<kind> within <n> days`, a hundred years.
+const MAX_WITHIN_DAYS: u32 = 36_500;
+/// The parser recurses for every `not` and parenthesis, this keeps it off the end of the stack.
+const MAX_DEPTH: usize = 64;
+const MAX_LEN: usize = 10_000;
+/// What `subscriber_events` rows are recorded as.
+const EVENT_KINDS: [&str; 4] = ["bounced", "soft_bounced", "complained", "deactivated"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error("Invalid filter at position {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("Invalid filter: {0}")]
+    Schema(String),
+}
+
+impl FilterError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        Self::Syntax {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Ne => "IS DISTINCT FROM",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+
+    fn is_equality(&self) -> bool {
+        matches!(self, Self::Eq | Self::Ne)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Attribute {
+        key: String,
+        op: Comparison,
+        value: Literal,
+    },
+    AttributeExists(String),
+    List(String),
+    Tag(String),
+    SubscribedAt {
+        op: Comparison,
+        value: DateTime<Utc>,
+    },
+    Event {
+        kind: String,
+        within_days: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Number(f64),
+    Op(Comparison),
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push((pos, Token::LParen));
+            }
+            ')' => {
+                chars.next();
+                tokens.push((pos, Token::RParen));
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => return Err(FilterError::new(pos, "Unterminated string")),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(FilterError::new(pos, "Unterminated string")),
+                    }
+                }
+                tokens.push((pos, Token::String(s)));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if(|&(_, c)| c == '=').is_some();
+                let op = match (c, eq) {
+                    ('=', _) => Comparison::Eq,
+                    ('!', true) => Comparison::Ne,
+                    ('<', false) => Comparison::Lt,
+                    ('<', true) => Comparison::Le,
+                    ('>', false) => Comparison::Gt,
+                    ('>', true) => Comparison::Ge,
+                    _ => return Err(FilterError::new(pos, "Expected '!='")),
+                };
+                tokens.push((pos, Token::Op(op)));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_digit() || "-.".contains(c))
+                {
+                    s.push(c);
+                }
+                let n = s
+                    .parse()
+                    .map_err(|_| FilterError::new(pos, format!("Invalid number \"{s}\"")))?;
+                tokens.push((pos, Token::Number(n)));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_alphanumeric() || "_.-".contains(c))
+                {
+                    s.push(c);
+                }
+                tokens.push((pos, Token::Ident(s)));
+            }
+            c => return Err(FilterError::new(pos, format!("Unexpected character '{c}'"))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    cursor: usize,
+    end: usize,
+    /// Of `not`s and parentheses around the current position.
+    depth: usize,
+}
+
+impl Parser {
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.cursor)
+            .map(|(pos, _)| *pos)
+            .unwrap_or(self.end)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.cursor).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.cursor).map(|(_, token)| token.clone());
+        self.cursor += 1;
+        token
+    }
+
+    fn error(&self, message: impl Into<String>) -> FilterError {
+        FilterError::new(self.position(), message)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.cursor += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), FilterError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected '{keyword}'")))
+        }
+    }
+
+    fn expect_op(&mut self) -> Result<Comparison, FilterError> {
+        match self.next() {
+            Some(Token::Op(op)) => Ok(op),
+            _ => {
+                self.cursor -= 1;
+                Err(self.error("Expected a comparison operator"))
+            }
+        }
+    }
+
+    fn expect_string(&mut self) -> Result<String, FilterError> {
+        match self.next() {
+            Some(Token::String(s)) => Ok(s),
+            _ => {
+                self.cursor -= 1;
+                Err(self.error("Expected a quoted string"))
+            }
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Filter, FilterError> {
+        let mut lhs = self.and_expr()?;
+        while self.eat_keyword("or") {
+            lhs = Filter::Or(Box::new(lhs), Box::new(self.and_expr()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and_expr(&mut self) -> Result<Filter, FilterError> {
+        let mut lhs = self.unary()?;
+        while self.eat_keyword("and") {
+            lhs = Filter::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        let nests = self.peek().is_some_and(|token| match token {
+            Token::LParen => true,
+            Token::Ident(s) => s.eq_ignore_ascii_case("not"),
+            _ => false,
+        });
+        if !nests {
+            return self.predicate();
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(format!("Nested deeper than {MAX_DEPTH} levels")));
+        }
+
+        self.depth += 1;
+        let filter = if self.eat_keyword("not") {
+            Filter::Not(Box::new(self.unary()?))
+        } else {
+            self.cursor += 1;
+            let inner = self.or_expr()?;
+            if self.next() != Some(Token::RParen) {
+                self.cursor -= 1;
+                return Err(self.error("Expected ')'"));
+            }
+            inner
+        };
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn predicate(&mut self) -> Result<Filter, FilterError> {
+        let position = self.position();
+        let ident = match self.next() {
+            Some(Token::Ident(ident)) => ident,
+            _ => return Err(FilterError::new(position, "Expected a predicate")),
+        };
+
+        if let Some(key) = ident.strip_prefix("attr.") {
+            if self.eat_keyword("exists") {
+                return Ok(Filter::AttributeExists(key.into()));
+            }
+            let op = self.expect_op()?;
+            let value = match self.next() {
+                Some(Token::String(s)) => Literal::String(s),
+                Some(Token::Number(n)) => Literal::Number(n),
+                Some(Token::Ident(s)) if s == "true" => Literal::Bool(true),
+                Some(Token::Ident(s)) if s == "false" => Literal::Bool(false),
+                _ => {
+                    self.cursor -= 1;
+                    return Err(self.error("Expected a string, number or boolean"));
+                }
+            };
+            return Ok(Filter::Attribute {
+                key: key.into(),
+                op,
+                value,
+            });
+        }
+
+        if let Some(kind) = ident.strip_prefix("event.") {
+            if !EVENT_KINDS.contains(&kind) {
+                return Err(FilterError::new(
+                    position,
+                    format!(
+                        "Unknown event '{kind}', the recorded ones are {}",
+                        EVENT_KINDS.join(", ")
+                    ),
+                ));
+            }
+            self.expect_keyword("within")?;
+            let within_days = match self.next() {
+                Some(Token::Number(n)) if n >= 0.0 && n.fract() == 0.0 => {
+                    if n > MAX_WITHIN_DAYS as f64 {
+                        self.cursor -= 1;
+                        return Err(self.error(format!("Expected at most {MAX_WITHIN_DAYS} days")));
+                    }
+                    n as u32
+                }
+                _ => {
+                    self.cursor -= 1;
+                    return Err(self.error("Expected a whole number of days"));
+                }
+            };
+            self.expect_keyword("days")?;
+            return Ok(Filter::Event {
+                kind: kind.into(),
+                within_days,
+            });
+        }
+
+        match ident.as_str() {
+            "list" | "tag" => {
+                if self.expect_op()? != Comparison::Eq {
+                    return Err(FilterError::new(
+                        position,
+                        format!("'{ident}' only supports '='"),
+                    ));
+                }
+                let value = self.expect_string()?;
+                Ok(match ident.as_str() {
+                    "list" => Filter::List(value.to_lowercase()),
+                    _ => Filter::Tag(value.to_lowercase()),
+                })
+            }
+            "subscribed_at" => {
+                let op = self.expect_op()?;
+                let value_position = self.position();
+                let value = self.expect_string()?;
+                let value = helpers::parse_datetime(&value).ok_or_else(|| {
+                    FilterError::new(value_position, "Expected a YYYY-MM-DD or RFC 3339 date")
+                })?;
+                Ok(Filter::SubscribedAt { op, value })
+            }
+            _ => Err(FilterError::new(
+                position,
+                format!("Unknown predicate '{ident}'"),
+            )),
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Self, FilterError> {
+        if input.len() > MAX_LEN {
+            return Err(FilterError::new(
+                MAX_LEN,
+                format!("Longer than {MAX_LEN} bytes"),
+            ));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            cursor: 0,
+            end: input.len(),
+            depth: 0,
+        };
+        let filter = parser.or_expr()?;
+        if parser.peek().is_some() {
+            return Err(parser.error("Unexpected trailing input"));
+        }
+        Ok(filter)
+    }
+
+    /// Checks attribute predicates against the schema, so that a typo doesn't silently match nobody.
+    pub fn check(&self, schema: &AttributeSchema) -> Result<(), FilterError> {
+        match self {
+            Filter::And(lhs, rhs) | Filter::Or(lhs, rhs) => {
+                lhs.check(schema)?;
+                rhs.check(schema)
+            }
+            Filter::Not(inner) => inner.check(schema),
+            Filter::AttributeExists(key) | Filter::Attribute { key, .. }
+                if !schema.0.contains_key(key) =>
+            {
+                Err(FilterError::Schema(format!("Unknown attribute \"{key}\"")))
+            }
+            Filter::Attribute { key, op, value } => {
+                let kind = schema.0[key].kind;
+                let matches = match (kind, value) {
+                    (AttributeKind::Text, Literal::String(_)) => true,
+                    (AttributeKind::Number, Literal::Number(_)) => true,
+                    (AttributeKind::Boolean, Literal::Bool(_)) => op.is_equality(),
+                    (AttributeKind::Date, Literal::String(s)) => {
+                        NaiveDate::parse_from_str(s, "%Y-%m-%d").is_ok()
+                    }
+                    _ => false,
+                };
+                if matches {
+                    Ok(())
+                } else {
+                    Err(FilterError::Schema(format!(
+                        "Attribute \"{key}\" of type {kind} can't be compared that way"
+                    )))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends the filter as a boolean SQL expression over the `subscribers` table.
+    pub fn push_sql(&self, qb: &mut QueryBuilder<'_, Postgres>, schema: &AttributeSchema) {
+        match self {
+            Filter::And(lhs, rhs) | Filter::Or(lhs, rhs) => {
+                qb.push("(");
+                lhs.push_sql(qb, schema);
+                qb.push(if matches!(self, Filter::And(..)) {
+                    " AND "
+                } else {
+                    " OR "
+                });
+                rhs.push_sql(qb, schema);
+                qb.push(")");
+            }
+            Filter::Not(inner) => {
+                // Comparisons on a missing attribute are NULL, which `NOT` would keep as NULL
+                // and so match nobody either way
+                qb.push("NOT COALESCE((");
+                inner.push_sql(qb, schema);
+                qb.push("), false)");
+            }
+            Filter::AttributeExists(key) => {
+                qb.push("subscribers.attributes ? ").push_bind(key.clone());
+            }
+            Filter::Attribute { key, op, value } => {
+                let kind = schema
+                    .0
+                    .get(key)
+                    .map(|definition| definition.kind)
+                    .unwrap_or(AttributeKind::Text);
+                match (kind, value) {
+                    (AttributeKind::Number, Literal::Number(n)) => {
+                        // Guard the cast, a value stored before the attribute's kind was changed
+                        // shouldn't make the whole query error out
+                        qb.push("(CASE WHEN jsonb_typeof(subscribers.attributes -> ")
+                            .push_bind(key.clone())
+                            .push(") = 'number' THEN (subscribers.attributes ->> ")
+                            .push_bind(key.clone())
+                            .push(")::float8 END) ")
+                            .push(op.as_sql())
+                            .push(" ")
+                            .push_bind(*n);
+                    }
+                    (_, Literal::Bool(b)) => {
+                        qb.push("(subscribers.attributes -> ")
+                            .push_bind(key.clone())
+                            .push(") ")
+                            .push(op.as_sql())
+                            .push(" to_jsonb(")
+                            .push_bind(*b)
+                            .push(")");
+                    }
+                    // Dates are stored as YYYY-MM-DD, which compares correctly as text
+                    (_, Literal::String(s)) => {
+                        qb.push("(subscribers.attributes ->> ")
+                            .push_bind(key.clone())
+                            .push(") ")
+                            .push(op.as_sql())
+                            .push(" ")
+                            .push_bind(s.clone());
+                    }
+                    (_, Literal::Number(n)) => {
+                        qb.push("(subscribers.attributes ->> ")
+                            .push_bind(key.clone())
+                            .push(") ")
+                            .push(op.as_sql())
+                            .push(" ")
+                            .push_bind(n.to_string());
+                    }
+                }
+            }
+            Filter::List(list) => {
+                qb.push(
+                    "EXISTS (SELECT 1 FROM list_subscriptions \
+                    WHERE list_subscriptions.subscriber_id = subscribers.id AND list_subscriptions.list = ",
+                )
+                .push_bind(list.clone())
+                .push(")");
+            }
+            Filter::Tag(tag) => {
+                qb.push(
+                    "EXISTS (SELECT 1 FROM subscriber_tags \
+                    WHERE subscriber_tags.subscriber_id = subscribers.id AND subscriber_tags.tag = ",
+                )
+                .push_bind(tag.clone())
+                .push(")");
+            }
+            Filter::SubscribedAt { op, value } => {
+                qb.push("subscribers.subscribed_at ")
+                    .push(op.as_sql())
+                    .push(" ")
+                    .push_bind(*value);
+            }
+            Filter::Event { kind, within_days } => {
+                qb.push(
+                    "EXISTS (SELECT 1 FROM subscriber_events \
+                    WHERE subscriber_events.subscriber_id = subscribers.id AND subscriber_events.kind = ",
+                )
+                .push_bind(kind.clone())
+                .push(" AND subscriber_events.occurred_at >= now() - ")
+                // The parser keeps it within `MAX_WITHIN_DAYS`
+                .push_bind(*within_days as i32)
+                .push(" * INTERVAL '1 day')");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::AttributeDefinition;
+
+    fn attr(key: &str, op: Comparison, value: Literal) -> Filter {
+        Filter::Attribute {
+            key: key.into(),
+            op,
+            value,
+        }
+    }
+
+    fn syntax_error_at(input: &str) -> usize {
+        match Filter::parse(input) {
+            Err(FilterError::Syntax { position, .. }) => position,
+            other => panic!("Expected a syntax error for {input:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_predicates() {
+        assert_eq!(
+            Filter::parse(r#"attr.plan = "pro""#).unwrap(),
+            attr("plan", Comparison::Eq, Literal::String("pro".into()))
+        );
+        assert_eq!(
+            Filter::parse("attr.age >= 18").unwrap(),
+            attr("age", Comparison::Ge, Literal::Number(18.0))
+        );
+        assert_eq!(
+            Filter::parse("attr.vip != true").unwrap(),
+            attr("vip", Comparison::Ne, Literal::Bool(true))
+        );
+        assert_eq!(
+            Filter::parse("attr.city exists").unwrap(),
+            Filter::AttributeExists("city".into())
+        );
+        assert_eq!(
+            Filter::parse(r#"list = "Weekly""#).unwrap(),
+            Filter::List("weekly".into())
+        );
+        assert_eq!(
+            Filter::parse(r#"tag = "beta-tester""#).unwrap(),
+            Filter::Tag("beta-tester".into())
+        );
+        assert!(matches!(
+            Filter::parse(r#"subscribed_at < "2026-01-01""#).unwrap(),
+            Filter::SubscribedAt {
+                op: Comparison::Lt,
+                ..
+            }
+        ));
+        assert_eq!(
+            Filter::parse("event.bounced within 30 days").unwrap(),
+            Filter::Event {
+                kind: "bounced".into(),
+                within_days: 30
+            }
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let list = || Filter::List("a".into());
+        let tag = || Filter::Tag("b".into());
+        let exists = || Filter::AttributeExists("c".into());
+
+        assert_eq!(
+            Filter::parse(r#"list = "a" or tag = "b" and attr.c exists"#).unwrap(),
+            Filter::Or(
+                Box::new(list()),
+                Box::new(Filter::And(Box::new(tag()), Box::new(exists())))
+            )
+        );
+        assert_eq!(
+            Filter::parse(r#"not (list = "a" or tag = "b") AND attr.c exists"#).unwrap(),
+            Filter::And(
+                Box::new(Filter::Not(Box::new(Filter::Or(
+                    Box::new(list()),
+                    Box::new(tag())
+                )))),
+                Box::new(exists())
+            )
+        );
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        assert_eq!(
+            Filter::parse(r#"attr.name = "say \"hi\"""#).unwrap(),
+            attr(
+                "name",
+                Comparison::Eq,
+                Literal::String(r#"say "hi""#.into())
+            )
+        );
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        assert_eq!(syntax_error_at(r#"attr.plan = "pro"#), 12);
+        assert_eq!(syntax_error_at(r#"list != "a""#), 0);
+        assert_eq!(syntax_error_at(r#"(list = "a""#), 11);
+        assert_eq!(syntax_error_at(r#"list = "a" tag"#), 11);
+        assert_eq!(syntax_error_at("nope = 1"), 0);
+        assert_eq!(syntax_error_at("attr.a = 1 and"), 14);
+        assert_eq!(syntax_error_at(r#"subscribed_at > "yesterday""#), 16);
+        assert_eq!(syntax_error_at("attr.a ~ 1"), 7);
+    }
+
+    fn schema() -> AttributeSchema {
+        let definitions = [
+            ("age", AttributeKind::Number),
+            ("plan", AttributeKind::Text),
+        ]
+        .into_iter()
+        .map(|(key, kind)| {
+            let definition = AttributeDefinition {
+                key: key.into(),
+                kind,
+                description: None,
+                required: false,
+                created_at: Utc::now(),
+            };
+            (key.to_owned(), definition)
+        });
+        AttributeSchema(definitions.collect())
+    }
+
+    fn sql(input: &str) -> String {
+        let mut qb = QueryBuilder::new("");
+        Filter::parse(input).unwrap().push_sql(&mut qb, &schema());
+        qb.sql().to_owned()
+    }
+
+    #[test]
+    fn compiles_to_sql() {
+        assert_eq!(
+            sql(r#"attr.age != 3 and not attr.plan = "pro""#),
+            "((CASE WHEN jsonb_typeof(subscribers.attributes -> $1) = 'number' \
+            THEN (subscribers.attributes ->> $2)::float8 END) IS DISTINCT FROM $3 \
+            AND NOT COALESCE(((subscribers.attributes ->> $4) = $5), false))"
+        );
+        assert_eq!(
+            sql("attr.plan exists or event.bounced within 30 days"),
+            "(subscribers.attributes ? $1 OR EXISTS (SELECT 1 FROM subscriber_events \
+            WHERE subscriber_events.subscriber_id = subscribers.id \
+            AND subscriber_events.kind = $2 \
+            AND subscriber_events.occurred_at >= now() - $3 * INTERVAL '1 day'))"
+        );
+        assert_eq!(
+            sql(r#"list = "a" and tag = "b" and subscribed_at >= "2026-01-01""#),
+            "((EXISTS (SELECT 1 FROM list_subscriptions \
+            WHERE list_subscriptions.subscriber_id = subscribers.id \
+            AND list_subscriptions.list = $1) \
+            AND EXISTS (SELECT 1 FROM subscriber_tags \
+            WHERE subscriber_tags.subscriber_id = subscribers.id AND subscriber_tags.tag = $2)) \
+            AND subscribers.subscribed_at >= $3)"
+        );
+        assert_eq!(
+            sql("attr.vip = true"),
+            "(subscribers.attributes -> $1) = to_jsonb($2)"
+        );
+    }
+
+    #[test]
+    fn limits_nesting_and_length() {
+        let nested = |depth| format!("{}list = \"a\"{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Filter::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(syntax_error_at(&nested(MAX_DEPTH + 1)), MAX_DEPTH);
+        assert_eq!(syntax_error_at(&nested(1000)), MAX_DEPTH);
+        assert_eq!(
+            syntax_error_at(&format!("{}list = \"a\"", "not ".repeat(MAX_DEPTH + 1))),
+            4 * MAX_DEPTH
+        );
+        // Siblings don't add up
+        let siblings = vec![r#"(list = "a")"#; 500].join(" or ");
+        assert!(Filter::parse(&siblings).is_ok());
+        assert_eq!(syntax_error_at(&"(".repeat(MAX_LEN + 1)), MAX_LEN);
+    }
+
+    #[test]
+    fn rejects_unknown_events() {
+        for kind in EVENT_KINDS {
+            assert!(Filter::parse(&format!("event.{kind} within 30 days")).is_ok());
+        }
+        assert_eq!(syntax_error_at("event.bouncd within 30 days"), 0);
+    }
+
+    #[test]
+    fn bounds_event_windows() {
+        assert!(Filter::parse("event.bounced within 36500 days").is_ok());
+        assert_eq!(syntax_error_at("event.bounced within 36501 days"), 21);
+        assert_eq!(syntax_error_at("event.bounced within 4294967296 days"), 21);
+        assert_eq!(syntax_error_at("event.bounced within 1.5 days"), 21);
+        assert_eq!(syntax_error_at("event.bounced within -1 days"), 21);
+    }
+}
@@ -0,0 +1,686 @@
+//! Data subject access and erasure requests.
+//!
+//! Erasing an address deletes everything held on it and only keeps a SHA-256 hash in the
+//! `erasures` table, which is enough to stop the address from being imported again.
+
+use crate::{
+    api::ApiResult,
+    auth::AuthUser,
+    config::PageThemeConfig,
+    consent::{consent_records, ConsentRecord},
+    email::{EmailAdderess, EmailClient, Suppressed},
+    helpers::{self, escape_html},
+    pages::render_page,
+    subscribe::{gen_subscription_token, SUBSCRIPTION_TOKEN_LEN},
+    suppressions::SuppressionReason,
+    throttle::{ConfirmationThrottle, ThrottleScope},
+    transactional::{send_transactional, TransactionalKind, TransactionalRecipient},
+    ServerError, ServerResult, SubscribeError,
+};
+use axum::{
+    extract::{rejection::QueryRejection, ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+/// Hex SHA-256 of the trimmed, lowercased address.
+pub fn email_hash(email: &str) -> String {
+    hex::encode(Sha256::digest(email.trim().to_lowercase().as_bytes()))
+}
+
+/// Which of `hashes` belong to erased addresses.
+pub async fn erased_hashes(pool: &PgPool, hashes: &[String]) -> ServerResult<Vec<String>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT email_hash FROM erasures
+        WHERE email_hash = ANY($1)
+        "#,
+        hashes
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubjectData {
+    pub email: String,
+    pub generated_at: DateTime<Utc>,
+    /// Set if the address was erased before, in which case nothing else is held.
+    pub erased_at: Option<DateTime<Utc>>,
+    pub subscribers: Vec<SubscriberRecord>,
+    pub confirmation_sends: Option<ConfirmationSendsRecord>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriberRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub attributes: Value,
+    pub subscription_tokens: Vec<TokenRecord>,
+    pub lists: Vec<ListRecord>,
+    pub tags: Vec<TagRecord>,
+    pub tag_events: Vec<TagEventRecord>,
+    pub events: Vec<EventRecord>,
+    pub consent_records: Vec<ConsentRecord>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TokenRecord {
+    pub subscription_token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ListRecord {
+    pub list: String,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TagRecord {
+    pub tag: String,
+    pub tagged_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TagEventRecord {
+    pub tag: String,
+    pub action: String,
+    pub actor: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EventRecord {
+    pub kind: String,
+    pub issue_id: Option<Uuid>,
+    pub details: Value,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ConfirmationSendsRecord {
+    pub window_start: DateTime<Utc>,
+    pub sends: i32,
+    pub last_sent_at: DateTime<Utc>,
+}
+
+/// One subscriber with everything recorded about them, `None` if there's no such subscriber.
+pub async fn subscriber_record(pool: &PgPool, id: Uuid) -> ServerResult<Option<SubscriberRecord>> {
+    let Some(row) = sqlx::query!(
+        r#"
+        SELECT id, email, name, status, subscribed_at, attributes FROM subscribers
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    else {
+        return Ok(None);
+    };
+
+    let subscription_tokens = sqlx::query_as!(
+        TokenRecord,
+        r#"
+        SELECT subscription_token, created_at FROM subscription_tokens
+        WHERE subscriber_id = $1
+        "#,
+        row.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let lists = sqlx::query_as!(
+        ListRecord,
+        r#"
+        SELECT list, subscribed_at FROM list_subscriptions
+        WHERE subscriber_id = $1
+        ORDER BY list
+        "#,
+        row.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let tags = sqlx::query_as!(
+        TagRecord,
+        r#"
+        SELECT tag, tagged_at FROM subscriber_tags
+        WHERE subscriber_id = $1
+        ORDER BY tag
+        "#,
+        row.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let tag_events = sqlx::query_as!(
+        TagEventRecord,
+        r#"
+        SELECT tag, action, actor, occurred_at FROM tag_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        row.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let events = sqlx::query_as!(
+        EventRecord,
+        r#"
+        SELECT kind, issue_id, details, occurred_at FROM subscriber_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        row.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(Some(SubscriberRecord {
+        id: row.id,
+        email: row.email,
+        name: row.name,
+        status: row.status,
+        subscribed_at: row.subscribed_at,
+        attributes: row.attributes,
+        subscription_tokens,
+        lists,
+        tags,
+        tag_events,
+        events,
+        consent_records: consent_records(pool, row.id).await?,
+    }))
+}
+
+/// Everything held on `email`, matched case-insensitively.
+pub async fn collect_subject_data(pool: &PgPool, email: &str) -> ServerResult<SubjectData> {
+    let erased_at = sqlx::query_scalar!(
+        r#"
+        SELECT erased_at FROM erasures
+        WHERE email_hash = $1
+        "#,
+        email_hash(email)
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM subscribers
+        WHERE lower(email) = lower($1)
+        "#,
+        email.trim()
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let mut subscribers = Vec::with_capacity(ids.len());
+    for id in ids {
+        subscribers.extend(subscriber_record(pool, id).await?);
+    }
+
+    let confirmation_sends = sqlx::query_as!(
+        ConfirmationSendsRecord,
+        r#"
+        SELECT window_start, sends, last_sent_at FROM confirmation_throttle
+        WHERE scope = $1 AND key = $2
+        "#,
+        ThrottleScope::Email.to_string(),
+        email.trim().to_lowercase()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(SubjectData {
+        email: email.trim().into(),
+        generated_at: Utc::now(),
+        erased_at,
+        subscribers,
+        confirmation_sends,
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Erasure {
+    pub email_hash: String,
+    pub subscribers_deleted: u64,
+    pub erased_at: DateTime<Utc>,
+}
+
+/// Deletes everything held on `email` and records its hash, erasing an unknown address still
+/// records the hash.
+pub async fn erase_subject(pool: &PgPool, email: &str, actor: &str) -> ServerResult<Erasure> {
+    let email = email.trim().to_lowercase();
+    let erasure = Erasure {
+        email_hash: email_hash(&email),
+        subscribers_deleted: 0,
+        erased_at: Utc::now(),
+    };
+
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let ids = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM subscribers
+        WHERE lower(email) = $1
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = ANY($1)
+        "#,
+        &ids
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    // Lists, tags and events go along with it
+    let subscribers_deleted = sqlx::query!(
+        r#"
+        DELETE FROM subscribers
+        WHERE id = ANY($1)
+        "#,
+        &ids
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+
+    sqlx::query!(
+        r#"
+        DELETE FROM confirmation_throttle
+        WHERE scope = $1 AND key = $2
+        "#,
+        ThrottleScope::Email.to_string(),
+        email
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM data_request_tokens
+        WHERE lower(email) = $1
+        "#,
+        email
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO erasures (email_hash, erased_at, erased_by)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (email_hash) DO UPDATE
+        SET erased_at = $2, erased_by = $3
+        "#,
+        erasure.email_hash,
+        erasure.erased_at,
+        actor
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    // An earlier suppression, e.g. from a bounce, still has the address in it
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email_hash, email, reason, source, created_at)
+        VALUES ($1, NULL, $2, 'erasure', $3)
+        ON CONFLICT (email_hash) DO UPDATE
+        SET email = NULL, reason = $2, source = 'erasure'
+        "#,
+        erasure.email_hash,
+        SuppressionReason::Erased.to_string(),
+        erasure.erased_at
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(
+        email_hash = erasure.email_hash,
+        subscribers_deleted, actor, "Data subject erased"
+    );
+
+    Ok(Erasure {
+        subscribers_deleted,
+        ..erasure
+    })
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DataSubjectQuery {
+    pub email: String,
+}
+
+fn subject_data_attachment(data: &SubjectData) -> ServerResult<Response> {
+    let body = serde_json::to_vec_pretty(data).map_err(ServerError::unexpected)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"personal-data.json\"",
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Admin side of a data subject access request.
+#[instrument(skip(user, pool, query), fields(username = user.username))]
+pub async fn get_data_subject(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<DataSubjectQuery>, QueryRejection>,
+) -> ApiResult<Json<SubjectData>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    Ok(Json(collect_subject_data(&pool, &query.email).await?))
+}
+
+/// Admin side of an erasure request.
+///
+/// Erased addresses are skipped by imports and suppressed, so nothing gets sent to them again
+/// unless the suppression is removed.
+#[instrument(skip(user, pool, query), fields(username = user.username))]
+pub async fn erase_data_subject(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<DataSubjectQuery>, QueryRejection>,
+) -> ApiResult<Json<Erasure>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    Ok(Json(
+        erase_subject(&pool, &query.email, &user.username).await?,
+    ))
+}
+
+#[derive(Debug, Clone)]
+pub struct DataRequestState {
+    pub pool: PgPool,
+    pub email_client: Arc<EmailClient>,
+    pub manage_endpoint: reqwest::Url,
+    pub throttle: ConfirmationThrottle,
+    pub trust_x_forwarded_for: bool,
+    pub token_ttl: Duration,
+    pub theme: PageThemeConfig,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DataRequestForm {
+    pub email: EmailAdderess,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DataRequestTokenForm {
+    pub token: Option<String>,
+}
+
+/// Resolves a self-service token back to the address it was sent to.
+async fn token_email(state: &DataRequestState, token: Option<&str>) -> ServerResult<String> {
+    let token = sqlx::query!(
+        r#"
+        SELECT email, created_at FROM data_request_tokens
+        WHERE token = $1
+        "#,
+        token.ok_or(SubscribeError::InvalidToken)?
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or(SubscribeError::InvalidToken)?;
+
+    let expired = chrono::Duration::from_std(state.token_ttl)
+        .map(|ttl| token.created_at + ttl < Utc::now())
+        .unwrap_or(false);
+    if expired {
+        return Err(SubscribeError::ExpiredToken.into());
+    }
+
+    Ok(token.email)
+}
+
+/// Content-Type: application/x-www-form-urlencoded
+///
+/// Always answers the same way, so that it can't be used to find out who's subscribed.
+/// Shares the send limits of confirmation emails.
+#[instrument(skip(state, headers, form), fields(%client_addr))]
+pub async fn request_data(
+    State(state): State<DataRequestState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<DataRequestForm>,
+) -> ServerResult<impl IntoResponse> {
+    let response = (
+        StatusCode::ACCEPTED,
+        "If that address is subscribed, a link to manage its data has been sent to it.",
+    );
+
+    // Before the lookup, so that unknown addresses are throttled like subscribed ones
+    let client_ip = helpers::client_ip(&headers, client_addr, state.trust_x_forwarded_for);
+    match state
+        .throttle
+        .acquire(&state.pool, &form.email, client_ip)
+        .await
+    {
+        Err(ServerError::Subscribe(SubscribeError::TooManyRequests { .. })) => {
+            info!("Skipped a throttled data request");
+            return Ok(response);
+        }
+        throttled => throttled?,
+    }
+
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT name, locale FROM subscribers
+        WHERE lower(email) = lower($1)
+        LIMIT 1
+        "#,
+        form.email.as_ref()
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let Some(subscriber) = subscriber else {
+        return Ok(response);
+    };
+
+    let token = gen_subscription_token(SUBSCRIPTION_TOKEN_LEN);
+    sqlx::query!(
+        r#"
+        INSERT INTO data_request_tokens (token, email, created_at)
+        VALUES ($1, $2, $3)
+        "#,
+        token,
+        form.email.as_ref(),
+        Utc::now()
+    )
+    .execute(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let mut url = state.manage_endpoint.clone();
+    url.query_pairs_mut().append_pair("token", &token);
+    let locale = subscriber.locale.and_then(|locale| locale.parse().ok());
+    let sent = send_transactional(
+        &state.pool,
+        &state.email_client,
+        TransactionalKind::DataRequest,
+        TransactionalRecipient {
+            email: &form.email,
+            name: &subscriber.name,
+            locale: locale.as_ref(),
+            list: None,
+        },
+        json!({ "manage_url": url.as_str() }),
+    )
+    .await;
+    match sent {
+        Err(err) if err.is::<Suppressed>() => info!(%err, "Skipped a data request link"),
+        sent => {
+            sent.map_err(ServerError::Unexpected)?;
+            info!("Sent a data request link");
+        }
+    }
+
+    Ok(response)
+}
+
+fn token_error_page(theme: &PageThemeConfig, err: ServerError) -> ServerResult<Response> {
+    let (status, title, message) = match err {
+        ServerError::Subscribe(SubscribeError::InvalidToken) => (
+            StatusCode::NOT_FOUND,
+            "Invalid link",
+            "This link is not valid. Make sure you opened the complete link from the email.",
+        ),
+        ServerError::Subscribe(SubscribeError::ExpiredToken) => (
+            StatusCode::GONE,
+            "Link expired",
+            "This link has expired, request a new one.",
+        ),
+        err => return Err(err),
+    };
+    Ok((
+        status,
+        render_page(theme, "data_request", title, &format!("<p>{message}</p>")),
+    )
+        .into_response())
+}
+
+/// Landing page of the emailed link, the actual actions are POSTs so that link scanners
+/// can't trigger them.
+pub async fn manage_data(
+    State(state): State<DataRequestState>,
+    Query(query): Query<DataRequestTokenForm>,
+) -> ServerResult<Response> {
+    let email = match token_email(&state, query.token.as_deref()).await {
+        Ok(email) => email,
+        Err(err) => return token_error_page(&state.theme, err),
+    };
+    let token = escape_html(query.token.as_deref().unwrap_or_default());
+
+    Ok(render_page(
+        &state.theme,
+        "data_request",
+        "Your personal data",
+        &format!(
+            r#"<p>Manage the data held on {email}.</p>
+        <form method="post" action="export">
+            <input type="hidden" name="token" value="{token}" />
+            <button type="submit">Download my data</button>
+        </form>
+        <form method="post" action="erase">
+            <input type="hidden" name="token" value="{token}" />
+            <button type="submit">Delete my data and unsubscribe</button>
+        </form>"#,
+            email = escape_html(&email),
+        ),
+    )
+    .into_response())
+}
+
+/// Content-Type: application/x-www-form-urlencoded
+#[instrument(skip_all)]
+pub async fn export_own_data(
+    State(state): State<DataRequestState>,
+    Form(form): Form<DataRequestTokenForm>,
+) -> ServerResult<Response> {
+    let email = match token_email(&state, form.token.as_deref()).await {
+        Ok(email) => email,
+        Err(err) => return token_error_page(&state.theme, err),
+    };
+    subject_data_attachment(&collect_subject_data(&state.pool, &email).await?)
+}
+
+/// Content-Type: application/x-www-form-urlencoded
+#[instrument(skip_all)]
+pub async fn erase_own_data(
+    State(state): State<DataRequestState>,
+    Form(form): Form<DataRequestTokenForm>,
+) -> ServerResult<Response> {
+    let email = match token_email(&state, form.token.as_deref()).await {
+        Ok(email) => email,
+        Err(err) => return token_error_page(&state.theme, err),
+    };
+    erase_subject(&state.pool, &email, "self-service").await?;
+
+    Ok(render_page(
+        &state.theme,
+        "data_request",
+        "Data deleted",
+        "<p>Everything we held on you has been deleted, and you won't hear from us again.</p>",
+    )
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::suppressions::suppress;
+
+    #[sqlx::test]
+    async fn erasure_clears_earlier_suppressions(pool: PgPool) {
+        let email = "Someone@Example.com";
+        suppress(
+            &pool,
+            Some(email),
+            &email_hash(email),
+            SuppressionReason::HardBounce,
+            "postmark",
+        )
+        .await
+        .unwrap();
+
+        erase_subject(&pool, email, "admin").await.unwrap();
+
+        let suppression = sqlx::query!(
+            r#"
+            SELECT email, reason, source FROM suppressions
+            WHERE email_hash = $1
+            "#,
+            email_hash(email)
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(suppression.email, None);
+        assert_eq!(suppression.reason, SuppressionReason::Erased.to_string());
+        assert_eq!(suppression.source, "erasure");
+    }
+}
@@ -1,6 +1,65 @@
-use std::str::FromStr;
+use axum::http::HeaderMap;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::{net::IpAddr, str::FromStr, time::Duration};
 
-#[derive(Debug)]
+/// Rounds up to the largest whole unit, e.g. "3 minutes" or "1 hour".
+pub fn humanize_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64().ceil() as u64;
+    let (amount, unit) = match secs {
+        0..=59 => (secs.max(1), "second"),
+        60..=3599 => (secs.div_ceil(60), "minute"),
+        3600..=86399 => (secs.div_ceil(3600), "hour"),
+        _ => (secs.div_ceil(86400), "day"),
+    };
+    format!("{amount} {unit}{}", if amount == 1 { "" } else { "s" })
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The IP of the client that made the request.
+///
+/// When running behind a reverse proxy, the last `X-Forwarded-For` entry is the one added
+/// by the proxy itself, so it's the only one that can't be spoofed by the client.
+pub fn client_ip(headers: &HeaderMap, peer: std::net::SocketAddr, trust_forwarded: bool) -> IpAddr {
+    trust_forwarded
+        .then(|| {
+            headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok())
+        })
+        .flatten()
+        .unwrap_or(peer.ip())
+}
+
+/// Accepts either a `YYYY-MM-DD` date, taken as midnight UTC, or an RFC 3339 timestamp.
+pub fn parse_datetime(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        })
+        .ok()
+}
+
+#[derive(Debug, Clone)]
 pub struct Url(pub reqwest::Url);
 
 impl std::fmt::Display for Url {
@@ -24,7 +83,7 @@ impl<'de> serde::Deserialize<'de> for Url {
         D: serde::Deserializer<'de>,
     {
         reqwest::Url::parse(&String::deserialize(deserializer)?)
-            .map(|inner| Self(inner))
+            .map(Self)
             .map_err(serde::de::Error::custom)
     }
 }
@@ -48,7 +107,7 @@ impl<'de> serde::Deserialize<'de> for SocketAddr {
     {
         String::deserialize(deserializer)?
             .parse::<std::net::SocketAddr>()
-            .map(|inner| Self(inner))
+            .map(Self)
             .map_err(serde::de::Error::custom)
     }
 }
@@ -62,7 +121,7 @@ impl<'de> serde::Deserialize<'de> for PgConnectOptions {
         D: serde::Deserializer<'de>,
     {
         sqlx::postgres::PgConnectOptions::from_str(&String::deserialize(deserializer)?)
-            .map(|inner| Self(inner))
+            .map(Self)
             .map_err(serde::de::Error::custom)
     }
 }
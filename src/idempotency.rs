@@ -0,0 +1,226 @@
+//! `Idempotency-Key` support, so that a retried request gets the original response instead of
+//! doing the work twice.
+//!
+//! Keys are scoped to the user and kept for `idempotency.ttl_ms`. A key only goes with the
+//! request it was first used for, reusing it for a different one is rejected.
+
+use crate::{ServerError, ServerResult};
+use axum::{
+    body::{self, HttpBody},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::info;
+use uuid::Uuid;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    /// The key of the request, if it has one.
+    pub fn from_headers(headers: &HeaderMap) -> ServerResult<Option<Self>> {
+        let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+            return Ok(None);
+        };
+        let key = value
+            .to_str()
+            .map_err(|_| {
+                ServerError::BadRequest(format!("{IDEMPOTENCY_KEY_HEADER} must be ASCII"))
+            })?
+            .trim();
+        if key.is_empty() || key.len() > 255 {
+            return Err(ServerError::BadRequest(format!(
+                "{IDEMPOTENCY_KEY_HEADER} must be 1 to 255 characters long"
+            )));
+        }
+        Ok(Some(Self(key.to_owned())))
+    }
+}
+
+/// Hex SHA-256 of the parsed request, so that formatting doesn't make it a different one.
+pub fn request_hash(request: &impl Serialize) -> ServerResult<String> {
+    let bytes = serde_json::to_vec(request).map_err(ServerError::unexpected)?;
+    Ok(hex::encode(Sha256::digest(bytes)))
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+pub enum NextAction {
+    StartProcessing,
+    /// The key was used before, this is what the first request got.
+    ReturnSavedResponse(Response),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdempotencyStore {
+    /// Keys are forgotten after this, the same key then starts a new request.
+    pub ttl: Duration,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl }
+    }
+
+    /// Claims `key` for this request, or returns the response of the request that claimed it.
+    /// A request that's still being processed makes this a [`ServerError::Conflict`], one that
+    /// died halfway holds on to the key until it expires. A key that was claimed with another
+    /// `request_hash` is a [`ServerError::Unprocessable`].
+    pub async fn try_processing(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        key: &IdempotencyKey,
+        request_hash: &str,
+    ) -> ServerResult<NextAction> {
+        // Takes over an expired key in the same statement
+        let claimed = sqlx::query!(
+            r#"
+            INSERT INTO idempotency_keys (user_id, idempotency_key, request_hash, created_at)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, idempotency_key) DO UPDATE
+            SET response_status_code = NULL, response_content_type = NULL, response_body = NULL,
+                request_hash = EXCLUDED.request_hash, created_at = EXCLUDED.created_at
+            WHERE idempotency_keys.created_at + $5 * INTERVAL '1 millisecond' < now()
+            "#,
+            user_id,
+            key.as_ref(),
+            request_hash,
+            Utc::now(),
+            self.ttl.as_millis() as i64
+        )
+        .execute(pool)
+        .await
+        .map_err(ServerError::unexpected)?
+        .rows_affected()
+            > 0;
+        if claimed {
+            return Ok(NextAction::StartProcessing);
+        }
+
+        let saved = sqlx::query!(
+            r#"
+            SELECT request_hash, response_status_code, response_content_type, response_body
+            FROM idempotency_keys
+            WHERE user_id = $1 AND idempotency_key = $2
+            "#,
+            user_id,
+            key.as_ref()
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(ServerError::unexpected)?;
+
+        if saved.request_hash != request_hash {
+            return Err(ServerError::Unprocessable(format!(
+                "This {IDEMPOTENCY_KEY_HEADER} was already used for a different request"
+            )));
+        }
+
+        let (Some(status), Some(body)) = (saved.response_status_code, saved.response_body) else {
+            return Err(ServerError::Conflict(format!(
+                "A request with this {IDEMPOTENCY_KEY_HEADER} is still being processed"
+            )));
+        };
+
+        info!(key = key.as_ref(), "Replaying the saved response");
+
+        let status = StatusCode::from_u16(status as u16).map_err(ServerError::unexpected)?;
+        let mut response = (status, body).into_response();
+        if let Some(content_type) = saved.response_content_type {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::try_from(content_type).map_err(ServerError::unexpected)?,
+            );
+        }
+
+        Ok(NextAction::ReturnSavedResponse(response))
+    }
+
+    /// Lets go of `key`, for requests that failed without doing anything, so that they can be
+    /// retried for real.
+    pub async fn release(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        key: &IdempotencyKey,
+    ) -> ServerResult<()> {
+        sqlx::query!(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE user_id = $1 AND idempotency_key = $2
+            "#,
+            user_id,
+            key.as_ref()
+        )
+        .execute(pool)
+        .await
+        .map_err(ServerError::unexpected)?;
+        Ok(())
+    }
+
+    /// Saves the response for the retries, errors included.
+    pub async fn save_response(
+        &self,
+        pool: &PgPool,
+        user_id: Uuid,
+        key: &IdempotencyKey,
+        response: Response,
+    ) -> ServerResult<Response> {
+        let (parts, mut response_body) = response.into_parts();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response_body.data().await {
+            bytes.extend_from_slice(&chunk.map_err(ServerError::unexpected)?);
+        }
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+
+        sqlx::query!(
+            r#"
+            UPDATE idempotency_keys
+            SET response_status_code = $1, response_content_type = $2, response_body = $3
+            WHERE user_id = $4 AND idempotency_key = $5
+            "#,
+            parts.status.as_u16() as i16,
+            content_type,
+            bytes,
+            user_id,
+            key.as_ref()
+        )
+        .execute(pool)
+        .await
+        .map_err(ServerError::unexpected)?;
+
+        Ok(Response::from_parts(
+            parts,
+            body::boxed(body::Full::from(bytes)),
+        ))
+    }
+
+    /// Deletes expired keys, returning how many.
+    pub async fn prune(&self, pool: &PgPool) -> sqlx::Result<u64> {
+        Ok(sqlx::query!(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE created_at + $1 * INTERVAL '1 millisecond' < now()
+            "#,
+            self.ttl.as_millis() as i64
+        )
+        .execute(pool)
+        .await?
+        .rows_affected())
+    }
+}
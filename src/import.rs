@@ -0,0 +1,591 @@
+use crate::{
+    api::ApiResult,
+    attributes::{AttributeSchema, AttributeValues},
+    auth::AuthUser,
+    consent::{record_consent, ConsentKind, ConsentSource},
+    email::EmailAdderess,
+    gdpr,
+    subscribe::{
+        email_subscription_confirmation, gen_subscription_token, SubscribeState, SubscriberName,
+        SubscriptionStatus, SUBSCRIPTION_TOKEN_LEN,
+    },
+    ServerError, ServerResult, SubscribeError,
+};
+use axum::{
+    body::Bytes,
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use chrono::Utc;
+use futures::{future, stream, StreamExt};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use tracing::{error, info, instrument, Instrument};
+use uuid::Uuid;
+
+/// Rows are written this many at a time, each batch in its own transaction.
+const BATCH_SIZE: usize = 500;
+
+/// Confirmation emails of an import that are in flight at once.
+const CONFIRMATION_CONCURRENCY: usize = 8;
+
+/// Upper bound for the request body of an import.
+pub const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    Csv,
+    /// JSON Lines, one object per line.
+    Jsonl,
+}
+
+impl ImportFormat {
+    fn from_content_type(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        match content_type.split(';').next()?.trim() {
+            "text/csv" => Some(Self::Csv),
+            "application/jsonl" | "application/x-jsonlines" | "application/x-ndjson" => {
+                Some(Self::Jsonl)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Rows are marked `Confirmed` right away, the consent was collected elsewhere.
+    Confirmed,
+    /// Rows stay `Pending` and get the usual confirmation email.
+    SendConfirmation,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImportQuery {
+    /// Falls back to the Content-Type of the body.
+    pub format: Option<ImportFormat>,
+    pub mode: ImportMode,
+    /// Where the consent of `Confirmed` rows was collected, e.g. "Signup form on the old provider".
+    pub consent_source: Option<String>,
+    /// Slug of one of the configured lists, for every imported row.
+    pub list: Option<String>,
+    pub email_column: Option<String>,
+    pub name_column: Option<String>,
+    /// `attr.<key>=<column>` maps a column to an attribute.
+    /// Columns named after an attribute key are mapped without it.
+    #[serde(flatten)]
+    pub rest: HashMap<String, String>,
+}
+
+impl ImportQuery {
+    fn attribute_columns(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rest.iter().filter_map(|(param, column)| {
+            param
+                .strip_prefix("attr.")
+                .map(|key| (column.as_str(), key))
+        })
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RowError {
+    /// Line of the row in the uploaded file, starting at 1.
+    pub line: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ImportReport {
+    pub rows: usize,
+    pub created: usize,
+    /// Rows whose email was already subscribed, these are left untouched.
+    pub existing: usize,
+    pub failed: usize,
+    /// Sent in the background after the response, failures are only logged.
+    pub confirmations_queued: usize,
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    fn fail(&mut self, line: u64, email: Option<String>, error: impl ToString) {
+        self.failed += 1;
+        self.errors.push(RowError {
+            line,
+            email,
+            error: error.to_string(),
+        });
+    }
+}
+
+type RawRow = (u64, Result<serde_json::Map<String, Value>, String>);
+
+fn parse_csv(body: &[u8]) -> ServerResult<Vec<RawRow>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body);
+    let headers = reader
+        .headers()
+        .map_err(|e| ServerError::BadRequest(format!("Invalid CSV header: {e}")))?
+        .clone();
+
+    Ok(reader
+        .records()
+        .map(|record| match record {
+            Ok(record) => (
+                record.position().map_or(0, |position| position.line()),
+                Ok(headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(column, value)| (column.to_owned(), Value::String(value.to_owned())))
+                    .collect()),
+            ),
+            Err(e) => (
+                e.position().map_or(0, |position| position.line()),
+                Err(e.to_string()),
+            ),
+        })
+        .collect())
+}
+
+fn parse_jsonl(body: &[u8]) -> ServerResult<Vec<RawRow>> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| ServerError::BadRequest("The body must be valid UTF-8".into()))?;
+
+    Ok(body
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            (
+                i as u64 + 1,
+                serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {e}")),
+            )
+        })
+        .collect())
+}
+
+struct ImportRow {
+    line: u64,
+    email: EmailAdderess,
+    name: SubscriberName,
+    attributes: AttributeValues,
+}
+
+fn field<'a>(fields: &'a serde_json::Map<String, Value>, column: &str) -> Option<&'a str> {
+    fields.get(column).and_then(Value::as_str).map(str::trim)
+}
+
+fn validate_row(
+    query: &ImportQuery,
+    schema: &AttributeSchema,
+    fields: &serde_json::Map<String, Value>,
+) -> anyhow::Result<(EmailAdderess, SubscriberName, AttributeValues)> {
+    let email_column = query.email_column.as_deref().unwrap_or("email");
+    let name_column = query.name_column.as_deref().unwrap_or("name");
+
+    let email = EmailAdderess::new(
+        field(fields, email_column)
+            .ok_or_else(|| anyhow::anyhow!("Missing column \"{email_column}\""))?
+            .to_owned(),
+    )?;
+    let name = SubscriberName::new(
+        field(fields, name_column)
+            .ok_or_else(|| anyhow::anyhow!("Missing column \"{name_column}\""))?
+            .to_owned(),
+    )?;
+
+    let mut attributes = fields
+        .get("attributes")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    for (column, value) in fields {
+        if schema.0.contains_key(column) {
+            attributes.insert(column.clone(), value.clone());
+        }
+    }
+    for (column, key) in query.attribute_columns() {
+        if let Some(value) = fields.get(column) {
+            attributes.insert(key.to_owned(), value.clone());
+        }
+    }
+    let attributes = schema.validate(&attributes, true)?;
+
+    Ok((email, name, attributes))
+}
+
+/// Writes one batch, returning the rows that were actually created along with their ids and
+/// confirmation tokens.
+///
+/// Emails are matched case-insensitively, against the existing subscribers and within the
+/// batch, where the first row wins.
+async fn insert_batch<'a>(
+    pool: &PgPool,
+    batch: &'a [ImportRow],
+    query: &ImportQuery,
+    list: Option<&str>,
+    actor: &str,
+) -> anyhow::Result<Vec<(&'a ImportRow, Uuid, Option<String>)>> {
+    let now = Utc::now();
+    let status = match query.mode {
+        ImportMode::Confirmed => SubscriptionStatus::Confirmed,
+        ImportMode::SendConfirmation => SubscriptionStatus::Pending,
+    };
+    let ids = batch.iter().map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+
+    let mut transaction = pool.begin().await?;
+
+    let created = sqlx::query_scalar!(
+        r#"
+        INSERT INTO subscribers (id, email, name, status, subscribed_at, attributes)
+        SELECT DISTINCT ON (lower(row.email)) id, email, name, $4, $5, attributes
+        FROM unnest($1::uuid[], $2::text[], $3::text[], $6::jsonb[])
+            WITH ORDINALITY AS row(id, email, name, attributes, n)
+        WHERE NOT EXISTS (
+            SELECT 1 FROM subscribers WHERE lower(subscribers.email) = lower(row.email)
+        )
+        ORDER BY lower(row.email), n
+        ON CONFLICT DO NOTHING
+        RETURNING id
+        "#,
+        &ids,
+        &batch
+            .iter()
+            .map(|row| row.email.as_ref().to_owned())
+            .collect::<Vec<_>>(),
+        &batch
+            .iter()
+            .map(|row| row.name.as_ref().to_owned())
+            .collect::<Vec<_>>(),
+        status.to_string(),
+        now,
+        &batch
+            .iter()
+            .map(|row| Value::Object(row.attributes.clone()))
+            .collect::<Vec<_>>()
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let created = batch
+        .iter()
+        .zip(ids)
+        .filter(|(_, id)| created.contains(id))
+        .map(|(row, id)| {
+            let token = (query.mode == ImportMode::SendConfirmation)
+                .then(|| gen_subscription_token(SUBSCRIPTION_TOKEN_LEN));
+            (row, id, token)
+        })
+        .collect::<Vec<_>>();
+    let created_ids = created.iter().map(|(_, id, _)| *id).collect::<Vec<_>>();
+
+    if query.mode == ImportMode::SendConfirmation {
+        let (tokens, token_ids): (Vec<_>, Vec<_>) = created
+            .iter()
+            .filter_map(|(_, id, token)| token.clone().map(|token| (token, *id)))
+            .unzip();
+        sqlx::query!(
+            r#"
+            INSERT INTO subscription_tokens (subscription_token, subscriber_id)
+            SELECT * FROM unnest($1::text[], $2::uuid[])
+            "#,
+            &tokens,
+            &token_ids
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    if let Some(list) = list {
+        sqlx::query!(
+            r#"
+            INSERT INTO list_subscriptions (list, subscriber_id, subscribed_at)
+            SELECT $1, id, $2 FROM unnest($3::uuid[]) AS id
+            ON CONFLICT DO NOTHING
+            "#,
+            list,
+            now,
+            &created_ids
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    // Provenance, so that it's known later where these subscribers and their consent came from
+    record_consent(
+        &mut *transaction,
+        &created_ids,
+        ConsentKind::Import,
+        list,
+        &ConsentSource::channel("import"),
+        json!({
+            "mode": query.mode,
+            "consent_source": query.consent_source,
+            "imported_by": actor,
+        }),
+    )
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(created)
+}
+
+/// Bulk import for migrating from another provider.
+///
+/// Every row is validated like a signup would be, but the domain check, bot protection and
+/// confirmation throttling are skipped since an admin is vouching for the data.
+#[instrument(skip(user, state, headers, query, body), fields(username = user.username))]
+pub async fn import_subscribers(
+    user: AuthUser,
+    State(state): State<SubscribeState>,
+    headers: HeaderMap,
+    query: Result<Query<ImportQuery>, QueryRejection>,
+    body: Bytes,
+) -> ApiResult<Json<ImportReport>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+
+    if query.mode == ImportMode::Confirmed
+        && query
+            .consent_source
+            .as_deref()
+            .is_none_or(|source| source.trim().is_empty())
+    {
+        return Err(ServerError::BadRequest(
+            "consent_source is required when importing rows as confirmed".into(),
+        )
+        .into());
+    }
+    let list = query.list.as_deref().map(str::to_lowercase);
+    if let Some(list) = &list {
+        if !state.lists.contains_key(list) {
+            return Err(SubscribeError::UnknownList(list.clone()).into());
+        }
+    }
+
+    let raw_rows = match query
+        .format
+        .or_else(|| ImportFormat::from_content_type(&headers))
+    {
+        Some(ImportFormat::Csv) => parse_csv(&body)?,
+        Some(ImportFormat::Jsonl) => parse_jsonl(&body)?,
+        None => {
+            return Err(ServerError::BadRequest(
+                "Pass format=csv or format=jsonl, or a matching Content-Type".into(),
+            )
+            .into())
+        }
+    };
+
+    let schema = AttributeSchema::load(&state.pool)
+        .await
+        .map_err(ServerError::Unexpected)?;
+
+    let mut report = ImportReport {
+        rows: raw_rows.len(),
+        ..Default::default()
+    };
+    let mut rows = Vec::with_capacity(raw_rows.len());
+    for (line, fields) in raw_rows {
+        match fields
+            .and_then(|fields| validate_row(&query, &schema, &fields).map_err(|e| e.to_string()))
+        {
+            Ok((email, name, attributes)) => rows.push(ImportRow {
+                line,
+                email,
+                name,
+                attributes,
+            }),
+            Err(e) => report.fail(line, None, e),
+        }
+    }
+
+    // Erased addresses must never come back through an import
+    let erased = gdpr::erased_hashes(
+        &state.pool,
+        &rows
+            .iter()
+            .map(|row| gdpr::email_hash(row.email.as_ref()))
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+    if !erased.is_empty() {
+        rows.retain(|row| {
+            let is_erased = erased.contains(&gdpr::email_hash(row.email.as_ref()));
+            if is_erased {
+                report.fail(
+                    row.line,
+                    Some(row.email.as_ref().into()),
+                    "The address was erased on request",
+                );
+            }
+            !is_erased
+        });
+    }
+
+    let mut confirmations = Vec::new();
+    for batch in rows.chunks(BATCH_SIZE) {
+        let created =
+            match insert_batch(&state.pool, batch, &query, list.as_deref(), &user.username).await {
+                Ok(created) => created,
+                Err(e) => {
+                    error!(error = ?e, "Failed to import a batch");
+                    for row in batch {
+                        report.fail(
+                            row.line,
+                            Some(row.email.as_ref().into()),
+                            "Failed to write the batch this row was in",
+                        );
+                    }
+                    continue;
+                }
+            };
+        report.created += created.len();
+        report.existing += batch.len() - created.len();
+
+        confirmations.extend(
+            created
+                .into_iter()
+                .filter_map(|(row, id, token)| Some((row.line, id, token?))),
+        );
+    }
+    report.confirmations_queued = confirmations.len();
+
+    info!(
+        rows = report.rows,
+        created = report.created,
+        existing = report.existing,
+        failed = report.failed,
+        "Subscribers imported"
+    );
+
+    if !confirmations.is_empty() {
+        tokio::spawn(send_confirmations(state, confirmations, list).in_current_span());
+    }
+
+    Ok(Json(report))
+}
+
+async fn send_confirmations(
+    state: SubscribeState,
+    confirmations: Vec<(u64, Uuid, String)>,
+    list: Option<String>,
+) {
+    let failed = stream::iter(confirmations)
+        .map(|(line, id, token)| {
+            let (state, list) = (&state, list.as_deref());
+            async move {
+                email_subscription_confirmation(
+                    state,
+                    id,
+                    state.subscribe_confirm_endpoint.clone(),
+                    &token,
+                    list,
+                )
+                .await
+                .map_err(
+                    |e| error!(error = ?e, line, "Imported, but the confirmation email failed"),
+                )
+            }
+        })
+        .buffer_unordered(CONFIRMATION_CONCURRENCY)
+        .filter(|sent| future::ready(sent.is_err()))
+        .count()
+        .await;
+
+    info!(failed, "Sent the confirmation emails of an import");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::{AttributeDefinition, AttributeKind};
+
+    fn query(rest: &[(&str, &str)]) -> ImportQuery {
+        ImportQuery {
+            format: None,
+            mode: ImportMode::Confirmed,
+            consent_source: Some("Old provider".into()),
+            list: None,
+            email_column: None,
+            name_column: None,
+            rest: rest
+                .iter()
+                .map(|(param, column)| (param.to_string(), column.to_string()))
+                .collect(),
+        }
+    }
+
+    fn schema() -> AttributeSchema {
+        let definition = AttributeDefinition {
+            key: "age".into(),
+            kind: AttributeKind::Number,
+            description: None,
+            required: false,
+            created_at: Utc::now(),
+        };
+        AttributeSchema([("age".to_owned(), definition)].into())
+    }
+
+    fn fields(value: Value) -> serde_json::Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn parses_csv() {
+        let rows = parse_csv(b"email,name\n a@example.com , Ann \n\"b@example.com\nB\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(
+            rows[0].1,
+            Ok(fields(json!({ "email": "a@example.com", "name": "Ann" })))
+        );
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.is_err());
+    }
+
+    #[test]
+    fn parses_jsonl() {
+        let rows = parse_jsonl(b"{\"email\": \"a@example.com\"}\n\n{nope\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            (1, Ok(fields(json!({ "email": "a@example.com" }))))
+        );
+        assert_eq!(rows[1].0, 3);
+        assert!(rows[1].1.as_ref().unwrap_err().starts_with("Invalid JSON"));
+
+        assert!(parse_jsonl(b"\xff").is_err());
+    }
+
+    #[test]
+    fn validates_rows() {
+        let row =
+            fields(json!({ "email": "a@example.com", "name": "Ann", "age": "42", "Plan": "x" }));
+        let (email, name, attributes) = validate_row(&query(&[]), &schema(), &row).unwrap();
+        assert_eq!(email.as_ref(), "a@example.com");
+        assert_eq!(name.as_ref(), "Ann");
+        assert_eq!(Value::Object(attributes), json!({ "age": 42 }));
+
+        let row = fields(json!({ "Mail": "a@example.com", "name": "Ann", "years": "42" }));
+        let mut mapped = query(&[("attr.age", "years")]);
+        mapped.email_column = Some("Mail".into());
+        let (_, _, attributes) = validate_row(&mapped, &schema(), &row).unwrap();
+        assert_eq!(Value::Object(attributes), json!({ "age": 42 }));
+
+        for row in [
+            json!({ "name": "Ann" }),
+            json!({ "email": "a@example.com" }),
+            json!({ "email": "not an email", "name": "Ann" }),
+            json!({ "email": "a@example.com", "name": "Ann", "age": "old" }),
+        ] {
+            assert!(validate_row(&query(&[]), &schema(), &fields(row)).is_err());
+        }
+    }
+}
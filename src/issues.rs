@@ -0,0 +1,637 @@
+//! Newsletter issues, from draft to sent, and the history of what went out.
+
+use crate::{
+    api::ApiResult,
+    attributes::AttributeSchema,
+    auth::AuthUser,
+    email::EmailClient,
+    publish::{deliver_issue, PublishState},
+    render::validate_issue_templates,
+    segments::Audience,
+    templating::SubscriberLinks,
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRef, Path, Query, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use std::str::FromStr;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum IssueStatus {
+    /// Can still be edited.
+    Draft,
+    /// Waiting for `scheduled_at`, can still be edited, rescheduled or cancelled.
+    Scheduled,
+    /// An instance that dies while sending leaves the issue here. It isn't retried, part of the
+    /// audience may already have it.
+    Sending,
+    Sent,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Issue {
+    pub id: Uuid,
+    pub title: String,
+    pub markdown_content: Option<String>,
+    /// Overrides what's rendered from the Markdown.
+    pub text_content: Option<String>,
+    pub html_content: Option<String>,
+    /// Missing once the user is deleted.
+    pub author_id: Option<Uuid>,
+    pub status: String,
+    pub segment_id: Option<Uuid>,
+    pub filter: Option<String>,
+    pub tag: Option<String>,
+    /// Counters of the delivery, zero until it's sent.
+    pub recipients: i32,
+    pub delivered: i32,
+    pub failed: i32,
+    pub suppressed: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub scheduled_at: Option<DateTime<Utc>>,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+impl Issue {
+    pub fn audience(&self) -> Audience {
+        Audience {
+            segment_id: self.segment_id,
+            filter: self.filter.clone(),
+            tag: self.tag.clone(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct IssueBody {
+    pub title: String,
+    pub content: IssueContent,
+    /// Saved segment or inline filter, every confirmed subscriber when left out.
+    #[serde(flatten)]
+    pub audience: Audience,
+}
+
+/// Markdown, or both `text` and `html` written by hand. Either of them also overrides what's
+/// rendered from the Markdown.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct IssueContent {
+    pub markdown: Option<String>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+}
+
+impl IssueBody {
+    async fn validate(&self, pool: &PgPool) -> ServerResult<()> {
+        if self.title.trim().is_empty() {
+            return Err(ServerError::BadRequest("Issue title can't be empty".into()));
+        }
+        if let (None, None, _) | (None, _, None) = (
+            &self.content.markdown,
+            &self.content.text,
+            &self.content.html,
+        ) {
+            return Err(ServerError::BadRequest(
+                "Give the content as markdown, or as both text and html".into(),
+            ));
+        }
+        validate_issue_templates(&self.title, &self.content)
+            .map_err(|e| ServerError::BadRequest(format!("Invalid template: {e}")))?;
+        let schema = AttributeSchema::load(pool)
+            .await
+            .map_err(ServerError::Unexpected)?;
+        self.audience.resolve(pool, &schema).await?;
+        Ok(())
+    }
+}
+
+pub(crate) async fn find_issue(pool: &PgPool, id: Uuid) -> ServerResult<Issue> {
+    sqlx::query_as!(
+        Issue,
+        r#"
+        SELECT * FROM newsletter_issues
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such issue {id}")))
+}
+
+/// The error for an issue that exists, but isn't in a status that allows `action`.
+async fn not_in_status(pool: &PgPool, id: Uuid, action: &str) -> ServerError {
+    match find_issue(pool, id).await {
+        Ok(issue) => {
+            ServerError::Conflict(format!("Can't {action} an issue that is {}", issue.status))
+        }
+        Err(e) => e,
+    }
+}
+
+/// Stores `body` as a new draft.
+pub async fn create_issue_row(
+    pool: &PgPool,
+    body: &IssueBody,
+    author_id: Uuid,
+) -> ServerResult<Issue> {
+    body.validate(pool).await?;
+
+    let issue = sqlx::query_as!(
+        Issue,
+        r#"
+        INSERT INTO newsletter_issues (
+            id, title, markdown_content, text_content, html_content, author_id, status,
+            segment_id, filter, tag, created_at, updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $11)
+        RETURNING *
+        "#,
+        Uuid::new_v4(),
+        body.title,
+        body.content.markdown,
+        body.content.text,
+        body.content.html,
+        author_id,
+        IssueStatus::Draft.to_string(),
+        body.audience.segment_id,
+        body.audience.filter,
+        body.audience.tag,
+        Utc::now()
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    info!(id = ?issue.id, "Issue created");
+
+    Ok(issue)
+}
+
+/// Sends a draft or scheduled issue right away, moving it through `sending` to `sent`. Only one
+/// caller gets to send it.
+pub async fn send_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    links: &SubscriberLinks,
+    id: Uuid,
+) -> ServerResult<Issue> {
+    let Some(issue) = sqlx::query_as!(
+        Issue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = $1, updated_at = $2
+        WHERE id = $3 AND status = ANY($4)
+        RETURNING *
+        "#,
+        IssueStatus::Sending.to_string(),
+        Utc::now(),
+        id,
+        &[
+            IssueStatus::Draft.to_string(),
+            IssueStatus::Scheduled.to_string()
+        ]
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    else {
+        return Err(not_in_status(pool, id, "publish").await);
+    };
+
+    finish_sending(pool, email_client, links, issue).await
+}
+
+/// Delivers an issue that was just moved to `sending`.
+pub(crate) async fn finish_sending(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    links: &SubscriberLinks,
+    issue: Issue,
+) -> ServerResult<Issue> {
+    let id = issue.id;
+    let report = match deliver_issue(pool, email_client, links, &issue).await {
+        Ok(report) => report,
+        Err(e) => {
+            // Nothing was sent yet, so it can be fixed and published again
+            sqlx::query!(
+                r#"
+                UPDATE newsletter_issues
+                SET status = $1, scheduled_at = NULL, updated_at = $2
+                WHERE id = $3
+                "#,
+                IssueStatus::Draft.to_string(),
+                Utc::now(),
+                id
+            )
+            .execute(pool)
+            .await
+            .map_err(ServerError::unexpected)?;
+            return Err(e);
+        }
+    };
+
+    let now = Utc::now();
+    let issue = sqlx::query_as!(
+        Issue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = $1, recipients = $2, delivered = $3, failed = $4, suppressed = $5,
+            sent_at = $6, updated_at = $6
+        WHERE id = $7
+        RETURNING *
+        "#,
+        IssueStatus::Sent.to_string(),
+        report.recipients as i32,
+        report.delivered as i32,
+        report.failed as i32,
+        report.suppressed as i32,
+        now,
+        id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    info!(?id, "Issue sent");
+
+    Ok(issue)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct IssuesQuery {
+    pub status: Option<String>,
+    /// `next` of the previous page.
+    pub after: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct IssuesPage {
+    pub issues: Vec<Issue>,
+    /// Pass as `after` to get the next page, missing on the last one.
+    pub next: Option<Uuid>,
+}
+
+/// Newest first.
+pub async fn list_issues(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<IssuesQuery>, QueryRejection>,
+) -> ApiResult<Json<IssuesPage>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let status = query
+        .status
+        .as_deref()
+        .map(|status| {
+            IssueStatus::from_str(status)
+                .map_err(|_| ServerError::BadRequest(format!("Unknown status \"{status}\"")))
+        })
+        .transpose()?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let issues = sqlx::query_as!(
+        Issue,
+        r#"
+        SELECT * FROM newsletter_issues
+        WHERE ($1::text IS NULL OR status = $1)
+            AND (
+                $2::uuid IS NULL
+                OR (created_at, id) < (SELECT created_at, id FROM newsletter_issues WHERE id = $2)
+            )
+        ORDER BY created_at DESC, id DESC
+        LIMIT $3
+        "#,
+        status.map(|status| status.to_string()),
+        query.after,
+        limit
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let next = (issues.len() as i64 == limit)
+        .then(|| issues.last().map(|issue| issue.id))
+        .flatten();
+
+    Ok(Json(IssuesPage { issues, next }))
+}
+
+pub async fn get_issue(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Issue>> {
+    Ok(Json(find_issue(&pool, id).await?))
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn create_issue(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    body: Result<Json<IssueBody>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Issue>)> {
+    let Json(body) = body?;
+    let issue = create_issue_row(&pool, &body, user.id).await?;
+
+    Ok((StatusCode::CREATED, Json(issue)))
+}
+
+/// Only drafts and scheduled issues can be edited.
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn update_issue(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    body: Result<Json<IssueBody>, JsonRejection>,
+) -> ApiResult<Json<Issue>> {
+    let Json(body) = body?;
+    body.validate(&pool).await?;
+
+    let Some(issue) = sqlx::query_as!(
+        Issue,
+        r#"
+        UPDATE newsletter_issues
+        SET title = $1, markdown_content = $2, text_content = $3, html_content = $4,
+            segment_id = $5, filter = $6, tag = $7, updated_at = $8
+        WHERE id = $9 AND status = ANY($10)
+        RETURNING *
+        "#,
+        body.title,
+        body.content.markdown,
+        body.content.text,
+        body.content.html,
+        body.audience.segment_id,
+        body.audience.filter,
+        body.audience.tag,
+        Utc::now(),
+        id,
+        &[
+            IssueStatus::Draft.to_string(),
+            IssueStatus::Scheduled.to_string()
+        ]
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    else {
+        return Err(not_in_status(&pool, id, "edit").await.into());
+    };
+
+    info!("Issue updated");
+
+    Ok(Json(issue))
+}
+
+/// Only drafts can be deleted, anything that was sent stays in the history.
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn delete_issue(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<StatusCode> {
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM newsletter_issues
+        WHERE id = $1 AND status = $2
+        "#,
+        id,
+        IssueStatus::Draft.to_string()
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(not_in_status(&pool, id, "delete").await.into());
+    }
+
+    info!("Issue deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends a draft or scheduled issue to its audience right away, returning the issue with the delivery counters.
+#[instrument(skip(user, state), fields(username = user.username))]
+pub async fn publish_issue(
+    user: AuthUser,
+    State(state): State<PublishState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Issue>> {
+    Ok(Json(
+        send_issue(&state.pool, &state.email_client, &state.links, id).await?,
+    ))
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleState {
+    pub pool: PgPool,
+    /// For schedules given without an offset or timezone.
+    pub timezone: Tz,
+}
+
+impl FromRef<ScheduleState> for PgPool {
+    fn from_ref(state: &ScheduleState) -> Self {
+        state.pool.clone()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ScheduleBody {
+    /// RFC 3339, or a local time like `2023-10-31T09:00` in `timezone`.
+    pub scheduled_at: String,
+    /// IANA name, e.g. `Europe/Berlin`, the configured timezone when left out.
+    pub timezone: Option<String>,
+}
+
+impl ScheduleBody {
+    fn parse(&self, default_timezone: Tz) -> ServerResult<DateTime<Utc>> {
+        if let Ok(at) = DateTime::parse_from_rfc3339(&self.scheduled_at) {
+            return Ok(at.with_timezone(&Utc));
+        }
+
+        let timezone = match &self.timezone {
+            Some(timezone) => Tz::from_str(timezone)
+                .map_err(|_| ServerError::BadRequest(format!("Unknown timezone \"{timezone}\"")))?,
+            None => default_timezone,
+        };
+        let local = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(&self.scheduled_at, format).ok())
+            .ok_or_else(|| {
+                ServerError::BadRequest(format!(
+                    "Invalid time \"{}\", expected RFC 3339 or YYYY-MM-DDTHH:MM",
+                    self.scheduled_at
+                ))
+            })?;
+
+        match timezone.from_local_datetime(&local) {
+            LocalResult::Single(at) => Ok(at.with_timezone(&Utc)),
+            // Clocks going back, take the first of the two
+            LocalResult::Ambiguous(at, _) => Ok(at.with_timezone(&Utc)),
+            LocalResult::None => Err(ServerError::BadRequest(format!(
+                "{local} doesn't exist in {timezone}, the clocks skip it"
+            ))),
+        }
+    }
+}
+
+/// Schedules a draft, or reschedules an issue that hasn't started sending yet.
+#[instrument(skip(user, state, body), fields(username = user.username))]
+pub async fn schedule_issue(
+    user: AuthUser,
+    State(state): State<ScheduleState>,
+    Path(id): Path<Uuid>,
+    body: Result<Json<ScheduleBody>, JsonRejection>,
+) -> ApiResult<Json<Issue>> {
+    let Json(body) = body?;
+    let scheduled_at = body.parse(state.timezone)?;
+    if scheduled_at <= Utc::now() {
+        return Err(ServerError::BadRequest(format!("{scheduled_at} is in the past")).into());
+    }
+
+    let Some(issue) = sqlx::query_as!(
+        Issue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = $1, scheduled_at = $2, updated_at = $3
+        WHERE id = $4 AND status = ANY($5)
+        RETURNING *
+        "#,
+        IssueStatus::Scheduled.to_string(),
+        scheduled_at,
+        Utc::now(),
+        id,
+        &[
+            IssueStatus::Draft.to_string(),
+            IssueStatus::Scheduled.to_string()
+        ]
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    else {
+        return Err(not_in_status(&state.pool, id, "schedule").await.into());
+    };
+
+    info!(%scheduled_at, "Issue scheduled");
+
+    Ok(Json(issue))
+}
+
+/// Cancels a scheduled issue before it starts sending. It's kept in the history.
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn cancel_issue(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Issue>> {
+    let now = Utc::now();
+    let Some(issue) = sqlx::query_as!(
+        Issue,
+        r#"
+        UPDATE newsletter_issues
+        SET status = $1, cancelled_at = $2, updated_at = $2
+        WHERE id = $3 AND status = $4
+        RETURNING *
+        "#,
+        IssueStatus::Cancelled.to_string(),
+        now,
+        id,
+        IssueStatus::Scheduled.to_string()
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    else {
+        return Err(not_in_status(&pool, id, "cancel").await.into());
+    };
+
+    info!("Issue cancelled");
+
+    Ok(Json(issue))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(scheduled_at: &str, timezone: Option<&str>) -> ServerResult<String> {
+        ScheduleBody {
+            scheduled_at: scheduled_at.into(),
+            timezone: timezone.map(Into::into),
+        }
+        .parse(Tz::Europe__Berlin)
+        .map(|at| at.to_rfc3339())
+    }
+
+    #[test]
+    fn takes_rfc3339_as_is() {
+        assert_eq!(
+            schedule("2023-10-31T09:00:00-04:00", Some("Asia/Tokyo")).unwrap(),
+            "2023-10-31T13:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn reads_local_times_in_the_timezone() {
+        assert_eq!(
+            schedule("2023-10-31T09:00", None).unwrap(),
+            "2023-10-31T08:00:00+00:00"
+        );
+        assert_eq!(
+            schedule("2023-10-31T09:00:30", Some("America/New_York")).unwrap(),
+            "2023-10-31T13:00:30+00:00"
+        );
+    }
+
+    #[test]
+    fn handles_clock_changes() {
+        // Clocks going back repeat 02:00-03:00, the first one is still summer time
+        assert_eq!(
+            schedule("2023-10-29T02:30", None).unwrap(),
+            "2023-10-29T00:30:00+00:00"
+        );
+        assert!(matches!(
+            schedule("2023-03-26T02:30", None),
+            Err(ServerError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(matches!(
+            schedule("2023-10-31T09:00", Some("Mars/Olympus")),
+            Err(ServerError::BadRequest(_))
+        ));
+        assert!(matches!(
+            schedule("next tuesday", None),
+            Err(ServerError::BadRequest(_))
+        ));
+    }
+}
@@ -1,11 +1,39 @@
-use axum::http::{header, StatusCode};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use std::time::Duration;
 
+pub mod api;
+pub mod attributes;
 pub mod auth;
+pub mod bot_protection;
+pub mod bounces;
 pub mod config;
+pub mod consent;
+pub mod deliverability;
+pub mod dsn;
 pub mod email;
+pub mod export;
+pub mod filter;
+pub mod gdpr;
 pub mod helpers;
+pub mod idempotency;
+pub mod import;
+pub mod issues;
+pub mod pages;
+pub mod preferences;
+pub mod preview;
+pub mod prune;
 pub mod publish;
+pub mod render;
+pub mod scheduler;
+pub mod segments;
 pub mod subscribe;
+pub mod subscribers;
+pub mod suppressions;
+pub mod tags;
+pub mod templating;
+pub mod throttle;
+pub mod transactional;
+pub mod unsubscribe;
 
 type ServerResult<T, E = ServerError> = core::result::Result<T, E>;
 
@@ -14,6 +42,16 @@ pub enum ServerError {
     #[error(transparent)]
     Auth(#[from] AuthError),
     #[error(transparent)]
+    Subscribe(#[from] SubscribeError),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unprocessable(String),
+    #[error(transparent)]
     Unexpected(#[from] anyhow::Error),
 }
 
@@ -25,6 +63,8 @@ impl ServerError {
 
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
+    #[error("Missing Basic auth credentials")]
+    MissingCredentials,
     #[error("No such user is found")]
     UserNotFound,
     #[error("Password does not match")]
@@ -33,18 +73,97 @@ pub enum AuthError {
     Unexpected(#[from] anyhow::Error),
 }
 
-impl axum::response::IntoResponse for ServerError {
-    fn into_response(self) -> axum::response::Response {
+#[derive(Debug, thiserror::Error)]
+pub enum SubscribeError {
+    #[error("The email domain \"{0}\" cannot receive mail")]
+    UndeliverableDomain(String),
+    #[error("Could not verify the email domain, please try again later")]
+    DomainCheckFailed(#[source] anyhow::Error),
+    #[error("The submission looks automated and was rejected")]
+    BotDetected,
+    #[error(
+        "Too many confirmation emails were requested, please try again in {}",
+        helpers::humanize_duration(*.retry_after)
+    )]
+    TooManyRequests { retry_after: Duration },
+    #[error("No such subscription token found")]
+    InvalidToken,
+    #[error("The subscription token has expired, please subscribe again to get a new one")]
+    ExpiredToken,
+    #[error("No such list \"{0}\"")]
+    UnknownList(String),
+    #[error(transparent)]
+    InvalidAttributes(#[from] attributes::AttributeError),
+    #[error(transparent)]
+    Unexpected(#[from] anyhow::Error),
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::Auth(_) => StatusCode::UNAUTHORIZED,
+            ServerError::Subscribe(e) => match e {
+                SubscribeError::UndeliverableDomain(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                SubscribeError::DomainCheckFailed(_) => StatusCode::SERVICE_UNAVAILABLE,
+                SubscribeError::BotDetected => StatusCode::BAD_REQUEST,
+                SubscribeError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+                SubscribeError::InvalidToken => StatusCode::NOT_FOUND,
+                SubscribeError::ExpiredToken => StatusCode::GONE,
+                SubscribeError::UnknownList(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                SubscribeError::InvalidAttributes(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                SubscribeError::Unexpected(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ServerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::Conflict(_) => StatusCode::CONFLICT,
+            ServerError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ServerError::Unexpected(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine readable identifier of the error, used by the JSON API.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::Auth(_) => "unauthorized",
+            ServerError::Subscribe(e) => match e {
+                SubscribeError::UndeliverableDomain(_) => "undeliverable_domain",
+                SubscribeError::DomainCheckFailed(_) => "domain_check_failed",
+                SubscribeError::BotDetected => "bot_detected",
+                SubscribeError::TooManyRequests { .. } => "too_many_requests",
+                SubscribeError::InvalidToken => "invalid_token",
+                SubscribeError::ExpiredToken => "expired_token",
+                SubscribeError::UnknownList(_) => "unknown_list",
+                SubscribeError::InvalidAttributes(_) => "invalid_attributes",
+                SubscribeError::Unexpected(_) => "internal",
+            },
+            ServerError::BadRequest(_) => "bad_request",
+            ServerError::NotFound(_) => "not_found",
+            ServerError::Conflict(_) => "conflict",
+            ServerError::Unprocessable(_) => "unprocessable",
+            ServerError::Unexpected(_) => "internal",
+        }
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
         match self {
-            ServerError::Auth(e) => (
-                StatusCode::UNAUTHORIZED,
-                [(header::WWW_AUTHENTICATE, "Basic")],
-                e.to_string(),
-            )
-                .into_response(),
-            ServerError::Unexpected(e) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+            ServerError::Auth(_) => {
+                headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic"));
             }
+            ServerError::Subscribe(SubscribeError::TooManyRequests { retry_after }) => {
+                headers.insert(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+                );
+            }
+            _ => {}
         }
+        headers
+    }
+}
+
+impl axum::response::IntoResponse for ServerError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status(), self.headers(), self.to_string()).into_response()
     }
 }
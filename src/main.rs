@@ -1,27 +1,106 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use axum::{
     body::Bytes,
-    extract::MatchedPath,
+    extract::{DefaultBodyLimit, MatchedPath},
     http::{HeaderMap, Request, StatusCode},
     response::Response,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
+use clap::{Parser, Subcommand};
+use mailmule::attributes::{
+    delete_attribute_definition, list_attribute_definitions, put_attribute_definition,
+};
+use mailmule::bounces::{
+    get_bounce_policy, get_subscriber_bounces, postmark_webhook, BouncePolicy, BounceState,
+};
+use mailmule::consent::export_consent;
+use mailmule::dsn::BounceMailbox;
+use mailmule::export::export_subscribers;
+use mailmule::gdpr::{
+    erase_data_subject, erase_own_data, export_own_data, get_data_subject, manage_data,
+    request_data, DataRequestState,
+};
+use mailmule::idempotency::IdempotencyStore;
+use mailmule::import::{import_subscribers, MAX_IMPORT_BYTES};
+use mailmule::issues::{
+    cancel_issue, create_issue, delete_issue, get_issue, list_issues, publish_issue,
+    schedule_issue, update_issue, ScheduleState,
+};
+use mailmule::preferences::{preferences_page, update_preferences};
+use mailmule::preview::{preview_issue, send_test_issue};
+use mailmule::prune::Pruner;
+use mailmule::scheduler::Scheduler;
+use mailmule::segments::{
+    count_audience, create_segment, delete_segment, get_segment, list_segments, update_segment,
+};
+use mailmule::subscribers::{
+    change_subscriber_status, delete_subscriber, get_subscriber, list_subscribers,
+    resend_confirmation,
+};
+use mailmule::suppressions::{
+    add_suppression, list_suppressions, remove_suppression, SuppressionList,
+};
+use mailmule::tags::{
+    add_subscriber_tag, bulk_tag, list_tag_events, list_tagged_subscribers, list_tags,
+    remove_subscriber_tag,
+};
+use mailmule::templating::SubscriberLinks;
+use mailmule::transactional::{
+    delete_template, get_template, list_templates, preview_template, put_template, TemplateState,
+};
+use mailmule::unsubscribe::{unsubscribe, unsubscribe_page};
 use mailmule::{
-    auth::login, config::Config, email::EmailClient, helpers::SocketAddr, publish::PublishState,
+    auth::{create_user, login},
+    bot_protection::BotProtection,
+    config::Config,
+    deliverability::DomainChecker,
+    email::EmailClient,
+    helpers::SocketAddr,
+    publish::PublishState,
+    throttle::ConfirmationThrottle,
 };
 use mailmule::{
     publish::publish,
-    subscribe::{subscribe, subscribe_confirm, SubscribeState},
+    subscribe::{
+        api_subscribe, api_subscribe_confirm, subscribe, subscribe_confirm, subscribe_form_token,
+        ConfirmState, SubscribeState,
+    },
 };
-use std::{sync::Arc, time::Duration};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
 use tracing::{info, info_span, Span};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the server, the default.
+    Serve,
+    /// Delete never-confirmed subscribers and expired tokens once, then exit.
+    Prune,
+    /// Process the delivery status notifications in the bounce Maildir once, then exit.
+    IngestBounces {
+        /// Instead of `bounce_mailbox.maildir` from the config.
+        #[arg(long)]
+        maildir: Option<PathBuf>,
+    },
+    /// Add a user for the admin API, with the password from `MAILMULE_PASSWORD` or the first
+    /// line of stdin, then exit.
+    CreateUser { username: String },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     tracing_subscriber::Registry::default()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -34,83 +113,380 @@ async fn main() -> Result<()> {
         .init();
 
     let mut cfg = Config::load()?;
-    let email_client = Arc::new(EmailClient::try_from(cfg.email_client)?);
+    let domain_checker = if cfg.domain_check.enabled {
+        Some(DomainChecker::try_from(cfg.domain_check)?)
+    } else {
+        None
+    };
+    let bot_protection = BotProtection::try_from(cfg.bot_protection)?;
+    let lists = Arc::new(cfg.lists);
 
     let pg_opts = cfg.database.url.0;
     info!(pg_opts = ?pg_opts.clone().password("REDACTED"), "Connecting to the database");
     let pool = sqlx::PgPool::connect_with(pg_opts).await?;
     info!("Connected to the database");
 
-    let app = Router::new()
+    let email_client = Arc::new(
+        EmailClient::try_from(cfg.email_client)?
+            .with_suppressions(SuppressionList::new(pool.clone())),
+    );
+
+    let confirmation_throttle = ConfirmationThrottle::from(cfg.confirmation_throttle);
+    let idempotency = IdempotencyStore::new(cfg.idempotency.ttl_ms);
+    let pruner = Pruner::new(
+        &cfg.pruning,
+        cfg.confirmation.token_ttl_ms,
+        confirmation_throttle,
+        cfg.data_requests.token_ttl_ms,
+        idempotency,
+    );
+    let bounce_policy = BouncePolicy::from(cfg.bounce_policy);
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Prune => {
+            pruner.run(&pool).await?;
+            return Ok(());
+        }
+        Command::CreateUser { username } => {
+            let password = match std::env::var("MAILMULE_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                    line.trim_end_matches(['\r', '\n']).to_owned()
+                }
+            };
+            if password.is_empty() {
+                bail!("The password can't be empty");
+            }
+            create_user(&pool, &username, password).await?;
+            return Ok(());
+        }
+        Command::IngestBounces { maildir } => {
+            let maildir = maildir
+                .or(cfg.bounce_mailbox.maildir)
+                .context("No Maildir given, pass --maildir or set bounce_mailbox.maildir")?;
+            BounceMailbox::new(maildir, bounce_policy)
+                .run(&pool)
+                .await?;
+            return Ok(());
+        }
+        Command::Serve => {}
+    }
+    if cfg.pruning.enabled {
+        tokio::spawn(pruner.run_periodically(pool.clone(), cfg.pruning.interval_ms));
+    }
+    if let Some(maildir) = cfg.bounce_mailbox.maildir {
+        tokio::spawn(
+            BounceMailbox::new(maildir, bounce_policy)
+                .run_periodically(pool.clone(), cfg.bounce_mailbox.interval_ms),
+        );
+    }
+
+    let subscriber_links = SubscriberLinks::new(&cfg.app.base_url()?)?;
+
+    if cfg.scheduler.enabled {
+        tokio::spawn(
+            Scheduler::new(email_client.clone(), subscriber_links.clone())
+                .run_periodically(pool.clone(), cfg.scheduler.interval_ms),
+        );
+    }
+
+    let bounce_state = BounceState {
+        pool: pool.clone(),
+        policy: bounce_policy,
+    };
+
+    let publish_state = PublishState {
+        pool: pool.clone(),
+        email_client: email_client.clone(),
+        idempotency,
+        links: subscriber_links.clone(),
+    };
+
+    let schedule_state = ScheduleState {
+        pool: pool.clone(),
+        timezone: cfg.scheduler.timezone,
+    };
+
+    let data_request_state = DataRequestState {
+        pool: pool.clone(),
+        email_client: email_client.clone(),
+        manage_endpoint: cfg.app.base_url()?.join("data-requests/")?.join("manage")?,
+        throttle: confirmation_throttle,
+        trust_x_forwarded_for: cfg.app.trust_x_forwarded_for,
+        token_ttl: cfg.data_requests.token_ttl_ms,
+        theme: cfg.confirmation.theme.clone(),
+    };
+    let subscribe_state = SubscribeState {
+        pool: pool.clone(),
+        email_client: email_client.clone(),
+        subscribe_confirm_endpoint: cfg.app.base_url()?.join("subscribe/")?.join("confirm")?,
+        domain_checker,
+        bot_protection,
+        confirmation_throttle,
+        trust_x_forwarded_for: cfg.app.trust_x_forwarded_for,
+        lists: lists.clone(),
+        token_ttl: cfg.confirmation.token_ttl_ms,
+    };
+    let confirm_state = ConfirmState {
+        pool: pool.clone(),
+        email_client: email_client.clone(),
+        confirmation: Arc::new(cfg.confirmation),
+        lists: lists.clone(),
+        trust_x_forwarded_for: cfg.app.trust_x_forwarded_for,
+        links: subscriber_links,
+        transactional_emails: cfg.transactional_emails,
+    };
+    let template_state = TemplateState {
+        pool: pool.clone(),
+        lists,
+    };
+
+    let mut app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/health", get(|| async { StatusCode::OK }))
         .route(
             "/subscribe",
-            post(subscribe).with_state(SubscribeState {
-                pool: pool.clone(),
-                email_client: email_client.clone(),
-                subscribe_confirm_endpoint: cfg
-                    .app
-                    .base_url()?
-                    .join("subscribe/")?
-                    .join("confirm")?,
-            }),
+            post(subscribe).with_state(subscribe_state.clone()),
+        )
+        .route(
+            "/subscribe/token",
+            get(subscribe_form_token).with_state(subscribe_state.clone()),
         )
         .route(
             "/subscribe/confirm",
-            get(subscribe_confirm).with_state(pool.clone()),
+            get(subscribe_confirm).with_state(confirm_state.clone()),
         )
         .route(
-            "/publish",
-            post(publish).with_state(PublishState {
-                pool: pool.clone(),
-                email_client: email_client.clone(),
-            }),
+            "/unsubscribe",
+            get(unsubscribe_page)
+                .post(unsubscribe)
+                .with_state(confirm_state.clone()),
+        )
+        .route(
+            "/preferences",
+            get(preferences_page)
+                .post(update_preferences)
+                .with_state(confirm_state.clone()),
+        )
+        .route(
+            "/api/v1/subscribers",
+            get(list_subscribers)
+                .post(api_subscribe)
+                .with_state(subscribe_state.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id",
+            get(get_subscriber)
+                .delete(delete_subscriber)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id/status",
+            put(change_subscriber_status).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id/confirmation",
+            post(resend_confirmation).with_state(subscribe_state.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/import",
+            post(import_subscribers)
+                .layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES))
+                .with_state(subscribe_state),
+        )
+        .route(
+            "/api/v1/subscribers/export",
+            get(export_subscribers).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/confirm",
+            post(api_subscribe_confirm).with_state(confirm_state),
         )
-        .route("/login", get(login).with_state(pool.clone()))
-        .route(
-            "/signup",
-            post(mailmule::auth::singup).with_state(pool.clone()),
-        )
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(|request: &Request<_>| {
-                    // Log the matched route's path (with placeholders not filled in).
-                    // Use request.uri() or OriginalUri if you want the real path.
-                    let matched_path = request
-                        .extensions()
-                        .get::<MatchedPath>()
-                        .map(MatchedPath::as_str);
-
-                    info_span!(
-                        "http_request",
-                        method = ?request.method(),
-                        matched_path,
-                        some_other_field = tracing::field::Empty,
-                    )
-                })
-                .on_request(|_request: &Request<_>, _span: &Span| {
-                    // You can use `_span.record("some_other_field", value)` in one of these
-                    // closures to attach a value to the initially empty field in the info_span
-                    // created above.
-                })
-                .on_response(|_response: &Response, _latency: Duration, _span: &Span| {
+        .route(
+            "/api/v1/attributes",
+            get(list_attribute_definitions).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/attributes/:key",
+            put(put_attribute_definition)
+                .delete(delete_attribute_definition)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/segments",
+            get(list_segments)
+                .post(create_segment)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/segments/count",
+            post(count_audience).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/segments/:id",
+            get(get_segment)
+                .put(update_segment)
+                .delete(delete_segment)
+                .with_state(pool.clone()),
+        )
+        .route("/api/v1/tags", get(list_tags).with_state(pool.clone()))
+        .route(
+            "/api/v1/tags/:tag/subscribers",
+            get(list_tagged_subscribers).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/tags/:tag/events",
+            get(list_tag_events).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/tags/:tag/bulk",
+            post(bulk_tag).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id/tags/:tag",
+            put(add_subscriber_tag)
+                .delete(remove_subscriber_tag)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id/consent",
+            get(export_consent).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/subscribers/:id/bounces",
+            get(get_subscriber_bounces).with_state(bounce_state.clone()),
+        )
+        .route(
+            "/api/v1/bounce-policy",
+            get(get_bounce_policy).with_state(bounce_state.clone()),
+        )
+        .route(
+            "/api/v1/suppressions",
+            get(list_suppressions)
+                .post(add_suppression)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/suppressions/:email",
+            delete(remove_suppression).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/data-subjects",
+            get(get_data_subject)
+                .delete(erase_data_subject)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/issues",
+            get(list_issues).post(create_issue).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/issues/:id",
+            get(get_issue)
+                .put(update_issue)
+                .delete(delete_issue)
+                .with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/issues/:id/publish",
+            post(publish_issue).with_state(publish_state.clone()),
+        )
+        .route(
+            "/api/v1/issues/:id/preview",
+            get(preview_issue).with_state(publish_state.clone()),
+        )
+        .route(
+            "/api/v1/issues/:id/test",
+            post(send_test_issue).with_state(publish_state.clone()),
+        )
+        .route(
+            "/api/v1/email-templates",
+            get(list_templates).with_state(pool.clone()),
+        )
+        .route(
+            "/api/v1/email-templates/:kind",
+            get(get_template)
+                .put(put_template)
+                .delete(delete_template)
+                .with_state(template_state.clone()),
+        )
+        .route(
+            "/api/v1/email-templates/:kind/preview",
+            get(preview_template).with_state(template_state),
+        )
+        .route(
+            "/api/v1/issues/:id/schedule",
+            post(schedule_issue).with_state(schedule_state),
+        )
+        .route(
+            "/api/v1/issues/:id/cancel",
+            post(cancel_issue).with_state(pool.clone()),
+        )
+        .route(
+            "/webhooks/postmark",
+            post(postmark_webhook).with_state(bounce_state.clone()),
+        )
+        .route("/publish", post(publish).with_state(publish_state.clone()))
+        .route("/login", get(login).with_state(pool.clone()));
+    if cfg.data_requests.self_service {
+        app = app
+            .route(
+                "/data-requests",
+                post(request_data).with_state(data_request_state.clone()),
+            )
+            .route(
+                "/data-requests/manage",
+                get(manage_data).with_state(data_request_state.clone()),
+            )
+            .route(
+                "/data-requests/export",
+                post(export_own_data).with_state(data_request_state.clone()),
+            )
+            .route(
+                "/data-requests/erase",
+                post(erase_own_data).with_state(data_request_state),
+            );
+    }
+    let app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|request: &Request<_>| {
+                // Log the matched route's path (with placeholders not filled in).
+                // Use request.uri() or OriginalUri if you want the real path.
+                let matched_path = request
+                    .extensions()
+                    .get::<MatchedPath>()
+                    .map(MatchedPath::as_str);
+
+                info_span!(
+                    "http_request",
+                    method = ?request.method(),
+                    matched_path,
+                    some_other_field = tracing::field::Empty,
+                )
+            })
+            .on_request(|_request: &Request<_>, _span: &Span| {
+                // You can use `_span.record("some_other_field", value)` in one of these
+                // closures to attach a value to the initially empty field in the info_span
+                // created above.
+            })
+            .on_response(|_response: &Response, _latency: Duration, _span: &Span| {
+                // ...
+            })
+            .on_body_chunk(|_chunk: &Bytes, _latency: Duration, _span: &Span| {
+                // ...
+            })
+            .on_eos(
+                |_trailers: Option<&HeaderMap>, _stream_duration: Duration, _span: &Span| {
                     // ...
-                })
-                .on_body_chunk(|_chunk: &Bytes, _latency: Duration, _span: &Span| {
+                },
+            )
+            .on_failure(
+                |_error: ServerErrorsFailureClass, _latency: Duration, _span: &Span| {
                     // ...
-                })
-                .on_eos(
-                    |_trailers: Option<&HeaderMap>, _stream_duration: Duration, _span: &Span| {
-                        // ...
-                    },
-                )
-                .on_failure(
-                    |_error: ServerErrorsFailureClass, _latency: Duration, _span: &Span| {
-                        // ...
-                    },
-                ),
-        );
+                },
+            ),
+    );
 
     let listener = TcpListener::bind(cfg.app.socket_addr.0).await?;
     // Updating socket after listening
@@ -121,7 +497,7 @@ async fn main() -> Result<()> {
         "Starting server on"
     );
     axum::Server::from_tcp(listener.into_std()?)?
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
 
@@ -0,0 +1,126 @@
+use crate::{config::PageThemeConfig, helpers::escape_html};
+use axum::{http::StatusCode, response::Html};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum ConfirmationPage {
+    Confirmed,
+    AlreadyConfirmed,
+    ExpiredToken,
+    InvalidToken,
+}
+
+impl ConfirmationPage {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Confirmed | Self::AlreadyConfirmed)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Confirmed | Self::AlreadyConfirmed => StatusCode::OK,
+            Self::ExpiredToken => StatusCode::GONE,
+            Self::InvalidToken => StatusCode::NOT_FOUND,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "Subscription confirmed",
+            Self::AlreadyConfirmed => "Already confirmed",
+            Self::ExpiredToken => "Link expired",
+            Self::InvalidToken => "Invalid link",
+        }
+    }
+
+    fn message(&self, list_name: &str) -> String {
+        match self {
+            Self::Confirmed => format!("Thanks! You're now subscribed to {list_name}."),
+            Self::AlreadyConfirmed => {
+                format!(
+                    "Your subscription to {list_name} was already confirmed, nothing else to do."
+                )
+            }
+            Self::ExpiredToken => "This confirmation link has expired. \
+                Subscribe again and we'll send you a fresh one."
+                .into(),
+            Self::InvalidToken => "This confirmation link is not valid. \
+                Make sure you opened the complete link from the email."
+                .into(),
+        }
+    }
+}
+
+/// Renders one of the built-in landing pages, `list_name` defaults to the brand name.
+pub fn render_confirmation_page(
+    theme: &PageThemeConfig,
+    page: ConfirmationPage,
+    list_name: Option<&str>,
+) -> Html<String> {
+    let list_name = list_name.unwrap_or(&theme.brand_name);
+    render_page(
+        theme,
+        &page.to_string(),
+        page.title(),
+        &format!("<p>{}</p>", escape_html(&page.message(list_name))),
+    )
+}
+
+/// Wraps `content`, which must already be escaped, into the themed page layout.
+pub fn render_page(
+    theme: &PageThemeConfig,
+    class: &str,
+    title: &str,
+    content: &str,
+) -> Html<String> {
+    let brand_name = escape_html(&theme.brand_name);
+    let logo = theme
+        .logo_url
+        .as_ref()
+        .map(|url| {
+            format!(
+                r#"<img class="logo" src="{}" alt="{brand_name}" />"#,
+                escape_html(url.0.as_str())
+            )
+        })
+        .unwrap_or_default();
+    let stylesheet = theme
+        .stylesheet_url
+        .as_ref()
+        .map(|url| {
+            format!(
+                r#"<link rel="stylesheet" href="{}" />"#,
+                escape_html(url.0.as_str())
+            )
+        })
+        .unwrap_or_default();
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+    <title>{title} - {brand_name}</title>
+    <style>
+        body {{ font-family: system-ui, sans-serif; background: #f4f4f5; color: #18181b; margin: 0; }}
+        main {{ max-width: 32rem; margin: 10vh auto; background: #fff; padding: 2rem; border-radius: 0.5rem;
+            border-top: 0.25rem solid {accent_color}; }}
+        h1 {{ color: {accent_color}; margin-top: 0; }}
+        .logo {{ max-height: 3rem; margin-bottom: 1rem; }}
+    </style>
+    {stylesheet}
+</head>
+<body>
+    <main class="{class}">
+        {logo}
+        <h1>{title}</h1>
+        {content}
+    </main>
+</body>
+</html>
+"#,
+        title = escape_html(title),
+        class = escape_html(class),
+        accent_color = escape_html(&theme.accent_color),
+    ))
+}
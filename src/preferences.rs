@@ -0,0 +1,245 @@
+//! The page linked from every issue, where subscribers pick the lists they're on.
+
+use crate::{
+    consent::{record_consent, ConsentKind, ConsentSource},
+    helpers::escape_html,
+    pages::render_page,
+    subscribe::{ConfirmState, SubscriptionStatus},
+    unsubscribe::UnsubscribeQuery,
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Form,
+};
+use chrono::Utc;
+use serde_json::json;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+};
+use tracing::{info, instrument};
+
+fn invalid_link_page(state: &ConfirmState) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        render_page(
+            &state.confirmation.theme,
+            "preferences",
+            "Invalid link",
+            "<p>This preferences link is not valid. \
+            Make sure you opened the complete link from the email.</p>",
+        ),
+    )
+        .into_response()
+}
+
+fn preferences_form(state: &ConfirmState, token: &str, joined: &BTreeSet<String>) -> String {
+    let mut slugs = state.lists.keys().collect::<Vec<_>>();
+    slugs.sort();
+    let checkboxes = slugs
+        .into_iter()
+        .map(|slug| {
+            format!(
+                r#"<label><input type="checkbox" name="{slug}"{checked} /> {name}</label><br />"#,
+                slug = escape_html(slug),
+                checked = if joined.contains(slug) {
+                    " checked"
+                } else {
+                    ""
+                },
+                name = escape_html(&state.lists[slug].name),
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<form method="post">
+            {checkboxes}
+            <button type="submit">Save</button>
+        </form>
+        <p><a href="unsubscribe?token={token}">Unsubscribe from everything</a></p>"#,
+        token = escape_html(token),
+    )
+}
+
+/// The lists the subscriber is on, to be changed with a click.
+pub async fn preferences_page(
+    State(state): State<ConfirmState>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> ServerResult<Response> {
+    let token = query.token.unwrap_or_default();
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id, email, status FROM subscribers
+        WHERE unsubscribe_token = $1
+        "#,
+        token
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let Some(subscriber) = subscriber else {
+        return Ok(invalid_link_page(&state));
+    };
+
+    if subscriber.status != SubscriptionStatus::Confirmed.to_string() {
+        return Ok(render_page(
+            &state.confirmation.theme,
+            "preferences",
+            "Not subscribed",
+            &format!(
+                "<p>{} isn't subscribed to {} anymore.</p>",
+                escape_html(&subscriber.email),
+                escape_html(&state.confirmation.theme.brand_name)
+            ),
+        )
+        .into_response());
+    }
+
+    let joined = sqlx::query_scalar!(
+        r#"
+        SELECT list FROM list_subscriptions
+        WHERE subscriber_id = $1
+        "#,
+        subscriber.id
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .into_iter()
+    .collect();
+
+    Ok(render_page(
+        &state.confirmation.theme,
+        "preferences",
+        "Email preferences",
+        &format!(
+            "<p>Pick the lists {} gets emails from.</p>{}",
+            escape_html(&subscriber.email),
+            preferences_form(&state, &token, &joined)
+        ),
+    )
+    .into_response())
+}
+
+/// Joins the checked lists and leaves the rest, recording consent for each change.
+#[instrument(skip(state, headers, query, form), fields(%client_addr))]
+pub async fn update_preferences(
+    State(state): State<ConfirmState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<UnsubscribeQuery>,
+    Form(form): Form<HashMap<String, String>>,
+) -> ServerResult<Response> {
+    let token = query.token.unwrap_or_default();
+    let mut transaction = state.pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id FROM subscribers
+        WHERE unsubscribe_token = $1 AND status = $2
+        FOR UPDATE
+        "#,
+        token,
+        SubscriptionStatus::Confirmed.to_string()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let Some(subscriber) = subscriber else {
+        return Ok(invalid_link_page(&state));
+    };
+    let uuid = subscriber.id;
+
+    let joined: BTreeSet<String> = sqlx::query_scalar!(
+        r#"
+        SELECT list FROM list_subscriptions
+        WHERE subscriber_id = $1
+        "#,
+        uuid
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .into_iter()
+    .collect();
+    // Unknown lists are ignored, and lists that aren't configured anymore are left alone
+    let wanted: BTreeSet<String> = form
+        .into_keys()
+        .filter(|slug| state.lists.contains_key(slug))
+        .collect();
+    let leaving = joined
+        .iter()
+        .filter(|slug| state.lists.contains_key(*slug) && !wanted.contains(*slug))
+        .collect::<Vec<_>>();
+    let joining = wanted.difference(&joined).collect::<Vec<_>>();
+
+    let source =
+        ConsentSource::from_request("link", &headers, client_addr, state.trust_x_forwarded_for);
+    for list in &joining {
+        sqlx::query!(
+            r#"
+            INSERT INTO list_subscriptions (list, subscriber_id, subscribed_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+            list,
+            uuid,
+            Utc::now()
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::unexpected)?;
+        record_consent(
+            &mut *transaction,
+            &[uuid],
+            ConsentKind::Confirmation,
+            Some(list),
+            &source,
+            json!({ "via": "preferences" }),
+        )
+        .await?;
+    }
+    for list in &leaving {
+        sqlx::query!(
+            r#"
+            DELETE FROM list_subscriptions
+            WHERE list = $1 AND subscriber_id = $2
+            "#,
+            list,
+            uuid
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::unexpected)?;
+        record_consent(
+            &mut *transaction,
+            &[uuid],
+            ConsentKind::Unsubscribe,
+            Some(list),
+            &source,
+            json!({ "via": "preferences" }),
+        )
+        .await?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(?uuid, ?joining, ?leaving, "Updated preferences");
+
+    Ok(render_page(
+        &state.confirmation.theme,
+        "preferences",
+        "Preferences saved",
+        &format!(
+            "<p>Your preferences are saved.</p>{}",
+            preferences_form(&state, &token, &wanted)
+        ),
+    )
+    .into_response())
+}
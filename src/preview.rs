@@ -0,0 +1,231 @@
+//! Previews and test sends, to check an issue before publishing it.
+
+use crate::{
+    api::ApiResult,
+    auth::AuthUser,
+    email::{EmailAdderess, Suppressed},
+    issues::find_issue,
+    publish::PublishState,
+    render::{IssueRenderer, RenderedIssue},
+    templating::Recipient,
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, State,
+    },
+    Json,
+};
+use futures::{stream, StreamExt};
+use sqlx::PgPool;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+/// Subscribers with this tag get test sends when asked for seeds.
+pub const SEED_TAG: &str = "seed";
+
+/// Prepended to the subject of test sends.
+pub const TEST_SUBJECT_PREFIX: &str = "[TEST]";
+
+/// Most addresses a single test send goes to, seeds included.
+const MAX_TEST_RECIPIENTS: usize = 50;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PreviewQuery {
+    pub subscriber_id: Option<Uuid>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct IssuePreview {
+    pub subscriber: Recipient,
+    #[serde(flatten)]
+    pub rendered: RenderedIssue,
+}
+
+async fn find_sample(
+    pool: &PgPool,
+    id: Option<Uuid>,
+    email: Option<&str>,
+) -> ServerResult<Option<Recipient>> {
+    sqlx::query_as!(
+        Recipient,
+        r#"
+        SELECT id AS "id?", email, name, attributes, unsubscribe_token AS "unsubscribe_token?"
+        FROM subscribers
+        WHERE ($1::uuid IS NULL OR id = $1) AND ($2::text IS NULL OR lower(email) = lower($2))
+        ORDER BY subscribed_at
+        LIMIT 1
+        "#,
+        id,
+        email
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(ServerError::unexpected)
+}
+
+/// Renders the issue as `subscriber_id` or `email` would get it, or the first subscriber when
+/// neither is given.
+pub async fn preview_issue(
+    _user: AuthUser,
+    State(state): State<PublishState>,
+    Path(id): Path<Uuid>,
+    query: Result<Query<PreviewQuery>, QueryRejection>,
+) -> ApiResult<Json<IssuePreview>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let pool = &state.pool;
+    let issue = find_issue(pool, id).await?;
+
+    let subscriber = match (query.subscriber_id, query.email) {
+        (Some(subscriber_id), _) => find_sample(pool, Some(subscriber_id), None)
+            .await?
+            .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {subscriber_id}")))?,
+        (None, Some(email)) => find_sample(pool, None, Some(&email))
+            .await?
+            .unwrap_or_else(|| Recipient::unknown(email)),
+        (None, None) => find_sample(pool, None, None)
+            .await?
+            .unwrap_or_else(|| Recipient::unknown("subscriber@example.com".into())),
+    };
+
+    let rendered = IssueRenderer::new(&issue, &state.links)
+        .and_then(|renderer| renderer.render(&subscriber))
+        .map_err(|e| ServerError::BadRequest(format!("Invalid template: {e}")))?;
+
+    Ok(Json(IssuePreview {
+        subscriber,
+        rendered,
+    }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TestSendBody {
+    #[serde(default)]
+    pub emails: Vec<EmailAdderess>,
+    /// Also send to the subscribers tagged [`SEED_TAG`].
+    #[serde(default)]
+    pub seeds: bool,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct TestSendReport {
+    pub sent: Vec<String>,
+    pub suppressed: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Sends the issue with a `[TEST]` subject to the given addresses and the seed subscribers. Test
+/// sends aren't recorded as deliveries, and don't change the issue's status.
+#[instrument(skip(user, state, body), fields(username = user.username))]
+pub async fn send_test_issue(
+    user: AuthUser,
+    State(state): State<PublishState>,
+    Path(id): Path<Uuid>,
+    body: Result<Json<TestSendBody>, JsonRejection>,
+) -> ApiResult<Json<TestSendReport>> {
+    let Json(body) = body?;
+    let issue = find_issue(&state.pool, id).await?;
+
+    let mut recipients: Vec<String> = body
+        .emails
+        .iter()
+        .map(|email| email.as_ref().to_lowercase())
+        .collect();
+    if body.seeds {
+        recipients.extend(
+            sqlx::query_scalar!(
+                r#"
+                SELECT lower(email) AS "email!" FROM subscribers
+                JOIN subscriber_tags ON subscriber_tags.subscriber_id = subscribers.id
+                WHERE subscriber_tags.tag = $1
+                ORDER BY email
+                "#,
+                SEED_TAG
+            )
+            .fetch_all(&state.pool)
+            .await
+            .map_err(ServerError::unexpected)?,
+        );
+    }
+    recipients.sort();
+    recipients.dedup();
+    if recipients.is_empty() {
+        return Err(ServerError::BadRequest(format!(
+            "No recipients, give emails or tag seed subscribers with \"{SEED_TAG}\""
+        ))
+        .into());
+    }
+    if recipients.len() > MAX_TEST_RECIPIENTS {
+        return Err(ServerError::BadRequest(format!(
+            "A test send can go to at most {MAX_TEST_RECIPIENTS} addresses"
+        ))
+        .into());
+    }
+
+    let renderer = IssueRenderer::new(&issue, &state.links)
+        .map_err(|e| ServerError::BadRequest(format!("Invalid template: {e}")))?;
+
+    let mut report = TestSendReport::default();
+    let mut messages = Vec::with_capacity(recipients.len());
+    for email in recipients {
+        let Ok(address) = EmailAdderess::new(email.clone()) else {
+            report.failed.push(email);
+            continue;
+        };
+        // Personalized like the real thing for subscribers
+        let recipient = find_sample(&state.pool, None, Some(&email))
+            .await?
+            .unwrap_or_else(|| Recipient::unknown(email.clone()));
+        match renderer.render(&recipient) {
+            Ok(mut rendered) => {
+                rendered.subject = format!("{TEST_SUBJECT_PREFIX} {}", rendered.subject);
+                messages.push((address, rendered));
+            }
+            Err(err) => {
+                warn!(to = email, %err, "Failed to render test email");
+                report.failed.push(email);
+            }
+        }
+    }
+    let email_client = &state.email_client;
+    // Collected first, like in `deliver_issue`
+    let sends = messages.iter().map(|(email, rendered)| async move {
+        let res = email_client
+            .send_email_with_metadata(
+                email,
+                &rendered.subject,
+                &rendered.text,
+                &rendered.html,
+                &[],
+                &rendered.headers(),
+            )
+            .await;
+        (email, res)
+    });
+    let results = stream::iter(sends.collect::<Vec<_>>())
+        .buffer_unordered(email_client.max_concurrent_sends)
+        .collect::<Vec<_>>()
+        .await;
+    for (email, res) in results {
+        let email = email.as_ref().to_owned();
+        match res {
+            Ok(()) => report.sent.push(email),
+            Err(err) if err.is::<Suppressed>() => report.suppressed.push(email),
+            Err(err) => {
+                warn!(to = email, err = ?err.context("Failed to send test email"));
+                report.failed.push(email);
+            }
+        }
+    }
+
+    info!(
+        sent = report.sent.len(),
+        suppressed = report.suppressed.len(),
+        failed = report.failed.len(),
+        "Sent test issue"
+    );
+
+    Ok(Json(report))
+}
@@ -0,0 +1,153 @@
+use crate::{
+    config::PruningConfig, idempotency::IdempotencyStore, subscribe::SubscriptionStatus,
+    throttle::ConfirmationThrottle,
+};
+use anyhow::Result;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct PruneReport {
+    pub pending_subscribers: u64,
+    pub subscription_tokens: u64,
+    pub throttle_entries: u64,
+    pub data_request_tokens: u64,
+    pub idempotency_keys: u64,
+}
+
+/// Cleans up what unconfirmed signups, expired links and old idempotency keys leave behind.
+#[derive(Debug, Clone, Copy)]
+pub struct Pruner {
+    /// Pending subscribers with no signup or confirmation token newer than this are deleted.
+    pub pending_retention: Duration,
+    pub throttle: ConfirmationThrottle,
+    pub data_request_token_ttl: Duration,
+    pub idempotency: IdempotencyStore,
+}
+
+impl Pruner {
+    pub fn new(
+        cfg: &PruningConfig,
+        confirmation_token_ttl: Duration,
+        throttle: ConfirmationThrottle,
+        data_request_token_ttl: Duration,
+        idempotency: IdempotencyStore,
+    ) -> Self {
+        // Never delete someone who can still confirm
+        let pending_retention = if cfg.pending_retention_ms < confirmation_token_ttl {
+            warn!(
+                retention = ?cfg.pending_retention_ms,
+                token_ttl = ?confirmation_token_ttl,
+                "Pending retention is shorter than the confirmation token TTL, using the TTL instead"
+            );
+            confirmation_token_ttl
+        } else {
+            cfg.pending_retention_ms
+        };
+
+        Self {
+            pending_retention,
+            throttle,
+            data_request_token_ttl,
+            idempotency,
+        }
+    }
+
+    pub async fn run(&self, pool: &PgPool) -> Result<PruneReport> {
+        let retention_cutoff = Utc::now() - chrono::Duration::from_std(self.pending_retention)?;
+        let data_request_cutoff =
+            Utc::now() - chrono::Duration::from_std(self.data_request_token_ttl)?;
+        let mut report = PruneReport::default();
+
+        let mut transaction = pool.begin().await?;
+
+        let stale = sqlx::query_scalar!(
+            r#"
+            SELECT id FROM subscribers
+            WHERE status = $2
+                AND subscribed_at < $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM subscription_tokens
+                    WHERE subscription_tokens.subscriber_id = subscribers.id
+                        AND subscription_tokens.created_at >= $1
+                )
+            FOR UPDATE
+            "#,
+            retention_cutoff,
+            SubscriptionStatus::Pending.to_string()
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        // Tokens of subscribers that have confirmed or left since only serve old links
+        report.subscription_tokens = sqlx::query!(
+            r#"
+            DELETE FROM subscription_tokens
+            WHERE subscriber_id = ANY($1)
+                OR (
+                    created_at < $2
+                    AND subscriber_id IN (SELECT id FROM subscribers WHERE status <> $3)
+                )
+            "#,
+            &stale,
+            retention_cutoff,
+            SubscriptionStatus::Pending.to_string()
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        report.pending_subscribers = sqlx::query!(
+            r#"
+            DELETE FROM subscribers
+            WHERE id = ANY($1)
+            "#,
+            &stale
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        report.data_request_tokens = sqlx::query!(
+            r#"
+            DELETE FROM data_request_tokens
+            WHERE created_at < $1
+            "#,
+            data_request_cutoff
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        report.throttle_entries = self.throttle.prune(pool).await?;
+        report.idempotency_keys = self.idempotency.prune(pool).await?;
+
+        info!(
+            pending_subscribers = report.pending_subscribers,
+            subscription_tokens = report.subscription_tokens,
+            throttle_entries = report.throttle_entries,
+            data_request_tokens = report.data_request_tokens,
+            idempotency_keys = report.idempotency_keys,
+            "Pruned stale data"
+        );
+
+        Ok(report)
+    }
+
+    /// Runs forever, every `interval`. Instances running it side by side only race for the same
+    /// rows to delete.
+    pub async fn run_periodically(self, pool: PgPool, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.run(&pool).await {
+                error!(error = ?e, "Pruning failed");
+            }
+        }
+    }
+}
@@ -1,100 +1,260 @@
 use crate::{
-    email::{EmailAdderess, EmailClient},
-    subscribe::SubscriptionStatus,
+    attributes::AttributeSchema,
+    auth::AuthUser,
+    email::{EmailAdderess, EmailClient, Suppressed},
+    idempotency::{request_hash, IdempotencyKey, IdempotencyStore, NextAction},
+    issues::{create_issue_row, find_issue, send_issue, Issue, IssueBody, IssueStatus},
+    render::IssueRenderer,
+    segments::recipients_query,
+    templating::{Recipient, SubscriberLinks},
     ServerError, ServerResult,
 };
 use axum::response::IntoResponse;
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
+    extract::{FromRef, Json, State},
+    http::{HeaderMap, StatusCode},
     response::Response,
 };
-use futures::future;
+use chrono::Utc;
+use futures::{stream, StreamExt};
 use sqlx::PgPool;
 use std::sync::Arc;
-use tracing::{info, instrument, warn};
-
-#[derive(Debug, serde::Deserialize)]
-pub struct PublishBody {
-    title: String,
-    content: PublishContent,
-}
-
-#[derive(Debug, serde::Deserialize)]
-pub struct PublishContent {
-    text: String,
-    html: String,
-}
+use tracing::{error, info, instrument, warn, Instrument};
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct PublishState {
     pub pool: PgPool,
     pub email_client: Arc<EmailClient>,
+    pub idempotency: IdempotencyStore,
+    pub links: SubscriberLinks,
+}
+
+impl FromRef<PublishState> for PgPool {
+    fn from_ref(state: &PublishState) -> Self {
+        state.pool.clone()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct DeliveryReport {
+    /// Confirmed subscribers in the audience.
+    pub recipients: usize,
+    pub delivered: usize,
+    /// Invalid addresses and sends that failed.
+    pub failed: usize,
+    pub suppressed: usize,
 }
 
+/// Sends `issue` to its audience and records who it was delivered to.
+#[instrument(skip(pool, email_client, links, issue), fields(issue_id = %issue.id))]
+pub async fn deliver_issue(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    links: &SubscriberLinks,
+    issue: &Issue,
+) -> ServerResult<DeliveryReport> {
+    let mut report = DeliveryReport::default();
+
+    let schema = AttributeSchema::load(pool)
+        .await
+        .map_err(ServerError::Unexpected)?;
+    let filter = issue.audience().resolve(pool, &schema).await?;
+
+    // Templates were checked when the issue was stored, this only fails for issues stored before
+    let renderer = IssueRenderer::new(issue, links)
+        .map_err(|e| ServerError::BadRequest(format!("Invalid template: {e}")))?;
+
+    let recipients = recipients_query(
+        "id, email, name, attributes, unsubscribe_token",
+        filter.as_ref(),
+        &schema,
+    )
+    .build_query_as::<(Uuid, String, String, serde_json::Value, String)>()
+    .fetch_all(pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    report.recipients = recipients.len();
+
+    let mut messages = Vec::with_capacity(recipients.len());
+    for (id, email, name, attributes, unsubscribe_token) in recipients {
+        let address = match EmailAdderess::new(email.clone()) {
+            Ok(address) => address,
+            Err(err) => {
+                warn!(err = ?err.context("Skipping subscriber due to invalid data"));
+                report.failed += 1;
+                continue;
+            }
+        };
+        let recipient = Recipient {
+            id: Some(id),
+            email,
+            name,
+            attributes,
+            unsubscribe_token: Some(unsubscribe_token),
+        };
+        match renderer.render(&recipient) {
+            Ok(rendered) => messages.push((id, address, rendered)),
+            Err(err) => {
+                warn!(to = address.as_ref(), %err, "Failed to render email");
+                report.failed += 1;
+            }
+        }
+    }
+
+    // Bounces and complaints are recorded against the issue they came back from
+    let issue_id = issue.id.to_string();
+    let metadata = [("issue_id", issue_id.as_str())];
+    info!("Rendered emails for valid addresses, now sending them");
+
+    // Collected first, the compiler can't tell a stream mapped over borrows is `Send`
+    let sends = messages.iter().map(|(id, email, rendered)| async move {
+        let res = email_client
+            .send_email_with_metadata(
+                email,
+                &rendered.subject,
+                &rendered.text,
+                &rendered.html,
+                &metadata,
+                &rendered.headers(),
+            )
+            .await;
+        (id, email, res)
+    });
+    let results = stream::iter(sends.collect::<Vec<_>>())
+        .buffer_unordered(email_client.max_concurrent_sends)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut delivered = Vec::with_capacity(messages.len());
+    for (id, email, res) in results {
+        match res {
+            Err(err) if err.is::<Suppressed>() => report.suppressed += 1,
+            Err(err) => {
+                warn!(to = email.as_ref(), err = ?err.context("Failed to send email"));
+                report.failed += 1;
+            }
+            Ok(()) => delivered.push(*id),
+        }
+    }
+    report.delivered = delivered.len();
+
+    // What soft bounces are scored against. The mails are out either way, so this can't fail the
+    // delivery.
+    if let Err(e) = sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (issue_id, subscriber_id, sent_at)
+        SELECT $1, id, $2 FROM unnest($3::uuid[]) AS id
+        "#,
+        issue.id,
+        Utc::now(),
+        &delivered
+    )
+    .execute(pool)
+    .await
+    {
+        error!(error = ?e, "Failed to record issue deliveries");
+    }
+
+    info!(
+        recipients = report.recipients,
+        delivered = report.delivered,
+        failed = report.failed,
+        suppressed = report.suppressed,
+        "Dispatched content to subscribers"
+    );
+
+    Ok(report)
+}
+
+/// Publishes straight away, without a draft first. The issue is still kept, with `user` as its
+/// author.
+///
+/// With an `Idempotency-Key`, retries get the response of the first request instead of sending
+/// the issue again. The key is only let go of when nothing was sent, so that the retry can send.
 #[instrument(
-    skip(state, body),
-    fields(title = body.title)
+    skip(user, state, headers, body),
+    fields(username = user.username, title = body.title)
 )]
 pub async fn publish(
+    user: AuthUser,
     State(state): State<PublishState>,
-    Json(body): Json<PublishBody>,
+    headers: HeaderMap,
+    Json(body): Json<IssueBody>,
 ) -> ServerResult<Response> {
-    let mut valids = 0usize;
-    let mut total = 0usize;
-    let mut fails = 0usize;
+    let key = IdempotencyKey::from_headers(&headers)?;
 
-    let confirmed_emails: Vec<EmailAdderess> = sqlx::query!(
-        r#"
-        SELECT email FROM subscribers
-        WHERE status = $1
-        "#,
-        SubscriptionStatus::Confirmed.to_string()
+    // Spawned, so that a client going away doesn't stop the sending halfway through
+    tokio::spawn(
+        async move {
+            match key {
+                Some(key) => publish_idempotently(&state, &user, &body, &key).await,
+                None => publish_issue_body(&state, &user, &body)
+                    .await
+                    .map_err(|(e, _)| e),
+            }
+        }
+        .in_current_span(),
     )
-    .fetch_all(&state.pool)
     .await
     .map_err(ServerError::unexpected)?
-    .into_iter()
-    .map(|obj| EmailAdderess::new(obj.email))
-    .inspect(|res| {
-        if res.is_ok() {
-            valids = valids + 1;
-        }
-        total = total + 1;
-    })
-    .filter_map(|res| match res {
-        Ok(email) => Some(email),
-        Err(err) => {
-            warn!(err = ?err.context("Skipping subscriber due to invalid data"));
-            None
-        }
-    })
-    .collect();
-
-    info!("Evaluated valid email addresses, now sending emails");
+}
 
-    for (i, res) in future::join_all(confirmed_emails.iter().map(|email| {
-        state
-            .email_client
-            .send_email(&email, &body.title, &body.content.text, &body.content.html)
-    }))
-    .await
-    .into_iter()
-    .enumerate()
+async fn publish_idempotently(
+    state: &PublishState,
+    user: &AuthUser,
+    body: &IssueBody,
+    key: &IdempotencyKey,
+) -> ServerResult<Response> {
+    let request_hash = request_hash(body)?;
+    if let NextAction::ReturnSavedResponse(response) = state
+        .idempotency
+        .try_processing(&state.pool, user.id, key, &request_hash)
+        .await?
     {
-        if let Err(err) = res {
-            warn!(to = confirmed_emails[i].as_ref(), err = ?err.context("Failed to send email"));
-            fails = fails + 1;
-        }
+        return Ok(response);
     }
 
-    info!(valids, fails, total, "Dispatched content to subscribers");
+    let response = match publish_issue_body(state, user, body).await {
+        Ok(response) => response,
+        Err((e, false)) => {
+            state.idempotency.release(&state.pool, user.id, key).await?;
+            return Err(e);
+        }
+        Err((e, true)) => e.into_response(),
+    };
+    state
+        .idempotency
+        .save_response(&state.pool, user.id, key, response)
+        .await
+}
+
+/// Fails with whether any email may have gone out.
+async fn publish_issue_body(
+    state: &PublishState,
+    user: &AuthUser,
+    body: &IssueBody,
+) -> Result<Response, (ServerError, bool)> {
+    let issue = create_issue_row(&state.pool, body, user.id)
+        .await
+        .map_err(|e| (e, false))?;
+    let issue = match send_issue(&state.pool, &state.email_client, &state.links, issue.id).await {
+        Ok(issue) => issue,
+        Err(e) => {
+            // Failed deliveries go back to drafts before anything is sent
+            let sent = !matches!(
+                find_issue(&state.pool, issue.id).await,
+                Ok(issue) if issue.status == IssueStatus::Draft.to_string()
+            );
+            return Err((e, sent));
+        }
+    };
 
     Ok((
         StatusCode::OK,
         format!(
-            "Dispatched content to {}/{total} subscribers.",
-            valids - fails
+            "Dispatched content to {}/{} subscribers, {} suppressed.",
+            issue.delivered, issue.recipients, issue.suppressed
         ),
     )
         .into_response())
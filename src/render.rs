@@ -0,0 +1,480 @@
+//! Turns a stored issue into the email that goes out.
+//!
+//! Issues written in Markdown (CommonMark with tables and footnotes) get an HTML part inside the
+//! email layout and a plain-text part with the links as footnotes. Hand-written text or HTML
+//! replaces the rendered part. All of them are templates, see [`crate::templating`].
+
+use crate::{
+    issues::{Issue, IssueContent},
+    templating::{Recipient, SubscriberLinks, TemplateError, Templates},
+};
+use pulldown_cmark::{escape::escape_html, html, Event, HeadingLevel, Options, Parser, Tag};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RenderedIssue {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+    /// `<unsubscribe link>`, for recipients that can unsubscribe.
+    #[serde(skip)]
+    pub list_unsubscribe: Option<String>,
+}
+
+impl RenderedIssue {
+    /// `List-Unsubscribe` with one-click unsubscribes (RFC 8058), which mail clients show as an
+    /// unsubscribe button.
+    pub fn headers(&self) -> Vec<(&str, &str)> {
+        match &self.list_unsubscribe {
+            Some(list_unsubscribe) => vec![
+                ("List-Unsubscribe", list_unsubscribe.as_str()),
+                ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// What issue templates can use.
+pub const ISSUE_VARIABLES: &[&str] = &[
+    "subscriber.id",
+    "subscriber.email",
+    "subscriber.name",
+    "subscriber.attributes",
+    "unsubscribe_url",
+    "preferences_url",
+    "issue.id",
+    "issue.title",
+];
+
+const SUBJECT: &str = "subject.txt";
+const MARKDOWN: &str = "body.md";
+const TEXT: &str = "body.txt";
+const HTML: &str = "body.html";
+
+fn compile(title: &str, content: &IssueContent) -> Result<Templates, TemplateError> {
+    let parts = [
+        (SUBJECT, Some(title)),
+        (MARKDOWN, content.markdown.as_deref()),
+        (TEXT, content.text.as_deref()),
+        (HTML, content.html.as_deref()),
+    ];
+    Templates::new(
+        parts
+            .into_iter()
+            .filter_map(|(name, source)| Some((name, source?.to_owned()))),
+        ISSUE_VARIABLES,
+    )
+}
+
+/// Checks the title and content templates of an issue, before it's stored.
+pub fn validate_issue_templates(title: &str, content: &IssueContent) -> Result<(), TemplateError> {
+    compile(title, content).map(|_| ())
+}
+
+#[derive(serde::Serialize)]
+struct IssueVariables<'a> {
+    id: Uuid,
+    title: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct RecipientVariables<'a> {
+    subscriber: &'a Recipient,
+    unsubscribe_url: &'a str,
+    preferences_url: &'a str,
+    issue: IssueVariables<'a>,
+}
+
+/// The templates of an issue, compiled once and rendered for each recipient.
+#[derive(Debug)]
+pub struct IssueRenderer<'a> {
+    issue: &'a Issue,
+    links: &'a SubscriberLinks,
+    templates: Templates,
+}
+
+impl<'a> IssueRenderer<'a> {
+    pub fn new(issue: &'a Issue, links: &'a SubscriberLinks) -> Result<Self, TemplateError> {
+        let content = IssueContent {
+            markdown: issue.markdown_content.clone(),
+            text: issue.text_content.clone(),
+            html: issue.html_content.clone(),
+        };
+        Ok(Self {
+            issue,
+            links,
+            templates: compile(&issue.title, &content)?,
+        })
+    }
+
+    /// What `recipient` gets. Used for deliveries, previews and test sends alike, so that what
+    /// editors look at is what goes out.
+    pub fn render(&self, recipient: &Recipient) -> Result<RenderedIssue, TemplateError> {
+        let unsubscribe_url = self.links.unsubscribe_url(recipient).to_string();
+        let preferences_url = self.links.preferences_url(recipient).to_string();
+        let variables = RecipientVariables {
+            subscriber: recipient,
+            unsubscribe_url: &unsubscribe_url,
+            preferences_url: &preferences_url,
+            issue: IssueVariables {
+                id: self.issue.id,
+                title: &self.issue.title,
+            },
+        };
+
+        let subject = self.templates.render(SUBJECT, &variables)?;
+        let markdown = match self.templates.has(MARKDOWN) {
+            true => self.templates.render(MARKDOWN, &variables)?,
+            false => String::new(),
+        };
+        let text = match self.templates.has(TEXT) {
+            true => self.templates.render(TEXT, &variables)?,
+            false => markdown_to_text(&markdown),
+        };
+        let html = match self.templates.has(HTML) {
+            true => self.templates.render(HTML, &variables)?,
+            false => email_layout(&subject, &markdown_to_html(&markdown)),
+        };
+
+        Ok(RenderedIssue {
+            subject,
+            text,
+            html,
+            list_unsubscribe: recipient
+                .unsubscribe_token
+                .is_some()
+                .then(|| format!("<{unsubscribe_url}>")),
+        })
+    }
+}
+
+fn parser(markdown: &str) -> Parser<'_, '_> {
+    Parser::new_ext(markdown, Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES)
+}
+
+/// Raw HTML in the Markdown is escaped, mail clients are picky about markup and the layout
+/// shouldn't break. Use the HTML override for full control.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut body = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(
+        &mut body,
+        parser(markdown).map(|event| match event {
+            Event::Html(html) => Event::Text(html),
+            event => event,
+        }),
+    );
+    body
+}
+
+/// Table-based, with the styles that matter inline, which is what mail clients render reliably.
+fn email_layout(title: &str, body: &str) -> String {
+    let mut escaped_title = String::new();
+    escape_html(&mut escaped_title, title).expect("Writing to a String can't fail");
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{escaped_title}</title>
+<style>
+  a {{ color: #4f46e5; }}
+  img {{ max-width: 100%; height: auto; }}
+  pre {{ background: #f4f4f5; padding: 12px; overflow-x: auto; }}
+  table.content table {{ border-collapse: collapse; }}
+  table.content table th, table.content table td {{ border: 1px solid #e4e4e7; padding: 6px 10px; }}
+  blockquote {{ margin: 0; padding-left: 12px; border-left: 3px solid #e4e4e7; color: #52525b; }}
+</style>
+</head>
+<body style="margin: 0; padding: 0; background: #f4f4f5;">
+<table role="presentation" width="100%" cellpadding="0" cellspacing="0" border="0" style="background: #f4f4f5;">
+<tr><td align="center" style="padding: 24px 12px;">
+<table role="presentation" class="content" width="100%" cellpadding="0" cellspacing="0" border="0" style="max-width: 600px; background: #ffffff; border-radius: 8px;">
+<tr><td style="padding: 32px; font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Helvetica, Arial, sans-serif; font-size: 16px; line-height: 1.6; color: #18181b;">
+{body}</td></tr>
+</table>
+</td></tr>
+</table>
+</body>
+</html>
+"#
+    )
+}
+
+/// Readable as is, with `[1]` after link texts and the URLs listed at the end.
+pub fn markdown_to_text(markdown: &str) -> String {
+    let mut text = TextWriter::default();
+    for event in parser(markdown) {
+        text.event(event);
+    }
+    text.finish()
+}
+
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    links: Vec<String>,
+    /// Target of each link being written.
+    link_targets: Vec<String>,
+    /// Next number of each ordered list, `None` for bullet lists.
+    lists: Vec<Option<u64>>,
+    /// Where the heading being written starts in `out`.
+    heading_start: usize,
+    in_code_block: bool,
+    /// Its first paragraph goes on the same line as the label.
+    in_footnote_definition: bool,
+    /// Cells of the table row being written, the last one is being written.
+    cells: Option<Vec<String>>,
+}
+
+impl TextWriter {
+    fn push(&mut self, s: &str) {
+        match self.cells.as_mut().and_then(|cells| cells.last_mut()) {
+            Some(cell) => cell.push_str(s),
+            None => self.out.push_str(s),
+        }
+    }
+
+    /// Ends the current line.
+    fn line_break(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+
+    /// Leaves an empty line before the next block.
+    fn block_break(&mut self) {
+        self.line_break();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.in_code_block => {
+                for line in text.lines() {
+                    self.out.push_str("    ");
+                    self.out.push_str(line);
+                    self.out.push('\n');
+                }
+            }
+            Event::Text(text) | Event::Code(text) | Event::Html(text) => self.push(&text),
+            Event::FootnoteReference(label) => self.push(&format!("[^{label}]")),
+            Event::SoftBreak | Event::HardBreak => self.push("\n"),
+            Event::Rule => {
+                self.block_break();
+                self.out.push_str("----------");
+                self.block_break();
+            }
+            Event::TaskListMarker(checked) => self.push(if checked { "[x] " } else { "[ ] " }),
+        }
+    }
+
+    fn start(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Paragraph if self.in_footnote_definition => self.in_footnote_definition = false,
+            Tag::Paragraph | Tag::BlockQuote if self.lists.is_empty() => self.block_break(),
+            Tag::Paragraph | Tag::BlockQuote => {}
+            Tag::Heading(..) => {
+                self.block_break();
+                self.heading_start = self.out.len();
+            }
+            Tag::CodeBlock(_) => {
+                self.block_break();
+                self.in_code_block = true;
+            }
+            Tag::List(first) => {
+                if self.lists.is_empty() {
+                    self.block_break();
+                } else {
+                    self.line_break();
+                }
+                self.lists.push(first);
+            }
+            Tag::Item => {
+                self.line_break();
+                let depth = self.lists.len().saturating_sub(1);
+                self.out.push_str(&"  ".repeat(depth));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".into(),
+                };
+                self.out.push_str(&marker);
+            }
+            Tag::FootnoteDefinition(label) => {
+                self.block_break();
+                self.out.push_str(&format!("[^{label}]: "));
+                self.in_footnote_definition = true;
+            }
+            Tag::Table(_) => self.block_break(),
+            Tag::TableHead | Tag::TableRow => self.cells = Some(Vec::new()),
+            Tag::TableCell => {
+                if let Some(cells) = self.cells.as_mut() {
+                    cells.push(String::new());
+                }
+            }
+            Tag::Emphasis => self.push("_"),
+            Tag::Strong => self.push("*"),
+            Tag::Strikethrough => self.push("~"),
+            Tag::Link(_, target, _) | Tag::Image(_, target, _) => {
+                self.link_targets.push(target.into_string())
+            }
+        }
+    }
+
+    fn end(&mut self, tag: Tag<'_>) {
+        match tag {
+            Tag::Paragraph | Tag::BlockQuote if self.lists.is_empty() => self.block_break(),
+            Tag::Paragraph | Tag::BlockQuote => self.line_break(),
+            Tag::Heading(level, ..) => {
+                let width = self.out[self.heading_start..].chars().count();
+                let underline = match level {
+                    HeadingLevel::H1 => Some("="),
+                    HeadingLevel::H2 => Some("-"),
+                    _ => None,
+                };
+                if let Some(underline) = underline {
+                    self.out.push('\n');
+                    self.out.push_str(&underline.repeat(width));
+                }
+                self.block_break();
+            }
+            Tag::CodeBlock(_) => {
+                self.in_code_block = false;
+                self.block_break();
+            }
+            Tag::List(_) => {
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.block_break();
+                }
+            }
+            Tag::Item => self.line_break(),
+            Tag::FootnoteDefinition(_) | Tag::Table(_) => self.block_break(),
+            Tag::TableHead | Tag::TableRow => {
+                let cells = self.cells.take().unwrap_or_default();
+                let row = cells
+                    .iter()
+                    .map(|cell| cell.trim())
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                self.out.push_str(&row);
+                self.out.push('\n');
+                if let Tag::TableHead = tag {
+                    let rule = cells
+                        .iter()
+                        .map(|cell| "-".repeat(cell.trim().chars().count().max(3)))
+                        .collect::<Vec<_>>()
+                        .join("-|-");
+                    self.out.push_str(&rule);
+                    self.out.push('\n');
+                }
+            }
+            Tag::TableCell => {}
+            Tag::Emphasis => self.push("_"),
+            Tag::Strong => self.push("*"),
+            Tag::Strikethrough => self.push("~"),
+            Tag::Link(..) | Tag::Image(..) => {
+                let Some(target) = self.link_targets.pop() else {
+                    return;
+                };
+                // Autolinks already show the URL
+                let url = target.trim_start_matches("mailto:");
+                let shown = match &self.cells {
+                    Some(cells) => cells.last().is_some_and(|cell| cell.ends_with(url)),
+                    None => self.out.ends_with(url),
+                };
+                if !shown && !target.is_empty() && !target.starts_with('#') {
+                    self.links.push(target);
+                    let marker = format!("[{}]", self.links.len());
+                    self.push(&marker);
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.line_break();
+        // Only blank lines, a leading code block keeps its indentation
+        let mut text = self.out.trim_start_matches('\n').trim_end().to_owned();
+        if !self.links.is_empty() {
+            text.push_str("\n\n");
+            for (number, link) in self.links.iter().enumerate() {
+                text.push_str(&format!("[{}] {link}\n", number + 1));
+            }
+        }
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_structure_readable() {
+        assert_eq!(
+            markdown_to_text(
+                "# Title\n\nSome *emphasis*, **bold** and `code`.\n\n## Sub\n\n### Low"
+            ),
+            "Title\n=====\n\nSome _emphasis_, *bold* and code.\n\nSub\n---\n\nLow\n"
+        );
+        assert_eq!(
+            markdown_to_text("line one  \nline two\n\n---\n\n> quoted"),
+            "line one\nline two\n\n----------\n\nquoted\n"
+        );
+    }
+
+    #[test]
+    fn numbers_links() {
+        assert_eq!(
+            markdown_to_text(
+                "Read [the post](https://example.com/a), ![a chart](https://example.com/c.png) \
+                and <https://example.com/b>, or [jump](#end)."
+            ),
+            "Read the post[1], a chart[2] and https://example.com/b, or jump.\n\n\
+            [1] https://example.com/a\n[2] https://example.com/c.png\n"
+        );
+    }
+
+    #[test]
+    fn writes_lists() {
+        assert_eq!(
+            markdown_to_text("- one\n- two\n  - nested\n\n3. three\n4. four\n\n- [x] done"),
+            "- one\n- two\n  - nested\n\n3. three\n4. four\n\n- [x] done\n"
+        );
+    }
+
+    #[test]
+    fn indents_code_blocks() {
+        assert_eq!(
+            markdown_to_text("```\nlet x = 1;\nlet y = 2;\n```\n\nAfter"),
+            "    let x = 1;\n    let y = 2;\n\nAfter\n"
+        );
+    }
+
+    #[test]
+    fn writes_tables_and_footnotes() {
+        assert_eq!(
+            markdown_to_text("| Plan | Price |\n|---|---|\n| Pro | [5](https://example.com) |"),
+            "Plan | Price\n-----|------\nPro | 5[1]\n\n[1] https://example.com\n"
+        );
+        assert_eq!(
+            markdown_to_text("Note[^1].\n\n[^1]: The note."),
+            "Note[^1].\n\n[^1]: The note.\n"
+        );
+    }
+}
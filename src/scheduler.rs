@@ -0,0 +1,83 @@
+use crate::{
+    email::EmailClient,
+    issues::{finish_sending, Issue, IssueStatus},
+    templating::SubscriberLinks,
+};
+use anyhow::Result;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info};
+
+/// Sends scheduled issues once they're due. Schedules live in the database, so they survive
+/// restarts, and claiming an issue moves it to `sending` in the same statement, so only one
+/// instance ever sends it.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    pub email_client: Arc<EmailClient>,
+    pub links: SubscriberLinks,
+}
+
+impl Scheduler {
+    pub fn new(email_client: Arc<EmailClient>, links: SubscriberLinks) -> Self {
+        Self {
+            email_client,
+            links,
+        }
+    }
+
+    /// Moves the earliest due issue to `sending`, skipping any another instance is claiming.
+    async fn claim_due(pool: &PgPool) -> sqlx::Result<Option<Issue>> {
+        sqlx::query_as!(
+            Issue,
+            r#"
+            UPDATE newsletter_issues
+            SET status = $1, updated_at = $2
+            WHERE id = (
+                SELECT id FROM newsletter_issues
+                WHERE status = $3 AND scheduled_at <= $2
+                ORDER BY scheduled_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+            "#,
+            IssueStatus::Sending.to_string(),
+            Utc::now(),
+            IssueStatus::Scheduled.to_string()
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Sends every due issue, returning how many were sent.
+    pub async fn run(&self, pool: &PgPool) -> Result<u64> {
+        let mut sent = 0;
+        while let Some(issue) = Self::claim_due(pool).await? {
+            let id = issue.id;
+            match finish_sending(pool, &self.email_client, &self.links, issue).await {
+                Ok(_) => sent += 1,
+                // It's back to a draft, the next one can still go out
+                Err(e) => error!(?id, error = ?e, "Failed to send a scheduled issue"),
+            }
+        }
+
+        if sent > 0 {
+            info!(sent, "Sent scheduled issues");
+        }
+
+        Ok(sent)
+    }
+
+    /// Runs forever, every `interval`.
+    pub async fn run_periodically(self, pool: PgPool, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.run(&pool).await {
+                error!(error = ?e, "Sending scheduled issues failed");
+            }
+        }
+    }
+}
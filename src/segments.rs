@@ -0,0 +1,278 @@
+use crate::{
+    api::ApiResult, attributes::AttributeSchema, auth::AuthUser, filter::Filter,
+    subscribe::SubscriptionStatus, tags::normalize_tag, ServerError, ServerResult,
+};
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Segment {
+    pub id: Uuid,
+    pub name: String,
+    pub filter: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Who to send to, either a saved segment, an inline filter or a tag.
+/// Leaving all of them out means every confirmed subscriber.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Audience {
+    pub segment_id: Option<Uuid>,
+    pub filter: Option<String>,
+    /// Shorthand for `tag = "<tag>"`.
+    pub tag: Option<String>,
+}
+
+fn parse_filter(filter: &str, schema: &AttributeSchema) -> ServerResult<Filter> {
+    let filter = Filter::parse(filter).map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    filter
+        .check(schema)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    Ok(filter)
+}
+
+impl Audience {
+    pub async fn resolve(
+        &self,
+        pool: &PgPool,
+        schema: &AttributeSchema,
+    ) -> ServerResult<Option<Filter>> {
+        let filter = match (&self.segment_id, &self.filter, &self.tag) {
+            (Some(id), None, None) => Some(
+                sqlx::query!(
+                    r#"
+                    SELECT filter FROM segments
+                    WHERE id = $1
+                    "#,
+                    id
+                )
+                .fetch_optional(pool)
+                .await
+                .map_err(ServerError::unexpected)?
+                .ok_or_else(|| ServerError::NotFound(format!("No such segment {id}")))?
+                .filter,
+            ),
+            (None, Some(filter), None) => Some(filter.clone()),
+            (None, None, Some(tag)) => return Ok(Some(Filter::Tag(normalize_tag(tag)?))),
+            (None, None, None) => None,
+            _ => {
+                return Err(ServerError::BadRequest(
+                    "Only one of segment_id, filter and tag can be given".into(),
+                ))
+            }
+        };
+
+        filter
+            .map(|filter| parse_filter(&filter, schema))
+            .transpose()
+    }
+}
+
+/// `SELECT <columns> FROM subscribers`, narrowed down to the confirmed subscribers matching `filter`.
+pub fn recipients_query<'a>(
+    columns: &str,
+    filter: Option<&Filter>,
+    schema: &AttributeSchema,
+) -> QueryBuilder<'a, Postgres> {
+    let mut qb = QueryBuilder::new(format!("SELECT {columns} FROM subscribers WHERE status = "));
+    qb.push_bind(SubscriptionStatus::Confirmed.to_string());
+    if let Some(filter) = filter {
+        qb.push(" AND ");
+        filter.push_sql(&mut qb, schema);
+    }
+    qb
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SegmentBody {
+    pub name: String,
+    pub filter: String,
+}
+
+impl SegmentBody {
+    async fn validate(&self, pool: &PgPool) -> ServerResult<()> {
+        if self.name.trim().is_empty() {
+            return Err(ServerError::BadRequest(
+                "Segment name can't be empty".into(),
+            ));
+        }
+        let schema = AttributeSchema::load(pool)
+            .await
+            .map_err(ServerError::Unexpected)?;
+        parse_filter(&self.filter, &schema)?;
+        Ok(())
+    }
+}
+
+fn map_unique_violation(err: sqlx::Error, name: &str) -> ServerError {
+    match err.as_database_error() {
+        Some(db_err) if db_err.is_unique_violation() => {
+            ServerError::Conflict(format!("A segment named \"{name}\" already exists"))
+        }
+        _ => ServerError::unexpected(err),
+    }
+}
+
+pub async fn list_segments(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+) -> ApiResult<Json<Vec<Segment>>> {
+    let segments = sqlx::query_as!(
+        Segment,
+        r#"
+        SELECT id, name, filter, created_at, updated_at FROM segments
+        ORDER BY name
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(Json(segments))
+}
+
+pub async fn get_segment(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Segment>> {
+    let segment = sqlx::query_as!(
+        Segment,
+        r#"
+        SELECT id, name, filter, created_at, updated_at FROM segments
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such segment {id}")))?;
+
+    Ok(Json(segment))
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn create_segment(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    body: Result<Json<SegmentBody>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Segment>)> {
+    let Json(body) = body?;
+    body.validate(&pool).await?;
+
+    let now = Utc::now();
+    let segment = sqlx::query_as!(
+        Segment,
+        r#"
+        INSERT INTO segments (id, name, filter, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $4)
+        RETURNING id, name, filter, created_at, updated_at
+        "#,
+        Uuid::new_v4(),
+        body.name,
+        body.filter,
+        now
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| map_unique_violation(e, &body.name))?;
+
+    info!(id = ?segment.id, "Segment created");
+
+    Ok((StatusCode::CREATED, Json(segment)))
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn update_segment(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    body: Result<Json<SegmentBody>, JsonRejection>,
+) -> ApiResult<Json<Segment>> {
+    let Json(body) = body?;
+    body.validate(&pool).await?;
+
+    let segment = sqlx::query_as!(
+        Segment,
+        r#"
+        UPDATE segments
+        SET name = $1, filter = $2, updated_at = $3
+        WHERE id = $4
+        RETURNING id, name, filter, created_at, updated_at
+        "#,
+        body.name,
+        body.filter,
+        Utc::now(),
+        id
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| map_unique_violation(e, &body.name))?
+    .ok_or_else(|| ServerError::NotFound(format!("No such segment {id}")))?;
+
+    info!("Segment updated");
+
+    Ok(Json(segment))
+}
+
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn delete_segment(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<StatusCode> {
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM segments
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(ServerError::NotFound(format!("No such segment {id}")).into());
+    }
+
+    info!("Segment deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct AudienceCount {
+    pub recipients: i64,
+}
+
+/// Dry-run of a publish target, counts the confirmed subscribers it would reach.
+pub async fn count_audience(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    body: Result<Json<Audience>, JsonRejection>,
+) -> ApiResult<Json<AudienceCount>> {
+    let Json(audience) = body?;
+    let schema = AttributeSchema::load(&pool)
+        .await
+        .map_err(ServerError::Unexpected)?;
+    let filter = audience.resolve(&pool, &schema).await?;
+
+    let recipients = recipients_query("COUNT(*)", filter.as_ref(), &schema)
+        .build_query_scalar::<i64>()
+        .fetch_one(&pool)
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    Ok(Json(AudienceCount { recipients }))
+}
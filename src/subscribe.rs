@@ -1,20 +1,36 @@
-use crate::email::{EmailAdderess, EmailClient};
-use crate::{ServerError, ServerResult};
+use crate::api::ApiResult;
+use crate::attributes::{AttributeSchema, AttributeValues};
+use crate::bot_protection::{BotProtection, BotProtectionFields};
+use crate::config::{ConfirmationConfig, ListConfig, TransactionalEmailsConfig};
+use crate::consent::{record_consent, ConsentKind, ConsentSource};
+use crate::deliverability::DomainChecker;
+use crate::email::{EmailAdderess, EmailClient, Suppressed};
+use crate::pages::{render_confirmation_page, ConfirmationPage};
+use crate::templating::{Recipient, SubscriberLinks};
+use crate::throttle::ConfirmationThrottle;
+use crate::transactional::{send_transactional, Locale, TransactionalKind, TransactionalRecipient};
+use crate::{helpers, ServerError, ServerResult, SubscribeError};
 use anyhow::{bail, Context, Result};
-use axum::extract::Query;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Form};
-use chrono::Utc;
+use axum::extract::{rejection::JsonRejection, ConnectInfo, FromRef, Query};
+use axum::http::HeaderMap;
+use axum::response::{Redirect, Response};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Form, Json};
+use chrono::{DateTime, Utc};
 use rand::Rng;
-use sqlx::PgPool;
+use serde_json::json;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{info, instrument};
+use std::time::Duration;
+use tracing::{info, instrument, warn};
 use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
-const SUBSCRIPTION_TOKEN_LEN: usize = 26;
+pub(crate) const SUBSCRIPTION_TOKEN_LEN: usize = 26;
 
-fn gen_subscription_token(len: usize) -> String {
+pub(crate) fn gen_subscription_token(len: usize) -> String {
     let mut rng = rand::thread_rng();
     std::iter::repeat_with(|| rng.sample(rand::distributions::Alphanumeric))
         .map(char::from)
@@ -27,19 +43,54 @@ fn gen_subscription_token(len: usize) -> String {
 pub struct SubscriptionForm {
     pub name: SubscriberName,
     pub email: EmailAdderess,
+    /// Slug of one of the configured lists.
+    pub list: Option<String>,
+    /// Picks the language of transactional emails, e.g. `de`. Only taken for new subscribers, so
+    /// that anyone posting the form can't change it for an existing one.
+    pub locale: Option<Locale>,
+    /// `{"company": "Acme"}` in JSON bodies.
+    #[serde(default)]
+    pub attributes: AttributeValues,
+    #[serde(flatten)]
+    pub bot_protection: BotProtectionFields,
+    /// Catches `attributes[company]=Acme` from urlencoded forms, which can't nest.
+    #[serde(flatten)]
+    pub rest: HashMap<String, serde_json::Value>,
+}
+
+impl SubscriptionForm {
+    /// Attributes from both the JSON object and the `attributes[...]` form fields.
+    pub fn attributes(&self) -> AttributeValues {
+        let mut attributes = self.attributes.clone();
+        attributes.extend(self.rest.iter().filter_map(|(key, value)| {
+            key.strip_prefix("attributes[")
+                .and_then(|key| key.strip_suffix(']'))
+                .map(|key| (key.to_owned(), value.clone()))
+        }));
+        attributes
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 #[allow(dead_code)]
 pub struct SubscriptionConfirmQuery {
-    pub token: String,
+    pub token: Option<String>,
+    pub list: Option<String>,
 }
 
-#[derive(Debug, Default, strum::Display, strum::EnumString)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(ascii_case_insensitive)]
 pub enum SubscriptionStatus {
     #[default]
     Pending,
     Confirmed,
+    Unsubscribed,
+    /// Hard-bounced, the address is suppressed.
+    Bounced,
+    /// Marked an email as spam, the address is suppressed.
+    Complained,
+    /// Soft-bounced too often under the bounce policy, the address isn't suppressed.
+    Deactivated,
 }
 
 #[derive(Debug)]
@@ -85,91 +136,274 @@ pub struct SubscribeState {
     pub pool: PgPool,
     pub email_client: Arc<EmailClient>,
     pub subscribe_confirm_endpoint: reqwest::Url,
+    pub domain_checker: Option<DomainChecker>,
+    pub bot_protection: BotProtection,
+    pub confirmation_throttle: ConfirmationThrottle,
+    pub trust_x_forwarded_for: bool,
+    pub lists: Arc<HashMap<String, ListConfig>>,
+    pub token_ttl: Duration,
 }
 
-/// Content-Type: application/x-www-form-urlencoded
-#[instrument(
-    skip(state, form),
-    fields(
-        email = form.email.as_ref(),
-        name = form.name.as_ref()
+impl FromRef<SubscribeState> for PgPool {
+    fn from_ref(state: &SubscribeState) -> Self {
+        state.pool.clone()
+    }
+}
+
+fn is_expired(created_at: DateTime<Utc>, ttl: Duration) -> bool {
+    chrono::Duration::from_std(ttl)
+        .map(|ttl| created_at + ttl < Utc::now())
+        .unwrap_or(false)
+}
+
+/// Sends the confirmation email in the subscriber's locale, customized for `list` if it is.
+pub(crate) async fn email_subscription_confirmation(
+    state: &SubscribeState,
+    subscriber_id: Uuid,
+    mut subscription_url: reqwest::Url,
+    subscription_token: &str,
+    list: Option<&str>,
+) -> Result<()> {
+    {
+        let mut query = subscription_url.query_pairs_mut();
+        query.append_pair("token", subscription_token);
+        if let Some(list) = list {
+            query.append_pair("list", list);
+        }
+    }
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT email, name, locale FROM subscribers
+        WHERE id = $1
+        "#,
+        subscriber_id
     )
-)]
-pub async fn subscribe(
-    State(state): State<SubscribeState>,
-    Form(form): Form<SubscriptionForm>,
-) -> ServerResult<impl IntoResponse> {
-    async fn email_subscription_confirmation(
-        email_client: Arc<EmailClient>,
-        to: &EmailAdderess,
-        mut subscription_url: reqwest::Url,
-        subscription_token: &str,
-    ) -> Result<()> {
-        subscription_url.set_query(Some(&format!("token={}", subscription_token)));
-        email_client
-            .send_email(
-                to,
-                "Newsletter subscription confirmation",
-                &format!(
-                    "Open the link to confirm your newsletter subscription. {subscription_url}",
-                ),
-                &format!(
-                    "
-                <p>
-                    Open the link to confirm your newsletter subscription.<br />
-                    <a href='{0}'>{0}</a>
-                </p>",
-                    subscription_url
-                ),
-            )
-            .await
-            .context("Failed to send a confirmation email, please try again later.")?;
+    .fetch_one(&state.pool)
+    .await?;
+    let to = EmailAdderess::new(subscriber.email)?;
+    let locale = subscriber
+        .locale
+        .and_then(|locale| locale.parse::<Locale>().ok());
 
-        info!(%subscription_url, "Sent a confirmation email");
+    let sent = send_transactional(
+        &state.pool,
+        &state.email_client,
+        TransactionalKind::Confirmation,
+        TransactionalRecipient {
+            email: &to,
+            name: &subscriber.name,
+            locale: locale.as_ref(),
+            list: list
+                .and_then(|slug| state.lists.get_key_value(slug))
+                .map(|(slug, list)| (slug.as_str(), list)),
+        },
+        json!({ "confirmation_url": subscription_url.as_str() }),
+    )
+    .await;
+    // Answered as if it was sent, so that the form doesn't tell who is suppressed
+    if let Err(err) = &sent {
+        if err.is::<Suppressed>() {
+            info!(%err, "Skipped a confirmation email");
+            return Ok(());
+        }
+    }
+    sent.context("Failed to send a confirmation email, please try again later.")?;
+
+    info!(%subscription_url, "Sent a confirmation email");
+
+    Ok(())
+}
 
-        Ok(())
+/// Swaps whatever tokens the subscriber had for a single fresh one, so old links stop working.
+pub(crate) async fn replace_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> ServerResult<String> {
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = $1
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let subscription_token = gen_subscription_token(SUBSCRIPTION_TOKEN_LEN);
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id)
+        VALUES ($1, $2)
+        "#,
+        subscription_token,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(subscription_token)
+}
+
+#[derive(Debug, Clone, Copy, strum::Display, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SubscribeOutcome {
+    AlreadyConfirmed,
+    ConfirmationResent,
+    Created,
+    /// Was unsubscribed, and has to confirm again.
+    Resubscribed,
+}
+
+async fn join_list<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    subscriber_id: Uuid,
+    list: &str,
+) -> ServerResult<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO list_subscriptions (list, subscriber_id, subscribed_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        list,
+        subscriber_id,
+        Utc::now()
+    )
+    .execute(executor)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(())
+}
+
+/// Shared by the form and the JSON endpoints.
+async fn add_subscriber(
+    state: &SubscribeState,
+    form: &SubscriptionForm,
+    client_ip: IpAddr,
+    source: &ConsentSource,
+) -> ServerResult<SubscribeOutcome> {
+    state
+        .bot_protection
+        .check(&form.bot_protection, Some(client_ip))
+        .await?;
+
+    let list = form.list.as_deref().map(str::to_lowercase);
+    if let Some(list) = &list {
+        if !state.lists.contains_key(list) {
+            return Err(SubscribeError::UnknownList(list.clone()).into());
+        }
     }
 
-    match sqlx::query!(
+    let existing = sqlx::query!(
         r#"
-        SELECT status FROM subscribers
-        WHERE email = $1
+        SELECT id, status FROM subscribers
+        WHERE lower(email) = lower($1)
         "#,
         form.email.as_ref()
     )
     .fetch_optional(&state.pool)
     .await
     .map_err(ServerError::unexpected)?
-    .map(|obj| SubscriptionStatus::from_str(&obj.status).expect("Stored value must be valid"))
-    {
-        Some(SubscriptionStatus::Confirmed) => {
+    .map(|obj| {
+        (
+            obj.id,
+            SubscriptionStatus::from_str(&obj.status).expect("Stored value must be valid"),
+        )
+    });
+
+    let outcome = match existing {
+        Some((uuid, SubscriptionStatus::Confirmed)) => {
+            if let Some(list) = &list {
+                join_list(&state.pool, uuid, list).await?;
+            }
             info!("Already subscribed and confirmed");
-            Ok((
-                StatusCode::OK,
-                format!(
-                    "{} is already subscribed and confirmed",
-                    form.email.as_ref()
-                ),
-            ))
+            record_consent(
+                &state.pool,
+                &[uuid],
+                ConsentKind::Signup,
+                list.as_deref(),
+                source,
+                json!({ "outcome": SubscribeOutcome::AlreadyConfirmed }),
+            )
+            .await?;
+            SubscribeOutcome::AlreadyConfirmed
         }
-        // Send the confirmation email again
-        Some(SubscriptionStatus::Pending) => {
-            let uuid = sqlx::query!(
+        // Start over with a fresh token, they have to confirm again. Suppressed addresses
+        // don't get the confirmation email though.
+        Some((
+            uuid,
+            SubscriptionStatus::Unsubscribed
+            | SubscriptionStatus::Bounced
+            | SubscriptionStatus::Complained
+            | SubscriptionStatus::Deactivated,
+        )) => {
+            state
+                .confirmation_throttle
+                .acquire(&state.pool, &form.email, client_ip)
+                .await?;
+
+            let mut transaction = state.pool.begin().await.map_err(ServerError::unexpected)?;
+
+            sqlx::query!(
                 r#"
-                SELECT id FROM subscribers
-                WHERE email = $1
+                UPDATE subscribers
+                SET status = $1
+                WHERE id = $2
                 "#,
-                form.email.as_ref(),
+                SubscriptionStatus::Pending.to_string(),
+                uuid
             )
-            .fetch_optional(&state.pool)
+            .execute(&mut *transaction)
             .await
-            .map_err(ServerError::unexpected)?
-            .map(|obj| obj.id)
-            .expect("Subscriber must exist if we're in the Status::Pending branch");
+            .map_err(ServerError::unexpected)?;
+
+            let subscription_token = replace_subscription_token(&mut transaction, uuid).await?;
+
+            if let Some(list) = &list {
+                join_list(&mut *transaction, uuid, list).await?;
+            }
+
+            record_consent(
+                &mut *transaction,
+                &[uuid],
+                ConsentKind::Signup,
+                list.as_deref(),
+                source,
+                json!({ "outcome": SubscribeOutcome::Resubscribed }),
+            )
+            .await?;
+
+            transaction
+                .commit()
+                .await
+                .map_err(ServerError::unexpected)?;
+
+            info!(?uuid, "Unsubscribed subscriber signed up again");
+
+            email_subscription_confirmation(
+                state,
+                uuid,
+                state.subscribe_confirm_endpoint.clone(),
+                &subscription_token,
+                list.as_deref(),
+            )
+            .await?;
+
+            SubscribeOutcome::Resubscribed
+        }
+        // Send the confirmation email again
+        Some((uuid, SubscriptionStatus::Pending)) => {
+            if let Some(list) = &list {
+                join_list(&state.pool, uuid, list).await?;
+            }
 
-            let subscription_token = sqlx::query!(
+            let token = sqlx::query!(
                 r#"
-                SELECT subscription_token FROM subscription_tokens
+                SELECT subscription_token, created_at FROM subscription_tokens
                 WHERE subscriber_id = $1
                 "#,
                 uuid,
@@ -177,37 +411,88 @@ pub async fn subscribe(
             .fetch_optional(&state.pool)
             .await
             .map_err(ServerError::unexpected)?
-            .map(|obj| obj.subscription_token)
             .expect("Subscription token must exist if we're in the Status::Pending branch");
 
+            state
+                .confirmation_throttle
+                .acquire(&state.pool, &form.email, client_ip)
+                .await?;
+
+            let subscription_token = if is_expired(token.created_at, state.token_ttl) {
+                let subscription_token = gen_subscription_token(SUBSCRIPTION_TOKEN_LEN);
+                sqlx::query!(
+                    r#"
+                    UPDATE subscription_tokens
+                    SET subscription_token = $1, created_at = $2
+                    WHERE subscription_token = $3
+                    "#,
+                    subscription_token,
+                    Utc::now(),
+                    token.subscription_token
+                )
+                .execute(&state.pool)
+                .await
+                .map_err(ServerError::unexpected)?;
+
+                info!(?uuid, "Rotated expired subscription token");
+                subscription_token
+            } else {
+                token.subscription_token
+            };
+
+            record_consent(
+                &state.pool,
+                &[uuid],
+                ConsentKind::Signup,
+                list.as_deref(),
+                source,
+                json!({ "outcome": SubscribeOutcome::ConfirmationResent }),
+            )
+            .await?;
+
             email_subscription_confirmation(
-                state.email_client,
-                &form.email,
+                state,
+                uuid,
                 state.subscribe_confirm_endpoint.clone(),
                 &subscription_token,
+                list.as_deref(),
             )
             .await?;
 
-            Ok((
-                StatusCode::OK,
-                "A confirmation email has been sent again.".into(),
-            ))
+            SubscribeOutcome::ConfirmationResent
         }
         // Add subscriber
         None => {
+            let attributes = AttributeSchema::load(&state.pool)
+                .await
+                .map_err(ServerError::Unexpected)?
+                .validate(&form.attributes(), true)
+                .map_err(SubscribeError::from)?;
+
+            if let Some(domain_checker) = &state.domain_checker {
+                domain_checker.check(&form.email).await?;
+            }
+
+            state
+                .confirmation_throttle
+                .acquire(&state.pool, &form.email, client_ip)
+                .await?;
+
             let mut transaction = state.pool.begin().await.map_err(ServerError::unexpected)?;
 
             let uuid = Uuid::new_v4();
             sqlx::query!(
                 r#"
-                INSERT INTO subscribers (id, email, name, status, subscribed_at)
-                VALUES ($1, $2, $3, $4, $5)
+                INSERT INTO subscribers (id, email, name, status, subscribed_at, attributes, locale)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
                 "#,
                 uuid,
                 form.email.as_ref(),
                 form.name.as_ref(),
                 SubscriptionStatus::default().to_string(),
-                Utc::now()
+                Utc::now(),
+                serde_json::Value::Object(attributes),
+                form.locale.as_ref().map(AsRef::as_ref)
             )
             .execute(&mut *transaction)
             .await
@@ -226,6 +511,20 @@ pub async fn subscribe(
             .await
             .map_err(ServerError::unexpected)?;
 
+            if let Some(list) = &list {
+                join_list(&mut *transaction, uuid, list).await?;
+            }
+
+            record_consent(
+                &mut *transaction,
+                &[uuid],
+                ConsentKind::Signup,
+                list.as_deref(),
+                source,
+                json!({ "outcome": SubscribeOutcome::Created }),
+            )
+            .await?;
+
             transaction
                 .commit()
                 .await
@@ -237,53 +536,365 @@ pub async fn subscribe(
             );
 
             email_subscription_confirmation(
-                state.email_client,
-                &form.email,
+                state,
+                uuid,
                 state.subscribe_confirm_endpoint.clone(),
                 &subscription_token,
+                list.as_deref(),
             )
             .await?;
 
-            Ok((StatusCode::OK, "A confirmation email has been sent.".into()))
+            SubscribeOutcome::Created
         }
-    }
+    };
+
+    Ok(outcome)
 }
 
+/// Content-Type: application/x-www-form-urlencoded
 #[instrument(
-    skip(pool, query),
-    fields(token = query.token)
+    skip(state, headers, form),
+    fields(
+        email = form.email.as_ref(),
+        name = form.name.as_ref(),
+        %client_addr
+    )
 )]
-pub async fn subscribe_confirm(
-    State(pool): State<PgPool>,
-    Query(query): Query<SubscriptionConfirmQuery>,
+pub async fn subscribe(
+    State(state): State<SubscribeState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<SubscriptionForm>,
 ) -> ServerResult<impl IntoResponse> {
-    let uuid = sqlx::query!(
+    let client_ip = helpers::client_ip(&headers, client_addr, state.trust_x_forwarded_for);
+    let source =
+        ConsentSource::from_request("form", &headers, client_addr, state.trust_x_forwarded_for);
+
+    let body = match add_subscriber(&state, &form, client_ip, &source).await? {
+        SubscribeOutcome::AlreadyConfirmed => format!(
+            "{} is already subscribed and confirmed",
+            form.email.as_ref()
+        ),
+        SubscribeOutcome::ConfirmationResent => "A confirmation email has been sent again.".into(),
+        SubscribeOutcome::Created | SubscribeOutcome::Resubscribed => {
+            "A confirmation email has been sent.".into()
+        }
+    };
+
+    Ok((StatusCode::OK, body))
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscribeResponse {
+    pub email: String,
+    pub outcome: SubscribeOutcome,
+}
+
+/// Content-Type: application/json
+#[instrument(skip_all, fields(%client_addr))]
+pub async fn api_subscribe(
+    State(state): State<SubscribeState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Result<Json<SubscriptionForm>, JsonRejection>,
+) -> ApiResult<impl IntoResponse> {
+    let Json(form) = body?;
+    let client_ip = helpers::client_ip(&headers, client_addr, state.trust_x_forwarded_for);
+    let source =
+        ConsentSource::from_request("api", &headers, client_addr, state.trust_x_forwarded_for);
+
+    let outcome = add_subscriber(&state, &form, client_ip, &source).await?;
+    let status = match outcome {
+        SubscribeOutcome::Created | SubscribeOutcome::Resubscribed => StatusCode::CREATED,
+        _ => StatusCode::OK,
+    };
+
+    Ok((
+        status,
+        Json(SubscribeResponse {
+            email: form.email.as_ref().into(),
+            outcome,
+        }),
+    ))
+}
+
+/// Hands out a signed token to be embedded into the subscribe form as `form_token`.
+pub async fn subscribe_form_token(State(state): State<SubscribeState>) -> Response {
+    match &state.bot_protection.form_token {
+        Some(signer) => (StatusCode::OK, signer.issue()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmOutcome {
+    Confirmed,
+    AlreadyConfirmed,
+}
+
+async fn confirm_subscription(
+    pool: &PgPool,
+    token: Option<&str>,
+    token_ttl: Duration,
+    list: Option<&str>,
+    source: &ConsentSource,
+) -> ServerResult<(Uuid, ConfirmOutcome)> {
+    let token = sqlx::query!(
         r#"
-        SELECT subscriber_id FROM subscription_tokens
+        SELECT subscriber_id, created_at, status FROM subscription_tokens
+        JOIN subscribers ON subscribers.id = subscription_tokens.subscriber_id
         WHERE subscription_token = $1
         "#,
-        query.token
+        token.ok_or(SubscribeError::InvalidToken)?
     )
-    .fetch_optional(&pool)
+    .fetch_optional(pool)
     .await
     .map_err(ServerError::unexpected)?
-    .map(|obj| obj.subscriber_id)
-    .context("No such subscription token found.")?;
+    .ok_or(SubscribeError::InvalidToken)?;
+    let uuid = token.subscriber_id;
 
-    sqlx::query!(
+    match SubscriptionStatus::from_str(&token.status).expect("Stored value must be valid") {
+        SubscriptionStatus::Confirmed => {
+            info!(?uuid, "Subscription was already confirmed");
+            return Ok((uuid, ConfirmOutcome::AlreadyConfirmed));
+        }
+        SubscriptionStatus::Pending if is_expired(token.created_at, token_ttl) => {
+            info!(?uuid, "Subscription token has expired");
+            return Err(SubscribeError::ExpiredToken.into());
+        }
+        SubscriptionStatus::Pending => {}
+        // The token belongs to a subscription that has since ended
+        SubscriptionStatus::Unsubscribed
+        | SubscriptionStatus::Bounced
+        | SubscriptionStatus::Complained
+        | SubscriptionStatus::Deactivated => return Err(SubscribeError::InvalidToken.into()),
+    }
+
+    let confirmed = sqlx::query!(
         r#"
         UPDATE subscribers
         SET status = $1
-        WHERE id = $2
+        WHERE id = $2 AND status = $3
         "#,
         SubscriptionStatus::Confirmed.to_string(),
-        uuid
+        uuid,
+        SubscriptionStatus::Pending.to_string()
     )
-    .execute(&pool)
+    .execute(pool)
     .await
-    .map_err(ServerError::unexpected)?;
+    .map_err(ServerError::unexpected)?
+    .rows_affected()
+        > 0;
+    // The status changed since it was read, by another click on the link or a bounce
+    if !confirmed {
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT status FROM subscribers
+            WHERE id = $1
+            "#,
+            uuid
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(ServerError::unexpected)?;
+        return match status.as_deref().map(SubscriptionStatus::from_str) {
+            Some(Ok(SubscriptionStatus::Confirmed)) => {
+                info!(?uuid, "Subscription was already confirmed");
+                Ok((uuid, ConfirmOutcome::AlreadyConfirmed))
+            }
+            _ => Err(SubscribeError::InvalidToken.into()),
+        };
+    }
+
+    record_consent(
+        pool,
+        &[uuid],
+        ConsentKind::Confirmation,
+        list,
+        source,
+        json!({}),
+    )
+    .await?;
 
     info!(?uuid, "Subscription confirmed");
 
-    Ok((StatusCode::OK, "Subscription Confirmed!"))
+    Ok((uuid, ConfirmOutcome::Confirmed))
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfirmState {
+    pub pool: PgPool,
+    pub email_client: Arc<EmailClient>,
+    pub confirmation: Arc<ConfirmationConfig>,
+    pub lists: Arc<HashMap<String, ListConfig>>,
+    pub trust_x_forwarded_for: bool,
+    pub links: SubscriberLinks,
+    pub transactional_emails: TransactionalEmailsConfig,
+}
+
+/// Sends `kind` to the subscriber. Whatever it's about already happened, so failures are only
+/// logged.
+pub(crate) async fn notify_subscriber(
+    state: &ConfirmState,
+    kind: TransactionalKind,
+    subscriber_id: Uuid,
+    list: Option<&str>,
+) {
+    let sent = async {
+        let subscriber = sqlx::query!(
+            r#"
+            SELECT email, name, locale, unsubscribe_token FROM subscribers
+            WHERE id = $1
+            "#,
+            subscriber_id
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let email = EmailAdderess::new(subscriber.email)?;
+        let locale = subscriber
+            .locale
+            .and_then(|locale| locale.parse::<Locale>().ok());
+        let recipient = Recipient {
+            unsubscribe_token: Some(subscriber.unsubscribe_token),
+            ..Recipient::unknown(email.as_ref().to_owned())
+        };
+
+        send_transactional(
+            &state.pool,
+            &state.email_client,
+            kind,
+            TransactionalRecipient {
+                email: &email,
+                name: &subscriber.name,
+                locale: locale.as_ref(),
+                list: list
+                    .and_then(|slug| state.lists.get_key_value(slug))
+                    .map(|(slug, list)| (slug.as_str(), list)),
+            },
+            json!({
+                "unsubscribe_url": state.links.unsubscribe_url(&recipient).as_str(),
+                "preferences_url": state.links.preferences_url(&recipient).as_str(),
+            }),
+        )
+        .await
+    }
+    .await;
+
+    match sent {
+        Ok(()) => info!(?subscriber_id, %kind, "Sent a transactional email"),
+        Err(err) if err.is::<Suppressed>() => info!(%err, %kind, "Skipped a transactional email"),
+        Err(err) => warn!(?subscriber_id, %kind, ?err, "Failed to send a transactional email"),
+    }
+}
+
+async fn send_welcome(state: &ConfirmState, subscriber_id: Uuid, list: Option<&str>) {
+    if state.transactional_emails.welcome {
+        let list = list.map(str::to_lowercase);
+        notify_subscriber(
+            state,
+            TransactionalKind::Welcome,
+            subscriber_id,
+            list.as_deref(),
+        )
+        .await;
+    }
+}
+
+/// Either redirects to the configured success/failure URL, or renders one of the built-in pages.
+#[instrument(
+    skip(state, headers, query),
+    fields(token = query.token, list = query.list, %client_addr)
+)]
+pub async fn subscribe_confirm(
+    State(state): State<ConfirmState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<SubscriptionConfirmQuery>,
+) -> ServerResult<Response> {
+    let source =
+        ConsentSource::from_request("link", &headers, client_addr, state.trust_x_forwarded_for);
+    let page = match confirm_subscription(
+        &state.pool,
+        query.token.as_deref(),
+        state.confirmation.token_ttl_ms,
+        query.list.as_deref(),
+        &source,
+    )
+    .await
+    {
+        Ok((uuid, ConfirmOutcome::Confirmed)) => {
+            send_welcome(&state, uuid, query.list.as_deref()).await;
+            ConfirmationPage::Confirmed
+        }
+        Ok((_, ConfirmOutcome::AlreadyConfirmed)) => ConfirmationPage::AlreadyConfirmed,
+        Err(ServerError::Subscribe(SubscribeError::InvalidToken)) => ConfirmationPage::InvalidToken,
+        Err(ServerError::Subscribe(SubscribeError::ExpiredToken)) => ConfirmationPage::ExpiredToken,
+        Err(err) => return Err(err),
+    };
+
+    let list = query
+        .list
+        .as_deref()
+        .and_then(|slug| state.lists.get(&slug.to_lowercase()));
+    let redirect_url = if page.is_success() {
+        list.and_then(|list| list.success_redirect_url.as_ref())
+            .or(state.confirmation.success_redirect_url.as_ref())
+    } else {
+        list.and_then(|list| list.failure_redirect_url.as_ref())
+            .or(state.confirmation.failure_redirect_url.as_ref())
+    };
+
+    Ok(match redirect_url {
+        Some(url) => {
+            let mut url = url.0.clone();
+            url.query_pairs_mut()
+                .append_pair("status", &page.to_string());
+            Redirect::to(url.as_str()).into_response()
+        }
+        None => (
+            page.status(),
+            render_confirmation_page(
+                &state.confirmation.theme,
+                page,
+                list.map(|list| list.name.as_str()),
+            ),
+        )
+            .into_response(),
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ConfirmResponse {
+    pub subscriber_id: Uuid,
+    pub outcome: ConfirmOutcome,
+}
+
+/// Content-Type: application/json
+#[instrument(skip_all, fields(%client_addr))]
+pub async fn api_subscribe_confirm(
+    State(state): State<ConfirmState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Result<Json<SubscriptionConfirmQuery>, JsonRejection>,
+) -> ApiResult<Json<ConfirmResponse>> {
+    let Json(query) = body?;
+    let source =
+        ConsentSource::from_request("api", &headers, client_addr, state.trust_x_forwarded_for);
+    let (subscriber_id, outcome) = confirm_subscription(
+        &state.pool,
+        query.token.as_deref(),
+        state.confirmation.token_ttl_ms,
+        query.list.as_deref(),
+        &source,
+    )
+    .await?;
+    if let ConfirmOutcome::Confirmed = outcome {
+        send_welcome(&state, subscriber_id, query.list.as_deref()).await;
+    }
+
+    Ok(Json(ConfirmResponse {
+        subscriber_id,
+        outcome,
+    }))
 }
@@ -0,0 +1,331 @@
+//! Admin API for browsing and managing subscribers.
+
+use crate::{
+    api::ApiResult,
+    auth::AuthUser,
+    consent::{record_consent, ConsentKind, ConsentSource},
+    gdpr::{subscriber_record, SubscriberRecord},
+    subscribe::{
+        email_subscription_confirmation, replace_subscription_token, SubscribeState,
+        SubscriptionStatus,
+    },
+    ServerError,
+};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::PgPool;
+use std::str::FromStr;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscribersQuery {
+    /// Matches anywhere in the email or the name, case-insensitively.
+    pub q: Option<String>,
+    pub status: Option<String>,
+    /// `next` of the previous page.
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriberSummary {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscribersPage {
+    pub subscribers: Vec<SubscriberSummary>,
+    /// Pass as `after` to get the next page, missing on the last one.
+    pub next: Option<String>,
+}
+
+fn parse_status(status: &str) -> Result<SubscriptionStatus, ServerError> {
+    SubscriptionStatus::from_str(status)
+        .map_err(|_| ServerError::BadRequest(format!("Unknown status \"{status}\"")))
+}
+
+/// Ordered by email, which is also the cursor.
+pub async fn list_subscribers(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<SubscribersQuery>, QueryRejection>,
+) -> ApiResult<Json<SubscribersPage>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let status = query.status.as_deref().map(parse_status).transpose()?;
+    let search = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(|q| {
+            format!(
+                "%{}%",
+                q.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            )
+        });
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let subscribers = sqlx::query_as!(
+        SubscriberSummary,
+        r#"
+        SELECT id, email, name, status, subscribed_at FROM subscribers
+        WHERE ($1::text IS NULL OR email ILIKE $1 OR name ILIKE $1)
+            AND ($2::text IS NULL OR status = $2)
+            AND ($3::text IS NULL OR email > $3)
+        ORDER BY email
+        LIMIT $4
+        "#,
+        search,
+        status.map(|status| status.to_string()),
+        query.after,
+        limit
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let next = (subscribers.len() as i64 == limit)
+        .then(|| {
+            subscribers
+                .last()
+                .map(|subscriber| subscriber.email.clone())
+        })
+        .flatten();
+
+    Ok(Json(SubscribersPage { subscribers, next }))
+}
+
+/// The subscriber with their tokens, lists, tags and the history of events and consent.
+pub async fn get_subscriber(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<SubscriberRecord>> {
+    subscriber_record(&pool, id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {id}")).into())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct StatusChange {
+    pub status: String,
+    /// Kept in the consent log, e.g. where the consent was collected.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StatusChangeResponse {
+    pub id: Uuid,
+    pub status: String,
+    pub previous_status: String,
+}
+
+/// Sets the status by hand, which is recorded as consent given or withdrawn by the admin.
+/// Moving a subscriber back to `Pending` gives them a fresh confirmation token, but doesn't send it.
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn change_subscriber_status(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    body: Result<Json<StatusChange>, JsonRejection>,
+) -> ApiResult<Json<StatusChangeResponse>> {
+    let Json(body) = body?;
+    let status = parse_status(&body.status)?;
+
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let previous_status = sqlx::query_scalar!(
+        r#"
+        SELECT status FROM subscribers
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {id}")))?;
+
+    if previous_status != status.to_string() {
+        sqlx::query!(
+            r#"
+            UPDATE subscribers
+            SET status = $1
+            WHERE id = $2
+            "#,
+            status.to_string(),
+            id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(ServerError::unexpected)?;
+
+        let kind = match status {
+            SubscriptionStatus::Confirmed => ConsentKind::Confirmation,
+            SubscriptionStatus::Pending => {
+                replace_subscription_token(&mut transaction, id).await?;
+                ConsentKind::Unsubscribe
+            }
+            SubscriptionStatus::Unsubscribed
+            | SubscriptionStatus::Bounced
+            | SubscriptionStatus::Complained
+            | SubscriptionStatus::Deactivated => ConsentKind::Unsubscribe,
+        };
+        record_consent(
+            &mut *transaction,
+            &[id],
+            kind,
+            None,
+            &ConsentSource::channel("admin"),
+            json!({
+                "from": previous_status,
+                "to": status.to_string(),
+                "changed_by": user.username,
+                "reason": body.reason,
+            }),
+        )
+        .await?;
+
+        info!(?id, from = previous_status, to = %status, "Changed subscriber status");
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    Ok(Json(StatusChangeResponse {
+        id,
+        status: status.to_string(),
+        previous_status,
+    }))
+}
+
+/// Deletes the subscriber and everything attached to them. Unlike an erasure, the address can
+/// sign up or be imported again.
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn delete_subscriber(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<StatusCode> {
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE subscriber_id = $1
+        "#,
+        id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM subscribers
+        WHERE id = $1
+        "#,
+        id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(ServerError::NotFound(format!("No such subscriber {id}")).into());
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(?id, "Deleted subscriber");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ResendQuery {
+    /// Passed along in the confirmation link, like the subscribe form does.
+    pub list: Option<String>,
+}
+
+/// Sends a confirmation email with a fresh token to a pending subscriber, without going through
+/// the confirmation throttle.
+#[instrument(skip(user, state), fields(username = user.username))]
+pub async fn resend_confirmation(
+    user: AuthUser,
+    State(state): State<SubscribeState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ResendQuery>,
+) -> ApiResult<StatusCode> {
+    let list = query.list.as_deref().map(str::to_lowercase);
+    if let Some(list) = &list {
+        if !state.lists.contains_key(list) {
+            return Err(ServerError::BadRequest(format!("Unknown list \"{list}\"")).into());
+        }
+    }
+
+    let mut transaction = state.pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT status FROM subscribers
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .ok_or_else(|| ServerError::NotFound(format!("No such subscriber {id}")))?;
+    if subscriber.status != SubscriptionStatus::Pending.to_string() {
+        return Err(ServerError::Conflict(format!(
+            "Only pending subscribers can be sent a confirmation, this one is {}",
+            subscriber.status
+        ))
+        .into());
+    }
+
+    let subscription_token = replace_subscription_token(&mut transaction, id).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    email_subscription_confirmation(
+        &state,
+        id,
+        state.subscribe_confirm_endpoint.clone(),
+        &subscription_token,
+        list.as_deref(),
+    )
+    .await
+    .map_err(ServerError::Unexpected)?;
+
+    info!(?id, "Resent confirmation email");
+
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,255 @@
+//! Addresses that must never be mailed again.
+//!
+//! Entries are keyed by the same hash as erasures, so that an erased address can stay suppressed
+//! without keeping the address itself.
+
+use crate::{
+    api::ApiResult, auth::AuthUser, email::EmailAdderess, gdpr::email_hash, ServerError,
+    ServerResult,
+};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::str::FromStr;
+use tracing::{info, instrument};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SuppressionReason {
+    HardBounce,
+    Complaint,
+    Erased,
+    /// Added by an admin.
+    Manual,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Suppression {
+    pub email_hash: String,
+    /// Missing for erased addresses.
+    pub email: Option<String>,
+    pub reason: String,
+    /// What added the entry, e.g. `admin`, `erasure` or a webhook.
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Checked by [`EmailClient`](crate::email::EmailClient) before every send.
+#[derive(Debug, Clone)]
+pub struct SuppressionList {
+    pub pool: PgPool,
+}
+
+impl SuppressionList {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn find(&self, email: &str) -> sqlx::Result<Option<Suppression>> {
+        sqlx::query_as!(
+            Suppression,
+            r#"
+            SELECT email_hash, email, reason, source, created_at FROM suppressions
+            WHERE email_hash = $1
+            "#,
+            email_hash(email)
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
+
+/// Adds `email` to the suppression list, keeping the first entry if it's already there.
+/// Returns whether it was added.
+pub async fn suppress<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    email: Option<&str>,
+    email_hash: &str,
+    reason: SuppressionReason,
+    source: &str,
+) -> ServerResult<bool> {
+    let added = sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email_hash, email, reason, source, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (email_hash) DO NOTHING
+        "#,
+        email_hash,
+        email.map(|email| email.trim().to_lowercase()),
+        reason.to_string(),
+        source,
+        Utc::now()
+    )
+    .execute(executor)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected()
+        > 0;
+
+    if added {
+        info!(email_hash, %reason, source, "Suppressed address");
+    }
+
+    Ok(added)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SuppressionsQuery {
+    pub reason: Option<String>,
+    /// `next` of the previous page.
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SuppressionsPage {
+    pub suppressions: Vec<Suppression>,
+    /// Pass as `after` to get the next page, missing on the last one.
+    pub next: Option<String>,
+}
+
+pub async fn list_suppressions(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    query: Result<Query<SuppressionsQuery>, QueryRejection>,
+) -> ApiResult<Json<SuppressionsPage>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let reason = query
+        .reason
+        .as_deref()
+        .map(|reason| {
+            SuppressionReason::from_str(reason)
+                .map_err(|_| ServerError::BadRequest(format!("Unknown reason \"{reason}\"")))
+        })
+        .transpose()?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let suppressions = sqlx::query_as!(
+        Suppression,
+        r#"
+        SELECT email_hash, email, reason, source, created_at FROM suppressions
+        WHERE ($1::text IS NULL OR reason = $1)
+            AND ($2::text IS NULL OR email_hash > $2)
+        ORDER BY email_hash
+        LIMIT $3
+        "#,
+        reason.map(|reason| reason.to_string()),
+        query.after,
+        limit
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let next = (suppressions.len() as i64 == limit)
+        .then(|| suppressions.last().map(|entry| entry.email_hash.clone()))
+        .flatten();
+
+    Ok(Json(SuppressionsPage { suppressions, next }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SuppressionBody {
+    pub email: EmailAdderess,
+    #[serde(default = "default_reason")]
+    pub reason: SuppressionReason,
+}
+
+fn default_reason() -> SuppressionReason {
+    SuppressionReason::Manual
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn add_suppression(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    body: Result<Json<SuppressionBody>, JsonRejection>,
+) -> ApiResult<(StatusCode, Json<Suppression>)> {
+    let Json(body) = body?;
+    let hash = email_hash(body.email.as_ref());
+    // Like `suppress`, but returns the entry in the same statement, the no-op update is there
+    // so that an existing entry is returned as well
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email_hash, email, reason, source, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (email_hash) DO UPDATE SET email_hash = EXCLUDED.email_hash
+        RETURNING email_hash, email, reason, source, created_at, (xmax = 0) AS "added!"
+        "#,
+        hash,
+        body.email.as_ref().trim().to_lowercase(),
+        body.reason.to_string(),
+        "admin",
+        Utc::now()
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let status = if row.added {
+        info!(email_hash = hash, reason = %body.reason, source = "admin", "Suppressed address");
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    let suppression = Suppression {
+        email_hash: row.email_hash,
+        email: row.email,
+        reason: row.reason,
+        source: row.source,
+        created_at: row.created_at,
+    };
+
+    Ok((status, Json(suppression)))
+}
+
+/// Lets the address be mailed again. Takes the address, or the hash for erased addresses.
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn remove_suppression(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(email_or_hash): Path<String>,
+) -> ApiResult<StatusCode> {
+    let hash = if email_or_hash.contains('@') {
+        email_hash(&email_or_hash)
+    } else {
+        email_or_hash.to_lowercase()
+    };
+
+    let removed = sqlx::query!(
+        r#"
+        DELETE FROM suppressions
+        WHERE email_hash = $1
+        "#,
+        hash
+    )
+    .execute(&pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+    if removed == 0 {
+        return Err(ServerError::NotFound(format!("{email_or_hash} is not suppressed")).into());
+    }
+
+    info!(email_hash = hash, "Removed suppression");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,403 @@
+use crate::{api::ApiResult, auth::AuthUser, ServerError, ServerResult};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, strum::Display, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TagAction {
+    Added,
+    Removed,
+}
+
+/// Tags are lowercased, and limited to `a-z`, `0-9`, `-` and `_` so that they stay usable in filters.
+pub fn normalize_tag(tag: &str) -> ServerResult<String> {
+    let tag = tag.trim().to_lowercase();
+    let is_valid = !tag.is_empty()
+        && tag.len() <= 64
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if is_valid {
+        Ok(tag)
+    } else {
+        Err(ServerError::BadRequest(
+            "Tags must be 1 to 64 characters of a-z, 0-9, '-' and '_'".into(),
+        ))
+    }
+}
+
+/// Adds or removes `tag` for the given subscribers, logging a tag event for every actual change.
+/// Returns the ids of the subscribers that changed.
+pub async fn apply_tag(
+    transaction: &mut Transaction<'_, Postgres>,
+    tag: &str,
+    action: TagAction,
+    subscriber_ids: &[Uuid],
+    actor: Option<&str>,
+) -> ServerResult<Vec<Uuid>> {
+    let now = Utc::now();
+    let changed = match action {
+        TagAction::Added => sqlx::query_scalar!(
+            r#"
+            INSERT INTO subscriber_tags (subscriber_id, tag, tagged_at)
+            SELECT id, $1, $2 FROM unnest($3::uuid[]) AS id
+            ON CONFLICT DO NOTHING
+            RETURNING subscriber_id
+            "#,
+            tag,
+            now,
+            subscriber_ids
+        )
+        .fetch_all(&mut **transaction)
+        .await
+        .map_err(ServerError::unexpected)?,
+        TagAction::Removed => sqlx::query_scalar!(
+            r#"
+            DELETE FROM subscriber_tags
+            WHERE tag = $1 AND subscriber_id = ANY($2)
+            RETURNING subscriber_id
+            "#,
+            tag,
+            subscriber_ids
+        )
+        .fetch_all(&mut **transaction)
+        .await
+        .map_err(ServerError::unexpected)?,
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO tag_events (subscriber_id, tag, action, actor, occurred_at)
+        SELECT id, $1, $2, $3, $4 FROM unnest($5::uuid[]) AS id
+        "#,
+        tag,
+        action.to_string(),
+        actor,
+        now,
+        &changed
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(changed)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TagSummary {
+    pub tag: String,
+    pub subscribers: i64,
+}
+
+pub async fn list_tags(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+) -> ApiResult<Json<Vec<TagSummary>>> {
+    let tags = sqlx::query_as!(
+        TagSummary,
+        r#"
+        SELECT tag, COUNT(*) AS "subscribers!" FROM subscriber_tags
+        GROUP BY tag
+        ORDER BY tag
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    Ok(Json(tags))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TaggedQuery {
+    /// `next` of the previous page.
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TaggedSubscriber {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub tagged_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TaggedPage {
+    pub subscribers: Vec<TaggedSubscriber>,
+    /// Pass as `after` to get the next page, missing on the last one.
+    pub next: Option<String>,
+}
+
+/// Ordered by email, which is also the cursor.
+pub async fn list_tagged_subscribers(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(tag): Path<String>,
+    query: Result<Query<TaggedQuery>, QueryRejection>,
+) -> ApiResult<Json<TaggedPage>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let tag = normalize_tag(&tag)?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let subscribers = sqlx::query_as!(
+        TaggedSubscriber,
+        r#"
+        SELECT subscribers.id, email, name, status, tagged_at FROM subscriber_tags
+        JOIN subscribers ON subscribers.id = subscriber_tags.subscriber_id
+        WHERE tag = $1 AND ($2::text IS NULL OR email > $2)
+        ORDER BY email
+        LIMIT $3
+        "#,
+        tag,
+        query.after,
+        limit
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let next = (subscribers.len() as i64 == limit)
+        .then(|| {
+            subscribers
+                .last()
+                .map(|subscriber| subscriber.email.clone())
+        })
+        .flatten();
+
+    Ok(Json(TaggedPage { subscribers, next }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TagEventsQuery {
+    /// `next` of the previous page.
+    pub after: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TagEvent {
+    pub id: Uuid,
+    pub subscriber_id: Uuid,
+    pub email: String,
+    pub tag: String,
+    pub action: String,
+    pub actor: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TagEventsPage {
+    pub events: Vec<TagEvent>,
+    /// Pass as `after` to get the next page, missing on the last one.
+    pub next: Option<Uuid>,
+}
+
+/// When who entered or left the group, newest first.
+pub async fn list_tag_events(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(tag): Path<String>,
+    query: Result<Query<TagEventsQuery>, QueryRejection>,
+) -> ApiResult<Json<TagEventsPage>> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let tag = normalize_tag(&tag)?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let events = sqlx::query_as!(
+        TagEvent,
+        r#"
+        SELECT tag_events.id, subscriber_id, email, tag, action, actor, occurred_at
+        FROM tag_events
+        JOIN subscribers ON subscribers.id = tag_events.subscriber_id
+        WHERE tag = $1
+            AND (
+                $2::uuid IS NULL
+                OR (occurred_at, tag_events.id)
+                    < (SELECT occurred_at, id FROM tag_events WHERE id = $2)
+            )
+        ORDER BY occurred_at DESC, tag_events.id DESC
+        LIMIT $3
+        "#,
+        tag,
+        query.after,
+        limit
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let next = (events.len() as i64 == limit)
+        .then(|| events.last().map(|event| event.id))
+        .flatten();
+
+    Ok(Json(TagEventsPage { events, next }))
+}
+
+async fn tag_one(
+    user: AuthUser,
+    pool: PgPool,
+    id: Uuid,
+    tag: String,
+    action: TagAction,
+) -> ApiResult<StatusCode> {
+    let tag = normalize_tag(&tag)?;
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let exists = sqlx::query!(
+        r#"
+        SELECT id FROM subscribers
+        WHERE id = $1
+        "#,
+        id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?
+    .is_some();
+    if !exists {
+        return Err(ServerError::NotFound(format!("No such subscriber {id}")).into());
+    }
+
+    let changed = apply_tag(&mut transaction, &tag, action, &[id], Some(&user.username)).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    if !changed.is_empty() {
+        info!(?id, tag, %action, "Subscriber tag changed");
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn add_subscriber_tag(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path((id, tag)): Path<(Uuid, String)>,
+) -> ApiResult<StatusCode> {
+    tag_one(user, pool, id, tag, TagAction::Added).await
+}
+
+#[instrument(skip(user, pool), fields(username = user.username))]
+pub async fn remove_subscriber_tag(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path((id, tag)): Path<(Uuid, String)>,
+) -> ApiResult<StatusCode> {
+    tag_one(user, pool, id, tag, TagAction::Removed).await
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BulkTagBody {
+    pub action: BulkTagAction,
+    #[serde(default)]
+    pub subscriber_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkTagAction {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BulkTagResponse {
+    pub tag: String,
+    pub changed: usize,
+    pub unknown_subscriber_ids: Vec<Uuid>,
+    pub unknown_emails: Vec<String>,
+}
+
+#[instrument(skip(user, pool, body), fields(username = user.username))]
+pub async fn bulk_tag(
+    user: AuthUser,
+    State(pool): State<PgPool>,
+    Path(tag): Path<String>,
+    body: Result<Json<BulkTagBody>, JsonRejection>,
+) -> ApiResult<Json<BulkTagResponse>> {
+    let Json(body) = body?;
+    let tag = normalize_tag(&tag)?;
+    let action = match body.action {
+        BulkTagAction::Add => TagAction::Added,
+        BulkTagAction::Remove => TagAction::Removed,
+    };
+
+    let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let emails = body
+        .emails
+        .iter()
+        .map(|email| email.to_lowercase())
+        .collect::<Vec<_>>();
+    let found = sqlx::query!(
+        r#"
+        SELECT id, lower(email) AS "email!" FROM subscribers
+        WHERE id = ANY($1) OR lower(email) = ANY($2)
+        "#,
+        &body.subscriber_ids,
+        &emails
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let found_ids = found.iter().map(|obj| obj.id).collect::<HashSet<_>>();
+    let found_emails = found
+        .iter()
+        .map(|obj| obj.email.as_str())
+        .collect::<HashSet<_>>();
+    let unknown_subscriber_ids = body
+        .subscriber_ids
+        .iter()
+        .filter(|id| !found_ids.contains(*id))
+        .copied()
+        .collect();
+    let unknown_emails = body
+        .emails
+        .iter()
+        .zip(&emails)
+        .filter(|(_, email)| !found_emails.contains(email.as_str()))
+        .map(|(email, _)| email.clone())
+        .collect();
+    let ids = found_ids.into_iter().collect::<Vec<_>>();
+
+    let changed = apply_tag(&mut transaction, &tag, action, &ids, Some(&user.username)).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(tag, %action, changed = changed.len(), "Bulk tag change");
+
+    Ok(Json(BulkTagResponse {
+        tag,
+        changed: changed.len(),
+        unknown_subscriber_ids,
+        unknown_emails,
+    }))
+}
@@ -0,0 +1,136 @@
+//! Templates for subjects and bodies, rendered for each recipient.
+//!
+//! Templates use Jinja syntax (MiniJinja), e.g. `Hi {{ subscriber.name }}` or
+//! `{% if subscriber.attributes.plan == "pro" %}...{% endif %}`. Missing values render as nothing
+//! instead of failing the send, `default` gives them a fallback:
+//! `{{ subscriber.attributes.city | default("your city") }}`. Templates named `*.html` are
+//! HTML-escaped.
+
+use minijinja::{Environment, UndefinedBehavior};
+use reqwest::Url;
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct TemplateError(String);
+
+impl From<minijinja::Error> for TemplateError {
+    fn from(value: minijinja::Error) -> Self {
+        // Includes the template name and line
+        Self(value.to_string())
+    }
+}
+
+/// A set of named templates, checked against the variables they can use.
+#[derive(Debug)]
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    /// Compiles `templates`, failing on syntax errors and on variables that aren't one of
+    /// `variables`. A variable also allows everything below it, e.g. `subscriber.attributes`
+    /// allows `subscriber.attributes.city`.
+    pub fn new(
+        templates: impl IntoIterator<Item = (&'static str, String)>,
+        variables: &[&str],
+    ) -> Result<Self, TemplateError> {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Chainable);
+
+        for (name, source) in templates {
+            env.add_template_owned(name, source)?;
+            let template = env.get_template(name)?;
+
+            let mut used = template
+                .undeclared_variables(true)
+                .into_iter()
+                .collect::<Vec<_>>();
+            used.sort();
+            for variable in used {
+                let known = variables.iter().any(|known| {
+                    variable == *known
+                        || variable.starts_with(&format!("{known}."))
+                        || known.starts_with(&format!("{variable}."))
+                });
+                if !known {
+                    return Err(TemplateError(format!(
+                        "Unknown variable `{variable}` in {name}, the available ones are {}",
+                        variables.join(", ")
+                    )));
+                }
+            }
+        }
+
+        Ok(Self { env })
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.env.get_template(name).is_ok()
+    }
+
+    pub fn render(&self, name: &str, context: impl Serialize) -> Result<String, TemplateError> {
+        Ok(self.env.get_template(name)?.render(context)?)
+    }
+}
+
+/// The subscriber an email is rendered for, `subscriber` in templates.
+#[derive(Debug, Clone, Serialize)]
+pub struct Recipient {
+    /// Missing for addresses that aren't subscribed, like those of test sends.
+    pub id: Option<Uuid>,
+    pub email: String,
+    pub name: String,
+    /// Custom attributes, e.g. `subscriber.attributes.city`.
+    pub attributes: serde_json::Value,
+    #[serde(skip)]
+    pub unsubscribe_token: Option<String>,
+}
+
+impl Recipient {
+    /// Stands in for an address that isn't subscribed.
+    pub fn unknown(email: String) -> Self {
+        Self {
+            id: None,
+            email,
+            name: String::new(),
+            attributes: serde_json::Value::Object(Default::default()),
+            unsubscribe_token: None,
+        }
+    }
+}
+
+/// Where subscribers manage their subscription, with their unsubscribe token.
+#[derive(Debug, Clone)]
+pub struct SubscriberLinks {
+    pub unsubscribe_endpoint: Url,
+    pub preferences_endpoint: Url,
+}
+
+impl SubscriberLinks {
+    pub fn new(base_url: &Url) -> anyhow::Result<Self> {
+        Ok(Self {
+            unsubscribe_endpoint: base_url.join("unsubscribe")?,
+            preferences_endpoint: base_url.join("preferences")?,
+        })
+    }
+
+    fn with_token(endpoint: &Url, recipient: &Recipient) -> Url {
+        let mut url = endpoint.clone();
+        url.query_pairs_mut().append_pair(
+            "token",
+            recipient.unsubscribe_token.as_deref().unwrap_or_default(),
+        );
+        url
+    }
+
+    /// Also the target of one-click unsubscribes from the `List-Unsubscribe` header.
+    pub fn unsubscribe_url(&self, recipient: &Recipient) -> Url {
+        Self::with_token(&self.unsubscribe_endpoint, recipient)
+    }
+
+    pub fn preferences_url(&self, recipient: &Recipient) -> Url {
+        Self::with_token(&self.preferences_endpoint, recipient)
+    }
+}
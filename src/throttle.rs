@@ -0,0 +1,199 @@
+use crate::{
+    config::{ConfirmationThrottleConfig, ThrottleRuleConfig},
+    email::EmailAdderess,
+    ServerError, SubscribeError,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::{net::IpAddr, time::Duration};
+use tracing::info;
+
+#[derive(Debug, strum::Display, strum::EnumString)]
+pub enum ThrottleScope {
+    Email,
+    Ip,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleRule {
+    /// Minimum time between two sends for the same key.
+    pub cooldown: Duration,
+    /// Maximum sends for the same key within `window`.
+    pub max_sends: u32,
+    pub window: Duration,
+}
+
+impl From<ThrottleRuleConfig> for ThrottleRule {
+    fn from(value: ThrottleRuleConfig) -> Self {
+        Self {
+            cooldown: value.cooldown_ms,
+            max_sends: value.max_sends,
+            window: value.window_ms,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    window_start: DateTime<Utc>,
+    sends: i32,
+    last_sent_at: DateTime<Utc>,
+}
+
+impl ThrottleRule {
+    /// How long the key has to wait before another send is allowed, if at all.
+    fn retry_after(&self, now: DateTime<Utc>, state: &ThrottleState) -> Option<Duration> {
+        let wait_until = |instant: DateTime<Utc>, duration: Duration| {
+            (instant + chrono::Duration::from_std(duration).ok()? - now)
+                .to_std()
+                .ok()
+                .filter(|wait| !wait.is_zero())
+        };
+
+        let cooldown = wait_until(state.last_sent_at, self.cooldown);
+        let window = (state.sends >= self.max_sends as i32)
+            .then(|| wait_until(state.window_start, self.window))
+            .flatten();
+
+        cooldown.max(window)
+    }
+}
+
+/// Limits how often confirmation emails go out per address and per client IP.
+///
+/// The counters live in Postgres so that they survive restarts and are shared between instances.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmationThrottle {
+    pub per_email: ThrottleRule,
+    pub per_ip: ThrottleRule,
+}
+
+impl From<ConfirmationThrottleConfig> for ConfirmationThrottle {
+    fn from(value: ConfirmationThrottleConfig) -> Self {
+        Self {
+            per_email: value.per_email.into(),
+            per_ip: value.per_ip.into(),
+        }
+    }
+}
+
+impl ConfirmationThrottle {
+    /// Records a confirmation send for `email` and `ip`, or fails with
+    /// [`SubscribeError::TooManyRequests`] if either of them is over its limit.
+    pub async fn acquire(
+        &self,
+        pool: &PgPool,
+        email: &EmailAdderess,
+        ip: IpAddr,
+    ) -> Result<(), ServerError> {
+        let keys = [
+            (
+                ThrottleScope::Email,
+                email.as_ref().to_lowercase(),
+                self.per_email,
+            ),
+            (ThrottleScope::Ip, ip.to_string(), self.per_ip),
+        ];
+        let now = Utc::now();
+
+        let mut transaction = pool.begin().await.map_err(ServerError::unexpected)?;
+
+        let mut retry_after = None;
+        for (scope, key, rule) in &keys {
+            // `FOR UPDATE` can't lock a row that doesn't exist yet, so first requests for a new
+            // address or IP would all get through. Always taken in the same order.
+            sqlx::query!(
+                "SELECT pg_advisory_xact_lock(hashtext($1 || ':' || $2))",
+                scope.to_string(),
+                key
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+
+            let state = sqlx::query_as!(
+                ThrottleState,
+                r#"
+                SELECT window_start, sends, last_sent_at FROM confirmation_throttle
+                WHERE scope = $1 AND key = $2
+                FOR UPDATE
+                "#,
+                scope.to_string(),
+                key
+            )
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+
+            if let Some(wait) = state.and_then(|state| rule.retry_after(now, &state)) {
+                info!(%scope, ?wait, "Confirmation send throttled");
+                retry_after = retry_after.max(Some(wait));
+            }
+        }
+
+        if let Some(retry_after) = retry_after {
+            return Err(SubscribeError::TooManyRequests { retry_after }.into());
+        }
+
+        for (scope, key, rule) in &keys {
+            sqlx::query!(
+                r#"
+                INSERT INTO confirmation_throttle (scope, key, window_start, sends, last_sent_at)
+                VALUES ($1, $2, $3, 1, $3)
+                ON CONFLICT (scope, key) DO UPDATE
+                SET window_start = CASE
+                        WHEN confirmation_throttle.window_start + $4 * INTERVAL '1 millisecond' <= $3 THEN $3
+                        ELSE confirmation_throttle.window_start
+                    END,
+                    sends = CASE
+                        WHEN confirmation_throttle.window_start + $4 * INTERVAL '1 millisecond' <= $3 THEN 1
+                        ELSE confirmation_throttle.sends + 1
+                    END,
+                    last_sent_at = $3
+                "#,
+                scope.to_string(),
+                key,
+                now,
+                rule.window.as_millis() as i64
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(ServerError::unexpected)?;
+
+        Ok(())
+    }
+}
+
+impl ConfirmationThrottle {
+    /// Deletes the counters that no longer hold anything back, returning how many were removed.
+    pub async fn prune(&self, pool: &PgPool) -> sqlx::Result<u64> {
+        let mut pruned = 0;
+        for (scope, rule) in [
+            (ThrottleScope::Email, self.per_email),
+            (ThrottleScope::Ip, self.per_ip),
+        ] {
+            pruned += sqlx::query!(
+                r#"
+                DELETE FROM confirmation_throttle
+                WHERE scope = $1
+                    AND last_sent_at + $2 * INTERVAL '1 millisecond' <= now()
+                    AND window_start + $3 * INTERVAL '1 millisecond' <= now()
+                "#,
+                scope.to_string(),
+                rule.cooldown.as_millis() as i64,
+                rule.window.as_millis() as i64
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+        }
+
+        Ok(pruned)
+    }
+}
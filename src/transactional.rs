@@ -0,0 +1,598 @@
+//! System emails, like the confirmation email, as templates that can be customized per list and
+//! locale.
+//!
+//! Every kind comes with a built-in template. Customized ones are looked up from the most to the
+//! least specific, the locale counting more than the list: `de-at` on the list, `de-at` on all
+//! lists, `de` on the list, `de` on all lists, the list in any locale, and then all lists in any
+//! locale.
+
+use crate::{
+    api::ApiResult,
+    auth::AuthUser,
+    config::ListConfig,
+    email::{EmailAdderess, EmailClient},
+    templating::{TemplateError, Templates},
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRef, Path, Query, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+use tracing::{info, instrument};
+
+/// Every kind can be customized, `PasswordReset` and `EmailChange` are ready for when something
+/// sends them, nothing does yet.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    strum::EnumIter,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TransactionalKind {
+    /// The double opt-in link.
+    Confirmation,
+    /// After confirming, when `transactional_emails.welcome` is set.
+    Welcome,
+    PasswordReset,
+    EmailChange,
+    /// After unsubscribing, when `transactional_emails.unsubscribe_receipt` is set.
+    UnsubscribeReceipt,
+    /// The link to the personal data page.
+    DataRequest,
+}
+
+const COMMON_VARIABLES: [&str; 4] = [
+    "subscriber.email",
+    "subscriber.name",
+    "list.slug",
+    "list.name",
+];
+
+impl TransactionalKind {
+    /// What templates of this kind can use. `list` is missing for emails that aren't about a
+    /// single list.
+    pub fn variables(self) -> Vec<&'static str> {
+        let specific: &[&str] = match self {
+            Self::Confirmation => &["confirmation_url"],
+            Self::Welcome => &["unsubscribe_url", "preferences_url"],
+            Self::PasswordReset => &["reset_url"],
+            Self::EmailChange => &["new_email", "confirmation_url"],
+            Self::UnsubscribeReceipt => &[],
+            Self::DataRequest => &["manage_url"],
+        };
+        COMMON_VARIABLES.iter().chain(specific).copied().collect()
+    }
+
+    pub fn default_template(self) -> EmailTemplate {
+        let (subject, text, html) = match self {
+            Self::Confirmation => (
+                "Newsletter subscription confirmation",
+                "Open the link to confirm your newsletter subscription. {{ confirmation_url }}",
+                r#"
+<p>
+    Open the link to confirm your newsletter subscription.<br />
+    <a href="{{ confirmation_url }}">{{ confirmation_url }}</a>
+</p>"#,
+            ),
+            Self::Welcome => (
+                "Welcome{% if list.name %} to {{ list.name }}{% endif %}!",
+                "Hi{% if subscriber.name %} {{ subscriber.name }}{% endif %}, your subscription \
+                is confirmed. Pick what you get here: {{ preferences_url }}",
+                r#"
+<p>Hi{% if subscriber.name %} {{ subscriber.name }}{% endif %}, your subscription is confirmed.</p>
+<p><a href="{{ preferences_url }}">Pick what you get</a></p>"#,
+            ),
+            Self::PasswordReset => (
+                "Reset your password",
+                "Open the link to choose a new password. {{ reset_url }}\n\n\
+                If you didn't ask for this, ignore this email.",
+                r#"
+<p>
+    Open the link to choose a new password.<br />
+    <a href="{{ reset_url }}">{{ reset_url }}</a>
+</p>
+<p>If you didn't ask for this, ignore this email.</p>"#,
+            ),
+            Self::EmailChange => (
+                "Confirm your new email address",
+                "Open the link to get emails at {{ new_email }} from now on. {{ confirmation_url }}",
+                r#"
+<p>
+    Open the link to get emails at {{ new_email }} from now on.<br />
+    <a href="{{ confirmation_url }}">{{ confirmation_url }}</a>
+</p>"#,
+            ),
+            // Leaving a list still gets emails to all subscribers
+            Self::UnsubscribeReceipt => (
+                "You're unsubscribed",
+                "{% if list %}You're off {{ list.name }}, emails to all subscribers still reach \
+                you.{% else %}You won't get any more emails from us.{% endif %}",
+                r#"
+<p>
+    {%- if list %}You're off {{ list.name }}, emails to all subscribers still reach you.
+    {%- else %}You won't get any more emails from us.{% endif -%}
+</p>"#,
+            ),
+            Self::DataRequest => (
+                "Your personal data",
+                "Open the link to download or delete the data we hold on you. {{ manage_url }}",
+                r#"
+<p>
+    Open the link to download or delete the data we hold on you.<br />
+    <a href="{{ manage_url }}">{{ manage_url }}</a>
+</p>"#,
+            ),
+        };
+        EmailTemplate {
+            subject: subject.into(),
+            text: text.into(),
+            html: html.into(),
+        }
+    }
+
+    /// Stand-ins for the variables, for previews.
+    fn sample_variables(self) -> Value {
+        match self {
+            Self::Confirmation => {
+                json!({ "confirmation_url": "https://example.com/subscribe/confirm?token=sample" })
+            }
+            Self::Welcome => json!({
+                "unsubscribe_url": "https://example.com/unsubscribe?token=sample",
+                "preferences_url": "https://example.com/preferences?token=sample",
+            }),
+            Self::PasswordReset => json!({ "reset_url": "https://example.com/reset?token=sample" }),
+            Self::EmailChange => json!({
+                "new_email": "new-subscriber@example.com",
+                "confirmation_url": "https://example.com/confirm-email?token=sample",
+            }),
+            Self::UnsubscribeReceipt => json!({}),
+            Self::DataRequest => {
+                json!({ "manage_url": "https://example.com/data-requests/manage?token=sample" })
+            }
+        }
+    }
+}
+
+/// A language tag like `de` or `pt-br`, lowercase.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Locale(String);
+
+impl Locale {
+    /// `de` for `de-at`.
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or_default()
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let locale = s.trim().replace('_', "-").to_lowercase();
+        let mut parts = locale.split('-');
+        let language_ok = parts.next().is_some_and(|language| {
+            (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_lowercase())
+        });
+        let rest_ok = parts.all(|part| {
+            (2..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+        if !language_ok || !rest_ok {
+            return Err(format!("\"{s}\" isn't a locale like \"en\" or \"pt-BR\""));
+        }
+        Ok(Self(locale))
+    }
+}
+
+impl AsRef<str> for Locale {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Locale {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Locale::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl EmailTemplate {
+    fn compile(&self, kind: TransactionalKind) -> Result<Templates, TemplateError> {
+        Templates::new(
+            [
+                ("subject.txt", self.subject.clone()),
+                ("body.txt", self.text.clone()),
+                ("body.html", self.html.clone()),
+            ],
+            &kind.variables(),
+        )
+    }
+
+    pub fn render(
+        &self,
+        kind: TransactionalKind,
+        variables: &Value,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let templates = self.compile(kind)?;
+        Ok(RenderedEmail {
+            subject: templates.render("subject.txt", variables)?,
+            text: templates.render("body.txt", variables)?,
+            html: templates.render("body.html", variables)?,
+        })
+    }
+}
+
+/// The template that's used for a list and locale, and where it came from.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ResolvedTemplate {
+    pub kind: TransactionalKind,
+    /// Missing for the built-in template.
+    pub customized: Option<Customization>,
+    #[serde(flatten)]
+    pub template: EmailTemplate,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Customization {
+    /// Missing when it's for all lists.
+    pub list: Option<String>,
+    /// Missing when it's for all locales.
+    pub locale: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+fn non_empty(s: String) -> Option<String> {
+    (!s.is_empty()).then_some(s)
+}
+
+/// The template for `list` and `locale`, see the module docs for the order they're looked up in.
+pub async fn resolve_template(
+    pool: &PgPool,
+    kind: TransactionalKind,
+    list: Option<&str>,
+    locale: Option<&Locale>,
+) -> sqlx::Result<ResolvedTemplate> {
+    let lists = [list.unwrap_or_default(), ""];
+    let locales = match locale {
+        Some(locale) => vec![locale.as_ref(), locale.language(), ""],
+        None => vec![""],
+    };
+    let customized = sqlx::query!(
+        r#"
+        SELECT list, locale, subject, text_content, html_content, updated_at FROM email_templates
+        WHERE kind = $1 AND list = ANY($2) AND locale = ANY($3)
+        ORDER BY array_position($3, locale), list = ''
+        LIMIT 1
+        "#,
+        kind.to_string(),
+        &lists as &[&str],
+        &locales as &[&str]
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match customized {
+        Some(row) => ResolvedTemplate {
+            kind,
+            customized: Some(Customization {
+                list: non_empty(row.list),
+                locale: non_empty(row.locale),
+                updated_at: row.updated_at,
+            }),
+            template: EmailTemplate {
+                subject: row.subject,
+                text: row.text_content,
+                html: row.html_content,
+            },
+        },
+        None => ResolvedTemplate {
+            kind,
+            customized: None,
+            template: kind.default_template(),
+        },
+    })
+}
+
+/// Who a transactional email goes to.
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionalRecipient<'a> {
+    pub email: &'a EmailAdderess,
+    pub name: &'a str,
+    pub locale: Option<&'a Locale>,
+    /// Slug and config of the list the email is about.
+    pub list: Option<(&'a str, &'a ListConfig)>,
+}
+
+fn common_variables(email: &str, name: &str, list: Option<(&str, &ListConfig)>) -> Value {
+    let mut variables = json!({ "subscriber": { "email": email, "name": name } });
+    // Left out rather than null, so that `list.name` falls back like any other missing value
+    if let Some((slug, list)) = list {
+        variables["list"] = json!({ "slug": slug, "name": list.name });
+    }
+    variables
+}
+
+/// `variables` are the ones specific to `kind`, e.g. `confirmation_url`. Fails with
+/// [`crate::email::Suppressed`] for suppressed addresses, like any other send.
+pub async fn send_transactional(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    kind: TransactionalKind,
+    to: TransactionalRecipient<'_>,
+    variables: Value,
+) -> anyhow::Result<()> {
+    let resolved = resolve_template(pool, kind, to.list.map(|(slug, _)| slug), to.locale).await?;
+
+    let mut context = common_variables(to.email.as_ref(), to.name, to.list);
+    if let (Some(context), Value::Object(variables)) = (context.as_object_mut(), variables) {
+        context.extend(variables);
+    }
+    let rendered = resolved.template.render(kind, &context)?;
+
+    email_client
+        .send_email(to.email, &rendered.subject, &rendered.text, &rendered.html)
+        .await
+}
+
+#[derive(Debug, Clone)]
+pub struct TemplateState {
+    pub pool: PgPool,
+    pub lists: Arc<HashMap<String, ListConfig>>,
+}
+
+impl FromRef<TemplateState> for PgPool {
+    fn from_ref(state: &TemplateState) -> Self {
+        state.pool.clone()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TemplateScopeQuery {
+    /// Slug of one of the configured lists, all lists when missing.
+    pub list: Option<String>,
+    /// All locales when missing.
+    pub locale: Option<String>,
+}
+
+/// The list slug and locale of the query, checked.
+fn scope(
+    state: &TemplateState,
+    query: Result<Query<TemplateScopeQuery>, QueryRejection>,
+) -> ServerResult<(Option<String>, Option<Locale>)> {
+    let Query(query) = query.map_err(|e| ServerError::BadRequest(e.body_text()))?;
+    let list = query.list.as_deref().map(str::to_lowercase);
+    if let Some(list) = &list {
+        if !state.lists.contains_key(list) {
+            return Err(ServerError::BadRequest(format!("Unknown list \"{list}\"")));
+        }
+    }
+    let locale = query
+        .locale
+        .as_deref()
+        .map(Locale::from_str)
+        .transpose()
+        .map_err(ServerError::BadRequest)?;
+    Ok((list, locale))
+}
+
+fn parse_kind(kind: &str) -> ServerResult<TransactionalKind> {
+    TransactionalKind::from_str(kind)
+        .map_err(|_| ServerError::NotFound(format!("No such email template \"{kind}\"")))
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TemplateSummary {
+    pub kind: TransactionalKind,
+    pub variables: Vec<&'static str>,
+    pub customizations: Vec<Customization>,
+}
+
+/// Every kind of transactional email, with its customizations.
+pub async fn list_templates(
+    _user: AuthUser,
+    State(pool): State<PgPool>,
+) -> ApiResult<Json<Vec<TemplateSummary>>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT kind, list, locale, updated_at FROM email_templates
+        ORDER BY kind, list, locale
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    let mut customizations: HashMap<String, Vec<Customization>> = HashMap::new();
+    for row in rows {
+        customizations
+            .entry(row.kind)
+            .or_default()
+            .push(Customization {
+                list: non_empty(row.list),
+                locale: non_empty(row.locale),
+                updated_at: row.updated_at,
+            });
+    }
+
+    Ok(Json(
+        <TransactionalKind as strum::IntoEnumIterator>::iter()
+            .map(|kind| TemplateSummary {
+                kind,
+                variables: kind.variables(),
+                customizations: customizations.remove(&kind.to_string()).unwrap_or_default(),
+            })
+            .collect(),
+    ))
+}
+
+/// The template that's sent for `?list=` and `?locale=`, customized or not.
+pub async fn get_template(
+    _user: AuthUser,
+    State(state): State<TemplateState>,
+    Path(kind): Path<String>,
+    query: Result<Query<TemplateScopeQuery>, QueryRejection>,
+) -> ApiResult<Json<ResolvedTemplate>> {
+    let kind = parse_kind(&kind)?;
+    let (list, locale) = scope(&state, query)?;
+
+    Ok(Json(
+        resolve_template(&state.pool, kind, list.as_deref(), locale.as_ref())
+            .await
+            .map_err(ServerError::unexpected)?,
+    ))
+}
+
+/// Customizes the template for exactly `?list=` and `?locale=`, or all of them when missing.
+#[instrument(skip(user, state, query, body), fields(username = user.username))]
+pub async fn put_template(
+    user: AuthUser,
+    State(state): State<TemplateState>,
+    Path(kind): Path<String>,
+    query: Result<Query<TemplateScopeQuery>, QueryRejection>,
+    body: Result<Json<EmailTemplate>, JsonRejection>,
+) -> ApiResult<Json<ResolvedTemplate>> {
+    let kind = parse_kind(&kind)?;
+    let (list, locale) = scope(&state, query)?;
+    let Json(template) = body?;
+    if template.subject.trim().is_empty() {
+        return Err(ServerError::BadRequest("Subject can't be empty".into()).into());
+    }
+    template
+        .compile(kind)
+        .map_err(|e| ServerError::BadRequest(format!("Invalid template: {e}")))?;
+
+    let updated_at = sqlx::query_scalar!(
+        r#"
+        INSERT INTO email_templates (
+            kind, list, locale, subject, text_content, html_content, updated_by, updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (kind, list, locale) DO UPDATE
+        SET subject = EXCLUDED.subject, text_content = EXCLUDED.text_content,
+            html_content = EXCLUDED.html_content, updated_by = EXCLUDED.updated_by,
+            updated_at = EXCLUDED.updated_at
+        RETURNING updated_at
+        "#,
+        kind.to_string(),
+        list.as_deref().unwrap_or_default(),
+        locale.as_ref().map(AsRef::as_ref).unwrap_or_default(),
+        template.subject,
+        template.text,
+        template.html,
+        user.id,
+        Utc::now()
+    )
+    .fetch_one(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+
+    info!(%kind, ?list, ?locale, "Email template customized");
+
+    Ok(Json(ResolvedTemplate {
+        kind,
+        customized: Some(Customization {
+            list,
+            locale: locale.map(|locale| locale.0),
+            updated_at,
+        }),
+        template,
+    }))
+}
+
+/// Goes back to the next less specific template for `?list=` and `?locale=`.
+#[instrument(skip(user, state, query), fields(username = user.username))]
+pub async fn delete_template(
+    user: AuthUser,
+    State(state): State<TemplateState>,
+    Path(kind): Path<String>,
+    query: Result<Query<TemplateScopeQuery>, QueryRejection>,
+) -> ApiResult<StatusCode> {
+    let kind = parse_kind(&kind)?;
+    let (list, locale) = scope(&state, query)?;
+
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM email_templates
+        WHERE kind = $1 AND list = $2 AND locale = $3
+        "#,
+        kind.to_string(),
+        list.as_deref().unwrap_or_default(),
+        locale.as_ref().map(AsRef::as_ref).unwrap_or_default()
+    )
+    .execute(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(ServerError::NotFound(format!(
+            "The {kind} template isn't customized for this list and locale"
+        ))
+        .into());
+    }
+
+    info!(%kind, ?list, ?locale, "Email template reset");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Renders the template that's sent for `?list=` and `?locale=` with sample values.
+pub async fn preview_template(
+    _user: AuthUser,
+    State(state): State<TemplateState>,
+    Path(kind): Path<String>,
+    query: Result<Query<TemplateScopeQuery>, QueryRejection>,
+) -> ApiResult<Json<RenderedEmail>> {
+    let kind = parse_kind(&kind)?;
+    let (list, locale) = scope(&state, query)?;
+    let resolved = resolve_template(&state.pool, kind, list.as_deref(), locale.as_ref())
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    let mut variables = common_variables(
+        "subscriber@example.com",
+        "Sample Subscriber",
+        list.as_deref().map(|slug| (slug, &state.lists[slug])),
+    );
+    if let (Some(variables), Value::Object(sample)) =
+        (variables.as_object_mut(), kind.sample_variables())
+    {
+        variables.extend(sample);
+    }
+
+    Ok(Json(resolved.template.render(kind, &variables).map_err(
+        |e| ServerError::BadRequest(format!("Invalid template: {e}")),
+    )?))
+}
@@ -0,0 +1,203 @@
+use crate::{
+    consent::{record_consent, ConsentKind, ConsentSource},
+    helpers::escape_html,
+    pages::render_page,
+    subscribe::{notify_subscriber, ConfirmState, SubscriptionStatus},
+    transactional::TransactionalKind,
+    ServerError, ServerResult,
+};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::net::SocketAddr;
+use tracing::{info, instrument};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UnsubscribeQuery {
+    pub token: Option<String>,
+    /// Only leave this list, instead of unsubscribing from everything.
+    pub list: Option<String>,
+}
+
+fn invalid_link_page(state: &ConfirmState) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        render_page(
+            &state.confirmation.theme,
+            "unsubscribe",
+            "Invalid link",
+            "<p>This unsubscribe link is not valid. \
+            Make sure you opened the complete link from the email.</p>",
+        ),
+    )
+        .into_response()
+}
+
+/// Asks for a click before unsubscribing, since mail scanners follow links on their own.
+pub async fn unsubscribe_page(
+    State(state): State<ConfirmState>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> ServerResult<Response> {
+    let email = sqlx::query_scalar!(
+        r#"
+        SELECT email FROM subscribers
+        WHERE unsubscribe_token = $1
+        "#,
+        query.token.as_deref().unwrap_or_default()
+    )
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let Some(email) = email else {
+        return Ok(invalid_link_page(&state));
+    };
+
+    let list = query
+        .list
+        .as_deref()
+        .and_then(|slug| state.lists.get(&slug.to_lowercase()));
+    let question = match list {
+        Some(list) => format!(
+            "Take {} off {}?",
+            escape_html(&email),
+            escape_html(&list.name)
+        ),
+        None => format!(
+            "Stop sending {} emails from {}?",
+            escape_html(&email),
+            escape_html(&state.confirmation.theme.brand_name)
+        ),
+    };
+
+    Ok(render_page(
+        &state.confirmation.theme,
+        "unsubscribe",
+        "Unsubscribe",
+        &format!(
+            r#"<p>{question}</p>
+        <form method="post">
+            <button type="submit">Unsubscribe</button>
+        </form>"#
+        ),
+    )
+    .into_response())
+}
+
+/// Also the target of one-click `List-Unsubscribe-Post` requests (RFC 8058), which carry the
+/// token in the query as well.
+#[instrument(skip(state, headers, query), fields(list = query.list, %client_addr))]
+pub async fn unsubscribe(
+    State(state): State<ConfirmState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<UnsubscribeQuery>,
+) -> ServerResult<Response> {
+    let mut transaction = state.pool.begin().await.map_err(ServerError::unexpected)?;
+
+    let subscriber = sqlx::query!(
+        r#"
+        SELECT id FROM subscribers
+        WHERE unsubscribe_token = $1
+        FOR UPDATE
+        "#,
+        query.token.as_deref().unwrap_or_default()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(ServerError::unexpected)?;
+    let Some(subscriber) = subscriber else {
+        return Ok(invalid_link_page(&state));
+    };
+    let uuid = subscriber.id;
+
+    let list = query
+        .list
+        .as_deref()
+        .map(str::to_lowercase)
+        .filter(|slug| state.lists.contains_key(slug));
+    let message = match &list {
+        Some(list) => {
+            sqlx::query!(
+                r#"
+                DELETE FROM list_subscriptions
+                WHERE list = $1 AND subscriber_id = $2
+                "#,
+                list,
+                uuid
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+            // Issues without an audience still go to every confirmed subscriber
+            format!(
+                r#"<p>You're off {}. Emails to all subscribers still reach you,
+                <a href="unsubscribe?token={}">unsubscribe from everything</a> to stop those too.</p>"#,
+                escape_html(&state.lists[list].name),
+                escape_html(query.token.as_deref().unwrap_or_default())
+            )
+        }
+        None => {
+            sqlx::query!(
+                r#"
+                UPDATE subscribers
+                SET status = $1
+                WHERE id = $2 AND status = ANY($3)
+                "#,
+                SubscriptionStatus::Unsubscribed.to_string(),
+                uuid,
+                // A bounce or complaint stays recorded as such
+                &[
+                    SubscriptionStatus::Pending.to_string(),
+                    SubscriptionStatus::Confirmed.to_string(),
+                ]
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(ServerError::unexpected)?;
+            format!(
+                "<p>You won't get any more emails from {}.</p>",
+                escape_html(&state.confirmation.theme.brand_name)
+            )
+        }
+    };
+
+    let source =
+        ConsentSource::from_request("link", &headers, client_addr, state.trust_x_forwarded_for);
+    record_consent(
+        &mut *transaction,
+        &[uuid],
+        ConsentKind::Unsubscribe,
+        list.as_deref(),
+        &source,
+        json!({}),
+    )
+    .await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ServerError::unexpected)?;
+
+    info!(?uuid, "Unsubscribed");
+
+    if state.transactional_emails.unsubscribe_receipt {
+        notify_subscriber(
+            &state,
+            TransactionalKind::UnsubscribeReceipt,
+            uuid,
+            list.as_deref(),
+        )
+        .await;
+    }
+
+    Ok(render_page(
+        &state.confirmation.theme,
+        "unsubscribe",
+        "Unsubscribed",
+        &message,
+    )
+    .into_response())
+}